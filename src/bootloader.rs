@@ -0,0 +1,487 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{target_path, Cmd, CommandRunner},
+    config::InstallConfig,
+    disk::{self, BlockDevice},
+    error::{InstallerError, Result},
+    format::FilesystemKind,
+    partition::Partition,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    Uefi,
+    Bios,
+}
+
+/// Detect how the live system was booted. `fs_root` is normally `/` but can
+/// point at a fake tree containing `sys/firmware/efi`.
+pub fn detect_boot_mode(fs_root: &Path) -> BootMode {
+    if fs_root.join("sys/firmware/efi").is_dir() {
+        BootMode::Uefi
+    } else {
+        BootMode::Bios
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bootloader {
+    SystemdBoot,
+    Grub,
+}
+
+impl Bootloader {
+    /// The configured bootloader, or the natural choice for `mode` when unset.
+    pub fn choose(configured: Option<Bootloader>, mode: BootMode) -> Result<Bootloader> {
+        match (configured, mode) {
+            (Some(Bootloader::SystemdBoot), BootMode::Bios) => {
+                Err(InstallerError::PreconditionFailed(
+                    "systemd-boot needs a system booted in UEFI mode".to_string(),
+                ))
+            }
+            (Some(bootloader), _) => Ok(bootloader),
+            (None, BootMode::Uefi) => Ok(Bootloader::SystemdBoot),
+            (None, BootMode::Bios) => Ok(Bootloader::Grub),
+        }
+    }
+
+    pub fn packages(self, mode: BootMode) -> &'static [&'static str] {
+        match (self, mode) {
+            // bootctl ships with systemd, which base already pulls in
+            (Bootloader::SystemdBoot, _) => &[],
+            (Bootloader::Grub, BootMode::Uefi) => &["grub", "efibootmgr"],
+            (Bootloader::Grub, BootMode::Bios) => &["grub"],
+        }
+    }
+}
+
+// Where the ESP is mounted inside the target
+const ESP: &str = "/boot";
+
+/// Kernel command line for the installed system.
+pub fn kernel_params(
+    runner: &dyn CommandRunner,
+    root: &Partition,
+    config: &InstallConfig,
+) -> Result<Vec<String>> {
+    let mut params = match &config.encryption {
+        Some(encryption) => encryption.kernel_params(&disk::uuid_of(runner, &root.path)?),
+        None => vec![format!(
+            "root=UUID={}",
+            disk::uuid_of(runner, root.device())?
+        )],
+    };
+    params.push("rw".to_string());
+
+    if root.filesystem.kind == FilesystemKind::Btrfs {
+        if let Some(subvolume) = config.btrfs.subvolumes.iter().find(|s| s.mountpoint == "/") {
+            params.push(format!("rootflags=subvol=/{}", subvolume.name));
+        }
+    }
+    Ok(params)
+}
+
+/// Install and configure `bootloader` in the target mounted at `target`.
+/// In BIOS mode GRUB goes into the boot code of every disk in `disks`, so
+/// each member of a mirror can boot. With `alongside` other systems stay on
+/// the disk, so the loader they boot through is kept and the new system is
+/// added to it.
+pub fn install_bootloader(
+    runner: &dyn CommandRunner,
+    target: &Path,
+    bootloader: Bootloader,
+    mode: BootMode,
+    disks: &[BlockDevice],
+    params: &[String],
+    alongside: bool,
+) -> Result<()> {
+    match bootloader {
+        Bootloader::SystemdBoot => install_systemd_boot(runner, target, params, alongside),
+        Bootloader::Grub => install_grub(runner, target, mode, disks, params, alongside),
+    }
+}
+
+fn install_systemd_boot(
+    runner: &dyn CommandRunner,
+    target: &Path,
+    params: &[String],
+    alongside: bool,
+) -> Result<()> {
+    let esp = target_path(target, ESP);
+
+    // systemd-boot already on a shared ESP picks up the new entries by itself
+    if !(alongside && esp.join("EFI/systemd").is_dir()) {
+        runner.run_checked(
+            &Cmd::chroot(target, "bootctl")
+                .args([format!("--esp-path={}", ESP), "install".to_string()]),
+        )?;
+    }
+    // Keep the default entry and timeout the other systems were set up with
+    if !(alongside && esp.join("loader/loader.conf").is_file()) {
+        runner.write_file(&esp.join("loader/loader.conf"), &loader_conf())?;
+    }
+    runner.write_file(
+        &esp.join("loader/entries/arch.conf"),
+        &loader_entry("Arch Linux", "initramfs-linux.img", params),
+    )?;
+    runner.write_file(
+        &esp.join("loader/entries/arch-fallback.conf"),
+        &loader_entry(
+            "Arch Linux (fallback initramfs)",
+            "initramfs-linux-fallback.img",
+            params,
+        ),
+    )?;
+    Ok(())
+}
+
+pub fn loader_conf() -> String {
+    "default arch.conf\ntimeout 3\nconsole-mode max\neditor no\n".to_string()
+}
+
+pub fn loader_entry(title: &str, initrd: &str, params: &[String]) -> String {
+    format!(
+        "title   {}\nlinux   /vmlinuz-linux\ninitrd  /{}\noptions {}\n",
+        title,
+        initrd,
+        params.join(" ")
+    )
+}
+
+fn install_grub(
+    runner: &dyn CommandRunner,
+    target: &Path,
+    mode: BootMode,
+    disks: &[BlockDevice],
+    params: &[String],
+    alongside: bool,
+) -> Result<()> {
+    let installs: Vec<Cmd> = match mode {
+        BootMode::Uefi => vec![Cmd::chroot(target, "grub-install").args([
+            "--target=x86_64-efi".to_string(),
+            format!("--efi-directory={}", ESP),
+            "--bootloader-id=GRUB".to_string(),
+        ])],
+        BootMode::Bios => disks
+            .iter()
+            .map(|disk| Cmd::chroot(target, "grub-install").args(["--target=i386-pc", &disk.path]))
+            .collect(),
+    };
+    for install in &installs {
+        runner.run_checked(install)?;
+    }
+
+    // grub-mkconfig works out root=, rootflags= and rw by itself
+    let extra: Vec<&str> = params
+        .iter()
+        .map(String::as_str)
+        .filter(|p| !(p.starts_with("root=") || p.starts_with("rootflags=") || *p == "rw"))
+        .collect();
+    let cmdline = format!(
+        "s|^GRUB_CMDLINE_LINUX=.*|GRUB_CMDLINE_LINUX=\"{}\"|",
+        extra.join(" ")
+    );
+    runner.run_checked(&Cmd::chroot(target, "sed").args(["-i", &cmdline, "/etc/default/grub"]))?;
+    if alongside {
+        // Let grub-mkconfig add menu entries for the other systems through os-prober
+        runner.run_checked(&Cmd::chroot(target, "sed").args([
+            "-i",
+            "s|^#\\?GRUB_DISABLE_OS_PROBER=.*|GRUB_DISABLE_OS_PROBER=false|",
+            "/etc/default/grub",
+        ]))?;
+    }
+    runner
+        .run_checked(&Cmd::chroot(target, "grub-mkconfig").args(["-o", "/boot/grub/grub.cfg"]))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{CommandOutput, MockRunner},
+        disk::tests::disk,
+        format::FilesystemSpec,
+        partition::PartitionRole,
+        testing::TempDir,
+    };
+
+    fn params() -> Vec<String> {
+        vec![
+            "cryptdevice=UUID=1234:cryptroot".to_string(),
+            "root=/dev/mapper/cryptroot".to_string(),
+            "rw".to_string(),
+        ]
+    }
+
+    #[test]
+    fn boot_mode_follows_the_efi_firmware_directory() {
+        let root = TempDir::new();
+        assert_eq!(detect_boot_mode(root.path()), BootMode::Bios);
+        // A file of that name does not count
+        root.write("sys/firmware/efi", "");
+        assert_eq!(detect_boot_mode(root.path()), BootMode::Bios);
+
+        let root = TempDir::new();
+        root.mkdir("sys/firmware/efi/efivars");
+        assert_eq!(detect_boot_mode(root.path()), BootMode::Uefi);
+    }
+
+    #[test]
+    fn bootloader_choice_depends_on_the_boot_mode() {
+        use Bootloader::{Grub, SystemdBoot};
+        assert_eq!(
+            Bootloader::choose(None, BootMode::Uefi).unwrap(),
+            SystemdBoot
+        );
+        assert_eq!(Bootloader::choose(None, BootMode::Bios).unwrap(), Grub);
+        assert_eq!(
+            Bootloader::choose(Some(Grub), BootMode::Uefi).unwrap(),
+            Grub
+        );
+        assert_eq!(
+            Bootloader::choose(Some(Grub), BootMode::Bios).unwrap(),
+            Grub
+        );
+
+        let err = Bootloader::choose(Some(SystemdBoot), BootMode::Bios).unwrap_err();
+        assert!(matches!(err, InstallerError::PreconditionFailed(_)));
+        assert_eq!(
+            err.to_string(),
+            "systemd-boot needs a system booted in UEFI mode"
+        );
+    }
+
+    #[test]
+    fn kernel_params_point_at_the_root_filesystem() {
+        let runner = MockRunner::new();
+        runner.on(
+            "blkid",
+            CommandOutput::success("5d1f1c3a-8e4b-4d8e-9a57-2b0c6f1e7d21\n"),
+        );
+        let mut root = Partition {
+            role: PartitionRole::Root,
+            number: 2,
+            path: "/dev/sda2".to_string(),
+            filesystem: FilesystemSpec::new(FilesystemKind::Ext4),
+            mapper: None,
+            format: true,
+        };
+        let config = InstallConfig::default();
+        assert_eq!(
+            kernel_params(&runner, &root, &config).unwrap(),
+            ["root=UUID=5d1f1c3a-8e4b-4d8e-9a57-2b0c6f1e7d21", "rw"]
+        );
+
+        root.filesystem = FilesystemSpec::new(FilesystemKind::Btrfs);
+        assert_eq!(
+            kernel_params(&runner, &root, &config).unwrap(),
+            [
+                "root=UUID=5d1f1c3a-8e4b-4d8e-9a57-2b0c6f1e7d21",
+                "rw",
+                "rootflags=subvol=/@",
+            ]
+        );
+        assert_eq!(
+            runner.command_lines()[0],
+            "blkid -s UUID -o value /dev/sda2"
+        );
+
+        assert!(Bootloader::SystemdBoot.packages(BootMode::Uefi).is_empty());
+        assert_eq!(
+            Bootloader::Grub.packages(BootMode::Uefi),
+            ["grub", "efibootmgr"]
+        );
+        assert_eq!(Bootloader::Grub.packages(BootMode::Bios), ["grub"]);
+    }
+
+    #[test]
+    fn systemd_boot_writes_loader_and_entries() {
+        let runner = MockRunner::new();
+        let target = Path::new("/mnt");
+        let sda = disk("/dev/sda", 64 << 30);
+        install_bootloader(
+            &runner,
+            target,
+            Bootloader::SystemdBoot,
+            BootMode::Uefi,
+            &[sda],
+            &params(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.command_lines(),
+            ["arch-chroot /mnt bootctl --esp-path=/boot install"]
+        );
+        let files = runner.written_files();
+        let paths: Vec<&Path> = files.iter().map(|(path, _)| path.as_path()).collect();
+        assert_eq!(
+            paths,
+            [
+                Path::new("/mnt/boot/loader/loader.conf"),
+                Path::new("/mnt/boot/loader/entries/arch.conf"),
+                Path::new("/mnt/boot/loader/entries/arch-fallback.conf"),
+            ]
+        );
+        assert_eq!(files[0].1, loader_conf());
+        assert_eq!(
+            files[1].1,
+            "title   Arch Linux\nlinux   /vmlinuz-linux\ninitrd  /initramfs-linux.img\noptions cryptdevice=UUID=1234:cryptroot root=/dev/mapper/cryptroot rw\n"
+        );
+        assert!(files[2]
+            .1
+            .contains("initrd  /initramfs-linux-fallback.img\n"));
+    }
+
+    #[test]
+    fn systemd_boot_alongside_keeps_the_existing_loader() {
+        let target = TempDir::new();
+        target.mkdir("boot/EFI/systemd");
+        target.write("boot/loader/loader.conf", "default windows.conf\n");
+        let runner = MockRunner::new();
+        let sda = disk("/dev/sda", 64 << 30);
+        install_bootloader(
+            &runner,
+            target.path(),
+            Bootloader::SystemdBoot,
+            BootMode::Uefi,
+            &[sda],
+            &params(),
+            true,
+        )
+        .unwrap();
+
+        assert!(runner.invocations().is_empty());
+        let written: Vec<String> = runner
+            .written_files()
+            .iter()
+            .map(|(path, _)| {
+                path.strip_prefix(target.path())
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        assert_eq!(
+            written,
+            [
+                "boot/loader/entries/arch.conf",
+                "boot/loader/entries/arch-fallback.conf"
+            ]
+        );
+    }
+
+    #[test]
+    fn systemd_boot_alongside_installs_next_to_another_loader() {
+        let target = TempDir::new();
+        target.mkdir("boot/EFI/Microsoft/Boot");
+        let runner = MockRunner::new();
+        install_bootloader(
+            &runner,
+            target.path(),
+            Bootloader::SystemdBoot,
+            BootMode::Uefi,
+            &[disk("/dev/sda", 64 << 30)],
+            &params(),
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.command_lines(),
+            [format!(
+                "arch-chroot {} bootctl --esp-path=/boot install",
+                target.path().display()
+            )]
+        );
+        let files = runner.written_files();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].0, target.path().join("boot/loader/loader.conf"));
+        assert_eq!(files[0].1, loader_conf());
+    }
+
+    #[test]
+    fn grub_uefi_keeps_only_extra_parameters() {
+        let runner = MockRunner::new();
+        let sda = disk("/dev/sda", 64 << 30);
+        install_bootloader(
+            &runner,
+            Path::new("/mnt"),
+            Bootloader::Grub,
+            BootMode::Uefi,
+            &[sda],
+            &params(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.command_lines(),
+            [
+                "arch-chroot /mnt grub-install --target=x86_64-efi --efi-directory=/boot --bootloader-id=GRUB",
+                r#"arch-chroot /mnt sed -i 's|^GRUB_CMDLINE_LINUX=.*|GRUB_CMDLINE_LINUX="cryptdevice=UUID=1234:cryptroot"|' /etc/default/grub"#,
+                "arch-chroot /mnt grub-mkconfig -o /boot/grub/grub.cfg",
+            ]
+        );
+        assert!(runner.written_files().is_empty());
+    }
+
+    #[test]
+    fn grub_bios_installs_to_the_disk_and_enables_os_prober_alongside() {
+        let runner = MockRunner::new();
+        let sda = disk("/dev/sda", 64 << 30);
+        install_bootloader(
+            &runner,
+            Path::new("/mnt"),
+            Bootloader::Grub,
+            BootMode::Bios,
+            &[sda],
+            &params(),
+            true,
+        )
+        .unwrap();
+
+        let lines = runner.command_lines();
+        assert_eq!(
+            lines[0],
+            "arch-chroot /mnt grub-install --target=i386-pc /dev/sda"
+        );
+        assert_eq!(
+            lines[2],
+            r"arch-chroot /mnt sed -i 's|^#\?GRUB_DISABLE_OS_PROBER=.*|GRUB_DISABLE_OS_PROBER=false|' /etc/default/grub"
+        );
+        assert_eq!(
+            lines.last().unwrap(),
+            "arch-chroot /mnt grub-mkconfig -o /boot/grub/grub.cfg"
+        );
+    }
+
+    #[test]
+    fn grub_bios_installs_to_every_mirror_member() {
+        let runner = MockRunner::new();
+        install_bootloader(
+            &runner,
+            Path::new("/mnt"),
+            Bootloader::Grub,
+            BootMode::Bios,
+            &[disk("/dev/sda", 64 << 30), disk("/dev/sdb", 64 << 30)],
+            &params(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.command_lines()[..2],
+            [
+                "arch-chroot /mnt grub-install --target=i386-pc /dev/sda",
+                "arch-chroot /mnt grub-install --target=i386-pc /dev/sdb",
+            ]
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{mount_depth, target_path, Cmd, CommandRunner, Mount},
+    error::{InstallerError, Result},
+    mount::MountManager,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subvolume {
+    pub name: String,
+    // Absolute path inside the installed system
+    pub mountpoint: String,
+}
+
+impl Subvolume {
+    pub fn new(name: impl Into<String>, mountpoint: impl Into<String>) -> Self {
+        Subvolume {
+            name: name.into(),
+            mountpoint: mountpoint.into(),
+        }
+    }
+}
+
+/// Subvolumes created on a btrfs root and the options they are mounted with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BtrfsLayout {
+    pub subvolumes: Vec<Subvolume>,
+    pub mount_options: Vec<String>,
+}
+
+impl Default for BtrfsLayout {
+    // Snapper compatible layout
+    fn default() -> Self {
+        BtrfsLayout {
+            subvolumes: vec![
+                Subvolume::new("@", "/"),
+                Subvolume::new("@home", "/home"),
+                Subvolume::new("@log", "/var/log"),
+                Subvolume::new("@pkg", "/var/cache/pacman/pkg"),
+                Subvolume::new("@snapshots", "/.snapshots"),
+            ],
+            mount_options: vec!["compress=zstd".to_string(), "noatime".to_string()],
+        }
+    }
+}
+
+impl BtrfsLayout {
+    pub fn validate(&self) -> Result<()> {
+        let fail = |reason: String| Err(InstallerError::PreconditionFailed(reason));
+
+        if !self.subvolumes.iter().any(|s| s.mountpoint == "/") {
+            return fail("The btrfs layout needs a subvolume mounted on /".to_string());
+        }
+        for (i, subvolume) in self.subvolumes.iter().enumerate() {
+            if subvolume.name.is_empty() || subvolume.name.contains('/') {
+                return fail(format!("Invalid subvolume name \"{}\"", subvolume.name));
+            }
+            if !subvolume.mountpoint.starts_with('/') {
+                return fail(format!(
+                    "Mount point of {} must be an absolute path",
+                    subvolume.name
+                ));
+            }
+            let duplicate = self.subvolumes[..i]
+                .iter()
+                .any(|s| s.name == subvolume.name || s.mountpoint == subvolume.mountpoint);
+            if duplicate {
+                return fail(format!("Subvolume {} is listed twice", subvolume.name));
+            }
+        }
+        Ok(())
+    }
+
+    // Drop subvolumes whose mount point is served by a separate partition
+    pub fn without_mountpoint(&self, mountpoint: &str) -> BtrfsLayout {
+        BtrfsLayout {
+            subvolumes: self
+                .subvolumes
+                .iter()
+                .filter(|s| s.mountpoint != mountpoint)
+                .cloned()
+                .collect(),
+            mount_options: self.mount_options.clone(),
+        }
+    }
+
+    /// Mounts of every subvolume of `device` below `target`, parents before children.
+    pub fn mounts(&self, device: &str, target: &Path) -> Vec<Mount> {
+        let mut subvolumes: Vec<&Subvolume> = self.subvolumes.iter().collect();
+        subvolumes.sort_by_key(|s| mount_depth(&s.mountpoint));
+
+        subvolumes
+            .into_iter()
+            .map(|subvolume| {
+                let mut mount = Mount::new(device, target_path(target, &subvolume.mountpoint))
+                    .fstype("btrfs")
+                    .option(format!("subvol=/{}", subvolume.name));
+                for option in &self.mount_options {
+                    mount = mount.option(option.as_str());
+                }
+                mount
+            })
+            .collect()
+    }
+}
+
+/// Create every subvolume of `layout` on the freshly formatted btrfs `device`.
+pub fn create_subvolumes(
+    runner: &dyn CommandRunner,
+    mounts: &mut MountManager,
+    device: &str,
+    layout: &BtrfsLayout,
+    scratch: &Path,
+) -> Result<()> {
+    layout.validate()?;
+
+    // Subvolumes are created from the top level of the filesystem
+    mounts.mount(&Mount::new(device, scratch).fstype("btrfs"))?;
+    for subvolume in &layout.subvolumes {
+        let path = scratch.join(&subvolume.name);
+        runner.run_checked(
+            &Cmd::new("btrfs").args(["subvolume", "create", &path.to_string_lossy()]),
+        )?;
+    }
+    mounts.unmount(scratch)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::MockRunner;
+
+    fn layout(subvolumes: &[(&str, &str)]) -> BtrfsLayout {
+        BtrfsLayout {
+            subvolumes: subvolumes
+                .iter()
+                .map(|(name, mountpoint)| Subvolume::new(*name, *mountpoint))
+                .collect(),
+            ..BtrfsLayout::default()
+        }
+    }
+
+    #[test]
+    fn validate_rejects_broken_layouts() {
+        assert!(BtrfsLayout::default().validate().is_ok());
+
+        let error =
+            |subvolumes: &[(&str, &str)]| layout(subvolumes).validate().unwrap_err().to_string();
+        assert_eq!(
+            error(&[("@home", "/home")]),
+            "The btrfs layout needs a subvolume mounted on /"
+        );
+        assert_eq!(
+            error(&[("@", "/"), ("@", "/home")]),
+            "Subvolume @ is listed twice"
+        );
+        assert_eq!(
+            error(&[("@", "/"), ("@root", "/")]),
+            "Subvolume @root is listed twice"
+        );
+        assert_eq!(
+            error(&[("@", "/"), ("@log", "var/log")]),
+            "Mount point of @log must be an absolute path"
+        );
+        assert_eq!(
+            error(&[("@", "/"), ("@/home", "/home")]),
+            "Invalid subvolume name \"@/home\""
+        );
+        assert_eq!(error(&[("", "/")]), "Invalid subvolume name \"\"");
+    }
+
+    #[test]
+    fn mounts_put_the_root_before_nested_subvolumes() {
+        let layout = layout(&[
+            ("@pkg", "/var/cache/pacman/pkg"),
+            ("@home", "/home"),
+            ("@", "/"),
+        ]);
+        let mounts = layout.mounts("/dev/sda2", Path::new("/mnt"));
+
+        let targets: Vec<&Path> = mounts.iter().map(|m| m.target.as_path()).collect();
+        assert_eq!(
+            targets,
+            [
+                Path::new("/mnt"),
+                Path::new("/mnt/home"),
+                Path::new("/mnt/var/cache/pacman/pkg"),
+            ]
+        );
+        assert_eq!(
+            mounts[0],
+            Mount::new("/dev/sda2", "/mnt")
+                .fstype("btrfs")
+                .option("subvol=/@")
+                .option("compress=zstd")
+                .option("noatime")
+        );
+    }
+
+    #[test]
+    fn subvolumes_are_created_from_the_top_level() {
+        let runner = MockRunner::new();
+        let mut mounts = MountManager::new(&runner);
+        let layout = layout(&[("@", "/"), ("@home", "/home")]);
+        create_subvolumes(
+            &runner,
+            &mut mounts,
+            "/dev/sda2",
+            &layout,
+            Path::new("/mnt"),
+        )
+        .unwrap();
+        drop(mounts);
+
+        assert_eq!(
+            runner.command_lines(),
+            [
+                "mkdir -p /mnt",
+                "mount -t btrfs /dev/sda2 /mnt",
+                "btrfs subvolume create /mnt/@",
+                "btrfs subvolume create /mnt/@home",
+                "umount /mnt",
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_layouts_are_refused_before_mounting() {
+        let runner = MockRunner::new();
+        let mut mounts = MountManager::new(&runner);
+        let layout = layout(&[("@home", "/home")]);
+        assert!(create_subvolumes(
+            &runner,
+            &mut mounts,
+            "/dev/sda2",
+            &layout,
+            Path::new("/mnt")
+        )
+        .is_err());
+        drop(mounts);
+        assert!(runner.invocations().is_empty());
+    }
+
+    #[test]
+    fn a_separate_home_drops_its_subvolume() {
+        let layout = BtrfsLayout::default().without_mountpoint("/home");
+        assert!(!layout.subvolumes.iter().any(|s| s.name == "@home"));
+        assert_eq!(layout.subvolumes.len(), 4);
+        assert_eq!(layout.mount_options, BtrfsLayout::default().mount_options);
+    }
+}
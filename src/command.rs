@@ -0,0 +1,560 @@
+use std::{
+    fmt, fs,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{mpsc, Mutex},
+    thread,
+};
+
+use crate::error::{InstallerError, Result};
+
+// Stand-in for passphrases piped to commands
+pub const REDACTED: &str = "<redacted>";
+
+/// A single invocation of an external program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cmd {
+    pub program: String,
+    pub args: Vec<String>,
+    pub stdin: Option<String>,
+    // Standard input holds a passphrase and must never be shown or recorded
+    pub secret_stdin: bool,
+    // Only inspects the system, so it is safe to run even in dry-run mode
+    pub read_only: bool,
+}
+
+impl Cmd {
+    pub fn new(program: impl Into<String>) -> Self {
+        Cmd {
+            program: program.into(),
+            args: Vec::new(),
+            stdin: None,
+            secret_stdin: false,
+            read_only: false,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    // Data piped to the program's standard input
+    pub fn stdin(mut self, input: impl Into<String>) -> Self {
+        self.stdin = Some(input.into());
+        self
+    }
+
+    pub fn secret_stdin(mut self, input: impl Into<String>) -> Self {
+        self.stdin = Some(input.into());
+        self.secret_stdin = true;
+        self
+    }
+
+    // `program` run inside the target system
+    pub fn chroot(target: &Path, program: impl Into<String>) -> Self {
+        Cmd::new("arch-chroot")
+            .arg(target.to_string_lossy())
+            .arg(program)
+    }
+
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    // Copy that is safe to keep or show, with a secret stdin replaced by a marker
+    pub fn redacted(&self) -> Cmd {
+        let mut cmd = self.clone();
+        if cmd.secret_stdin {
+            cmd.stdin = cmd.stdin.map(|_| REDACTED.to_string());
+        }
+        cmd
+    }
+}
+
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", shell_quote(arg))?;
+        }
+        Ok(())
+    }
+}
+
+// Quote an argument so the printed command line can be pasted into a shell
+pub fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    // Exit code, `None` when the process was killed by a signal
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(stdout: impl Into<String>) -> Self {
+        CommandOutput {
+            code: Some(0),
+            stdout: stdout.into(),
+            stderr: String::new(),
+        }
+    }
+
+    pub fn failure(code: i32, stderr: impl Into<String>) -> Self {
+        CommandOutput {
+            code: Some(code),
+            stdout: String::new(),
+            stderr: stderr.into(),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// Everything the installer does to the system goes through a `CommandRunner`.
+pub trait CommandRunner {
+    /// Run `cmd` to completion, whatever its exit status.
+    fn run(&self, cmd: &Cmd) -> Result<CommandOutput>;
+
+    /// Run `cmd` and turn a non-zero exit status into `InstallerError::CommandFailed`.
+    fn run_checked(&self, cmd: &Cmd) -> Result<CommandOutput> {
+        let output = self.run(cmd)?;
+        if output.is_success() {
+            Ok(output)
+        } else {
+            Err(InstallerError::CommandFailed {
+                program: cmd.program.clone(),
+                code: output.code,
+                stderr: output.stderr,
+            })
+        }
+    }
+
+    /// Like `run_checked`, but hands every line of stdout and stderr to
+    /// `on_line` as it is printed. Returning false from `on_line` kills the
+    /// program and fails with `InstallerError::Cancelled`.
+    fn run_streaming(&self, cmd: &Cmd, on_line: &mut dyn FnMut(&str) -> bool) -> Result<CommandOutput> {
+        let output = self.run_checked(cmd)?;
+        for line in output.stdout.lines().chain(output.stderr.lines()) {
+            if !on_line(line) {
+                return Err(InstallerError::Cancelled);
+            }
+        }
+        Ok(output)
+    }
+
+    /// Create or replace `path` with `contents`, creating parent directories.
+    fn write_file(&self, path: &Path, contents: &str) -> Result<()>;
+
+    /// Mount `source` on `target`, creating the mount point if needed.
+    fn mount(&self, mount: &Mount) -> Result<()> {
+        self.run_checked(&Cmd::new("mkdir").args(["-p", &mount.target.to_string_lossy()]))?;
+        self.run_checked(&mount.to_cmd())?;
+        Ok(())
+    }
+
+    /// Whether commands are only being recorded rather than executed.
+    fn is_dry_run(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    pub source: String,
+    pub target: PathBuf,
+    pub fstype: Option<String>,
+    pub options: Vec<String>,
+}
+
+impl Mount {
+    pub fn new(source: impl Into<String>, target: impl Into<PathBuf>) -> Self {
+        Mount {
+            source: source.into(),
+            target: target.into(),
+            fstype: None,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn fstype(mut self, fstype: impl Into<String>) -> Self {
+        self.fstype = Some(fstype.into());
+        self
+    }
+
+    pub fn option(mut self, option: impl Into<String>) -> Self {
+        self.options.push(option.into());
+        self
+    }
+
+    pub fn to_cmd(&self) -> Cmd {
+        let mut cmd = Cmd::new("mount");
+        if let Some(fstype) = &self.fstype {
+            cmd = cmd.args(["-t", fstype]);
+        }
+        if !self.options.is_empty() {
+            cmd = cmd.args(["-o".to_string(), self.options.join(",")]);
+        }
+        cmd.arg(self.source.as_str())
+            .arg(self.target.to_string_lossy())
+    }
+}
+
+// Number of path components, "/" is 0 and "/var/log" is 2
+pub fn mount_depth(mountpoint: &str) -> usize {
+    mountpoint.split('/').filter(|c| !c.is_empty()).count()
+}
+
+// `mountpoint` of the installed system as seen from the live system
+pub fn target_path(target: &Path, mountpoint: &str) -> PathBuf {
+    match mountpoint.trim_start_matches('/') {
+        "" => target.to_path_buf(),
+        relative => target.join(relative),
+    }
+}
+
+/// Runs commands for real on the host.
+#[derive(Debug, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, cmd: &Cmd) -> Result<CommandOutput> {
+        let mut child = Command::new(&cmd.program)
+            .args(&cmd.args)
+            .stdin(if cmd.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| InstallerError::spawn(&cmd.program, err))?;
+
+        if let (Some(input), Some(mut stdin)) = (&cmd.stdin, child.stdin.take()) {
+            stdin.write_all(input.as_bytes())?;
+            // `stdin` is dropped here so the child sees EOF
+        }
+
+        let output = child.wait_with_output()?;
+        Ok(CommandOutput {
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+
+    fn run_streaming(&self, cmd: &Cmd, on_line: &mut dyn FnMut(&str) -> bool) -> Result<CommandOutput> {
+        let mut child = Command::new(&cmd.program)
+            .args(&cmd.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| InstallerError::spawn(&cmd.program, err))?;
+
+        // Both pipes feed one channel so lines show up in the order they are printed
+        let (sender, receiver) = mpsc::channel();
+        let readers = [
+            forward_lines(child.stdout.take(), sender.clone(), false),
+            forward_lines(child.stderr.take(), sender, true),
+        ];
+
+        let mut output = CommandOutput::default();
+        for (line, is_stderr) in receiver {
+            if !on_line(&line) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(InstallerError::Cancelled);
+            }
+            let buffer = if is_stderr {
+                &mut output.stderr
+            } else {
+                &mut output.stdout
+            };
+            buffer.push_str(&line);
+            buffer.push('\n');
+        }
+        for reader in readers {
+            let _ = reader.join();
+        }
+
+        output.code = child.wait()?.code();
+        if !output.is_success() {
+            return Err(InstallerError::CommandFailed {
+                program: cmd.program.clone(),
+                code: output.code,
+                stderr: output.stderr,
+            });
+        }
+        Ok(output)
+    }
+
+    fn write_file(&self, path: &Path, contents: &str) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+// Send each line read from `pipe` to `sender`, tagged with which stream it came from
+fn forward_lines<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    sender: mpsc::Sender<(String, bool)>,
+    is_stderr: bool,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let Some(pipe) = pipe else { return };
+        for line in BufReader::new(pipe).lines() {
+            let Ok(line) = line else { break };
+            if sender.send((line, is_stderr)).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+#[derive(Debug)]
+struct MockResponse {
+    program: String,
+    // `None` matches any arguments
+    args: Option<Vec<String>>,
+    output: CommandOutput,
+}
+
+/// Returns canned responses and records every invocation, for running the
+/// install flow without touching the host.
+#[derive(Debug, Default)]
+pub struct MockRunner {
+    responses: Mutex<Vec<MockResponse>>,
+    invocations: Mutex<Vec<Cmd>>,
+    files: Mutex<Vec<(PathBuf, String)>>,
+}
+
+impl MockRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Respond to any invocation of `program` with `output`
+    pub fn on(&self, program: &str, output: CommandOutput) -> &Self {
+        self.responses.lock().unwrap().push(MockResponse {
+            program: program.to_string(),
+            args: None,
+            output,
+        });
+        self
+    }
+
+    // Respond to `program` called with exactly `args`; takes precedence over `on`
+    pub fn on_args(&self, program: &str, args: &[&str], output: CommandOutput) -> &Self {
+        let args = args.iter().map(|a| a.to_string()).collect();
+        self.responses.lock().unwrap().push(MockResponse {
+            program: program.to_string(),
+            args: Some(args),
+            output,
+        });
+        self
+    }
+
+    pub fn invocations(&self) -> Vec<Cmd> {
+        self.invocations.lock().unwrap().clone()
+    }
+
+    // Every `write_file` call, in order
+    pub fn written_files(&self) -> Vec<(PathBuf, String)> {
+        self.files.lock().unwrap().clone()
+    }
+
+    // Invocations rendered as command lines, handy for comparing against expectations
+    pub fn command_lines(&self) -> Vec<String> {
+        self.invocations().iter().map(Cmd::to_string).collect()
+    }
+}
+
+impl CommandRunner for MockRunner {
+    fn run(&self, cmd: &Cmd) -> Result<CommandOutput> {
+        // Passphrases and password hashes are never kept, not even in tests
+        self.invocations.lock().unwrap().push(cmd.redacted());
+
+        let responses = self.responses.lock().unwrap();
+        let matching = |exact: bool| {
+            responses.iter().rev().find(|r| {
+                r.program == cmd.program
+                    && match &r.args {
+                        Some(args) => exact && *args == cmd.args,
+                        None => !exact,
+                    }
+            })
+        };
+
+        // Unknown commands succeed silently
+        Ok(matching(true)
+            .or_else(|| matching(false))
+            .map(|r| r.output.clone())
+            .unwrap_or_else(|| CommandOutput::success("")))
+    }
+
+    fn write_file(&self, path: &Path, contents: &str) -> Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .push((path.to_path_buf(), contents.to_string()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_records_secret_stdin_redacted() {
+        let runner = MockRunner::new();
+        runner
+            .run(&Cmd::new("cryptsetup").secret_stdin("hunter2"))
+            .unwrap();
+        runner
+            .run(&Cmd::new("sfdisk").stdin("label: gpt\n"))
+            .unwrap();
+
+        let invocations = runner.invocations();
+        assert_eq!(invocations[0].stdin.as_deref(), Some(REDACTED));
+        assert_eq!(invocations[1].stdin.as_deref(), Some("label: gpt\n"));
+        assert!(!format!("{:?}", invocations).contains("hunter2"));
+    }
+
+    #[test]
+    fn mock_prefers_exact_arguments() {
+        let runner = MockRunner::new();
+        runner.on("blkid", CommandOutput::success("any"));
+        runner.on_args("blkid", &["/dev/sda1"], CommandOutput::success("exact"));
+
+        let output = |arg: &str| runner.run(&Cmd::new("blkid").arg(arg)).unwrap().stdout;
+        assert_eq!(output("/dev/sda1"), "exact");
+        assert_eq!(output("/dev/sda2"), "any");
+        assert!(runner.run(&Cmd::new("true")).unwrap().is_success());
+    }
+
+    #[test]
+    fn run_checked_fails_on_non_zero_exit() {
+        let runner = MockRunner::new();
+        runner.on("false", CommandOutput::failure(1, "nope"));
+        match runner.run_checked(&Cmd::new("false")) {
+            Err(InstallerError::CommandFailed {
+                program,
+                code,
+                stderr,
+            }) => {
+                assert_eq!(
+                    (program.as_str(), code, stderr.as_str()),
+                    ("false", Some(1), "nope")
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mock_records_mounts_and_files_without_touching_the_host() {
+        let runner = MockRunner::new();
+        let mount = Mount::new("/dev/sda1", "/mnt/boot")
+            .fstype("vfat")
+            .option("fmask=0077")
+            .option("dmask=0077");
+        runner.mount(&mount).unwrap();
+        runner
+            .write_file(Path::new("/mnt/etc/hostname"), "arch\n")
+            .unwrap();
+
+        assert_eq!(
+            runner.command_lines(),
+            [
+                "mkdir -p /mnt/boot",
+                "mount -t vfat -o fmask=0077,dmask=0077 /dev/sda1 /mnt/boot",
+            ]
+        );
+        assert_eq!(
+            runner.written_files(),
+            [(PathBuf::from("/mnt/etc/hostname"), "arch\n".to_string())]
+        );
+        assert!(!runner.is_dry_run());
+    }
+
+    #[test]
+    fn mock_streams_canned_output_line_by_line() {
+        let runner = MockRunner::new();
+        runner.on("pacstrap", CommandOutput::success("one\ntwo\nthree\n"));
+        let mut seen = Vec::new();
+        runner
+            .run_streaming(&Cmd::new("pacstrap"), &mut |line| {
+                seen.push(line.to_string());
+                true
+            })
+            .unwrap();
+        assert_eq!(seen, ["one", "two", "three"]);
+
+        let result = runner.run_streaming(&Cmd::new("pacstrap"), &mut |line| line != "two");
+        assert!(matches!(result, Err(InstallerError::Cancelled)));
+    }
+
+    #[test]
+    fn cancelling_a_stream_kills_the_program() {
+        let started = std::time::Instant::now();
+        let mut seen = Vec::new();
+        let result = SystemRunner.run_streaming(
+            &Cmd::new("sh").args(["-c", "echo one; echo two; sleep 30; echo three"]),
+            &mut |line| {
+                seen.push(line.to_string());
+                line != "two"
+            },
+        );
+        assert!(matches!(result, Err(InstallerError::Cancelled)));
+        assert_eq!(seen, ["one", "two"]);
+        assert!(started.elapsed().as_secs() < 10);
+    }
+
+    #[test]
+    fn command_lines_are_shell_quoted() {
+        let cmd = Cmd::new("sed").args(["-i", "s|a b|c|", "/etc/x"]);
+        assert_eq!(cmd.to_string(), "sed -i 's|a b|c|' /etc/x");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn target_paths_stay_inside_the_target() {
+        let target = Path::new("/mnt");
+        assert_eq!(target_path(target, "/"), PathBuf::from("/mnt"));
+        assert_eq!(
+            target_path(target, "/var/log"),
+            PathBuf::from("/mnt/var/log")
+        );
+        assert_eq!(mount_depth("/"), 0);
+        assert_eq!(mount_depth("/var/log"), 2);
+    }
+}
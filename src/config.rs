@@ -0,0 +1,423 @@
+use std::{
+    fs::{self, OpenOptions, Permissions},
+    io::{self, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bootloader::Bootloader,
+    btrfs::BtrfsLayout,
+    crypt::{Encryption, LuksKey},
+    error::{InstallerError, Result},
+    format::FilesystemKind,
+    fstab::FstabIdentifier,
+    gpt::{GptEntry, GptTable},
+    locale::LocaleSettings,
+    network::{self, NetworkSettings},
+    pacstrap::PackageSet,
+    partition::{PartitionPlan, PartitionRole},
+    raid::MultiDisk,
+    users::User,
+};
+
+/// Every choice that drives an install. Serializes to the TOML install
+/// profile used for unattended installs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InstallConfig {
+    // Target disk, e.g. /dev/nvme0n1. Required for unattended installs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk: Option<String>,
+    pub hostname: String,
+    pub network: NetworkSettings,
+    pub locale: LocaleSettings,
+    // Picked from the boot mode when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bootloader: Option<Bootloader>,
+    pub fstab_identifier: FstabIdentifier,
+    pub partitions: PartitionPlan,
+    // Table from the manual partition editor, used instead of `partitions` when set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manual_partitions: Option<GptTable>,
+    // Disks used besides `disk`, for /home or a mirror
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multi_disk: Option<MultiDisk>,
+    // Only used when the root partition is btrfs
+    pub btrfs: BtrfsLayout,
+    // LUKS2 on the root partition
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    pub packages: PackageSet,
+    // Locked when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root_password_hash: Option<String>,
+    pub users: Vec<User>,
+    // Let members of wheel use sudo
+    pub sudo_wheel: bool,
+}
+
+impl Default for InstallConfig {
+    fn default() -> Self {
+        InstallConfig {
+            disk: None,
+            hostname: "archlinux".to_string(),
+            network: NetworkSettings::default(),
+            locale: LocaleSettings::default(),
+            bootloader: None,
+            fstab_identifier: FstabIdentifier::default(),
+            partitions: PartitionPlan::default(),
+            manual_partitions: None,
+            multi_disk: None,
+            btrfs: BtrfsLayout::default(),
+            encryption: None,
+            packages: PackageSet::default(),
+            root_password_hash: None,
+            users: Vec::new(),
+            sudo_wheel: false,
+        }
+    }
+}
+
+impl InstallConfig {
+    pub fn from_toml(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|err| InstallerError::parse("install profile", err))
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).map_err(|err| InstallerError::parse("install profile", err))
+    }
+
+    /// TOML profile for replaying this install later. Plain text secrets are
+    /// replaced by an "omitted" marker, passwords are only ever kept as hashes.
+    pub fn export_profile(&self) -> Result<String> {
+        let mut config = self.clone();
+        let mut omitted = Vec::new();
+        if let Some(encryption) = &mut config.encryption {
+            if let LuksKey::Passphrase(_) = encryption.key {
+                encryption.key = LuksKey::Omitted;
+                omitted.push("encryption.key: the LUKS passphrase, replace with key = { keyfile = \"/path\" }");
+            }
+        }
+
+        let mut text = String::from("# Install profile exported by archinstaller\n");
+        text.push_str("# Replay with: archinstaller --config <this file>\n");
+        text.push_str("# Passwords are stored as SHA-512 crypt hashes, never in plain text\n");
+        for secret in omitted {
+            text.push_str("# OMITTED SECRET ");
+            text.push_str(secret);
+            text.push('\n');
+        }
+        text.push('\n');
+        text.push_str(&config.to_toml()?);
+        Ok(text)
+    }
+
+    /// Write an exported profile to `path`, readable by its owner only since
+    /// it holds password hashes. A file already there is tightened as well.
+    pub fn save_profile(path: &Path, profile: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        file.set_permissions(Permissions::from_mode(0o600))?;
+        file.write_all(profile.as_bytes())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Filesystem of the root partition, from the manual table when there is one.
+    pub fn root_filesystem(&self) -> Option<FilesystemKind> {
+        match &self.manual_partitions {
+            Some(table) => table
+                .find(PartitionRole::Root)
+                .and_then(GptEntry::filesystem),
+            None => self
+                .partitions
+                .find(PartitionRole::Root)
+                .map(|root| root.filesystem.kind),
+        }
+    }
+
+    /// Every filesystem the installed system uses.
+    pub fn filesystems(&self) -> Vec<FilesystemKind> {
+        match &self.manual_partitions {
+            Some(table) => table
+                .entries
+                .iter()
+                .filter(|e| e.role.is_some())
+                .filter_map(GptEntry::filesystem)
+                .collect(),
+            None => {
+                let mut kinds: Vec<FilesystemKind> = self
+                    .partitions
+                    .partitions
+                    .iter()
+                    .map(|p| p.filesystem.kind)
+                    .collect();
+                if let Some(MultiDisk::SeparateHome { .. }) = self.multi_disk {
+                    kinds.push(FilesystemKind::default_for(PartitionRole::Home));
+                }
+                kinds
+            }
+        }
+    }
+
+    /// Whether partitions already on the disk stay, so other systems may be
+    /// installed next to this one.
+    pub fn keeps_other_systems(&self) -> bool {
+        self.manual_partitions
+            .as_ref()
+            .is_some_and(GptTable::keeps_existing)
+    }
+
+    /// Whether the installed system boots from mdadm arrays.
+    pub fn uses_mdadm(&self) -> bool {
+        self.multi_disk.as_ref().is_some_and(MultiDisk::uses_mdadm)
+    }
+
+    /// Checks that only need the profile, not the target disk itself.
+    pub fn validate(&self) -> Result<()> {
+        if self.disk.is_none() {
+            return Err(InstallerError::PreconditionFailed(
+                "The profile does not name a disk".to_string(),
+            ));
+        }
+        network::validate_hostname(&self.hostname)?;
+        self.locale.validate()?;
+        if let Some(encryption) = &self.encryption {
+            encryption.validate()?;
+        }
+        if let Some(multi) = &self.multi_disk {
+            multi.validate(self)?;
+        }
+        for user in &self.users {
+            user.validate()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypt::EncryptHook, format::FilesystemSpec, network::NetworkStack,
+        partition::PartitionSpec, raid::MirrorKind, testing::TempDir,
+    };
+
+    const PROFILE: &str = r#"
+disk = "/dev/nvme0n1"
+hostname = "workstation"
+bootloader = "systemd-boot"
+sudo_wheel = true
+
+[locale]
+locales = ["de_DE.UTF-8", "en_US.UTF-8"]
+keymap = "de-latin1"
+timezone = "Europe/Berlin"
+
+[network]
+stack = "network-manager"
+
+[partitions]
+layout = [
+    { role = "esp", size_mib = 512 },
+    { role = "swap", size_mib = 4096 },
+    { role = "root", filesystem = { kind = "btrfs", label = "arch" } },
+]
+
+[encryption]
+key = { keyfile = "/root/luks.key" }
+hook = "sd-encrypt"
+
+[packages]
+extra = ["vim", "git"]
+
+[[users]]
+name = "alice"
+groups = ["wheel"]
+"#;
+
+    // A profile that passes validation, for tests that break one thing
+    fn valid() -> InstallConfig {
+        InstallConfig {
+            disk: Some("/dev/sda".to_string()),
+            ..InstallConfig::default()
+        }
+    }
+
+    #[test]
+    fn hand_written_profile_parses() {
+        let config = InstallConfig::from_toml(PROFILE).unwrap();
+        assert_eq!(config.disk.as_deref(), Some("/dev/nvme0n1"));
+        assert_eq!(config.hostname, "workstation");
+        assert_eq!(config.bootloader, Some(Bootloader::SystemdBoot));
+        assert!(config.sudo_wheel);
+        assert_eq!(config.locale.locales, ["de_DE.UTF-8", "en_US.UTF-8"]);
+        assert_eq!(config.locale.timezone, "Europe/Berlin");
+        assert_eq!(config.network.stack, Some(NetworkStack::NetworkManager));
+        assert_eq!(
+            config.partitions.partitions,
+            [
+                PartitionSpec::new(PartitionRole::Esp, Some(512)),
+                PartitionSpec::new(PartitionRole::Swap, Some(4096)),
+                PartitionSpec::new(PartitionRole::Root, None)
+                    .filesystem(FilesystemSpec::new(FilesystemKind::Btrfs).label("arch")),
+            ]
+        );
+        let encryption = config.encryption.as_ref().unwrap();
+        assert_eq!(encryption.key, LuksKey::Keyfile("/root/luks.key".into()));
+        assert_eq!(encryption.hook, EncryptHook::SdEncrypt);
+        assert_eq!(config.packages.extra, ["vim", "git"]);
+        assert_eq!(config.users[0].name, "alice");
+        assert_eq!(config.users[0].password_hash, None);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn omitted_fields_take_their_defaults() {
+        let config = InstallConfig::from_toml(PROFILE).unwrap();
+        let defaults = InstallConfig::default();
+        assert_eq!(config.fstab_identifier, defaults.fstab_identifier);
+        assert_eq!(config.btrfs, defaults.btrfs);
+        assert_eq!(config.packages.base, defaults.packages.base);
+        assert_eq!(config.root_password_hash, None);
+        assert_eq!(config.multi_disk, None);
+        assert!(!config.network.copy_live_profiles);
+
+        let minimal = InstallConfig::from_toml("disk = \"/dev/sda\"\n").unwrap();
+        assert_eq!(minimal, valid());
+    }
+
+    #[test]
+    fn profiles_round_trip_through_toml() {
+        let mut config = InstallConfig::from_toml(PROFILE).unwrap();
+        config.multi_disk = Some(MultiDisk::Mirror {
+            disks: vec!["/dev/nvme1n1".to_string()],
+            raid: MirrorKind::Mdadm,
+        });
+        config.root_password_hash = Some("$6$salt$hash".to_string());
+        let text = config.to_toml().unwrap();
+        assert_eq!(InstallConfig::from_toml(&text).unwrap(), config);
+        assert_eq!(
+            InstallConfig::from_toml(&valid().to_toml().unwrap()).unwrap(),
+            valid()
+        );
+    }
+
+    #[test]
+    fn bad_profiles_are_rejected() {
+        valid().validate().unwrap();
+
+        let no_disk = InstallConfig::default();
+        assert!(no_disk
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("does not name a disk"));
+
+        let mut twice = valid();
+        twice.multi_disk = Some(MultiDisk::Mirror {
+            disks: vec!["/dev/sdb".to_string(), "/dev/sdb".to_string()],
+            raid: MirrorKind::Mdadm,
+        });
+        assert!(twice
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("/dev/sdb is used twice"));
+        twice.multi_disk = Some(MultiDisk::SeparateHome {
+            disk: "/dev/sda".to_string(),
+        });
+        assert!(twice
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("/dev/sda is used twice"));
+
+        let mut omitted = valid();
+        omitted.encryption = Some(Encryption::new(LuksKey::Omitted));
+        assert!(omitted
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("omitted"));
+
+        assert!(InstallConfig::from_toml("disk = 1").is_err());
+        assert!(InstallConfig::from_toml("[partitions]\nlayout = [{ size_mib = 512 }]").is_err());
+    }
+
+    #[test]
+    fn exported_profiles_keep_no_plain_text_secrets() {
+        let mut config = InstallConfig::from_toml(PROFILE).unwrap();
+        config.encryption = Some(Encryption::new(LuksKey::Passphrase(
+            "correct horse battery staple".to_string(),
+        )));
+        config.root_password_hash = Some("$6$rounds=5000$salt$roothash".to_string());
+        config.users[0].password_hash = Some("$6$rounds=5000$salt$alicehash".to_string());
+
+        let profile = config.export_profile().unwrap();
+        assert!(!profile.contains("correct horse"), "{}", profile);
+        assert!(
+            profile.contains("\n# OMITTED SECRET encryption.key: "),
+            "{}",
+            profile
+        );
+
+        let replayed = InstallConfig::from_toml(&profile).unwrap();
+        assert_eq!(replayed.encryption.as_ref().unwrap().key, LuksKey::Omitted);
+        // Hashes are kept so the accounts work after a replay
+        assert_eq!(replayed.root_password_hash, config.root_password_hash);
+        assert_eq!(replayed.users, config.users);
+        // Everything else replays as it was
+        assert_eq!(
+            InstallConfig {
+                encryption: config.encryption.clone(),
+                ..replayed
+            },
+            config
+        );
+    }
+
+    #[test]
+    fn keyfiles_are_exported_as_they_are() {
+        let config = InstallConfig::from_toml(PROFILE).unwrap();
+        let profile = config.export_profile().unwrap();
+        assert!(!profile.contains("OMITTED SECRET"));
+        assert_eq!(InstallConfig::from_toml(&profile).unwrap(), config);
+    }
+
+    #[test]
+    fn saved_profiles_are_only_readable_by_their_owner() {
+        let dir = TempDir::new();
+        let created = dir.path().join("profile.toml");
+        InstallConfig::save_profile(&created, "disk = \"/dev/sda\"\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(&created).unwrap(),
+            "disk = \"/dev/sda\"\n"
+        );
+        assert_eq!(
+            fs::metadata(&created).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let existing = dir.write("existing.toml", "a much longer profile than the new one\n");
+        fs::set_permissions(&existing, Permissions::from_mode(0o644)).unwrap();
+        InstallConfig::save_profile(&existing, "hostname = \"box\"\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(&existing).unwrap(),
+            "hostname = \"box\"\n"
+        );
+        assert_eq!(
+            fs::metadata(&existing).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use std::{fmt, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{Cmd, CommandRunner},
+    error::{InstallerError, Result},
+};
+
+// Name of the opened root container under /dev/mapper
+pub const ROOT_MAPPER: &str = "cryptroot";
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LuksKey {
+    Passphrase(String),
+    // Read the key from a file, used by unattended installs and tests
+    Keyfile(PathBuf),
+    // Left out of an exported profile, has to be filled in before replaying it
+    Omitted,
+}
+
+// Never print the passphrase, not even in debug output
+impl fmt::Debug for LuksKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LuksKey::Passphrase(_) => write!(f, "Passphrase(<redacted>)"),
+            LuksKey::Keyfile(path) => f.debug_tuple("Keyfile").field(path).finish(),
+            LuksKey::Omitted => write!(f, "Omitted"),
+        }
+    }
+}
+
+impl LuksKey {
+    // Add the key to a cryptsetup invocation
+    fn apply(&self, cmd: Cmd) -> Cmd {
+        match self {
+            // With "-" all of stdin is the key, so no newline is appended
+            LuksKey::Passphrase(passphrase) => cmd
+                .args(["--key-file", "-"])
+                .secret_stdin(passphrase.as_str()),
+            LuksKey::Keyfile(path) => cmd.arg("--key-file").arg(path.to_string_lossy()),
+            // `validate` rejects this before any command is built
+            LuksKey::Omitted => cmd,
+        }
+    }
+}
+
+/// Which mkinitcpio hook unlocks the root container at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncryptHook {
+    // Busybox based initramfs with the `encrypt` hook
+    #[default]
+    Encrypt,
+    // systemd based initramfs with the `sd-encrypt` hook
+    SdEncrypt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Encryption {
+    pub key: LuksKey,
+    #[serde(default)]
+    pub hook: EncryptHook,
+}
+
+impl Encryption {
+    pub fn new(key: LuksKey) -> Self {
+        Encryption {
+            key,
+            hook: EncryptHook::default(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match &self.key {
+            LuksKey::Passphrase(passphrase) if passphrase.is_empty() => {
+                Err(InstallerError::PreconditionFailed(
+                    "The encryption passphrase is empty".to_string(),
+                ))
+            }
+            LuksKey::Omitted => Err(InstallerError::PreconditionFailed(
+                "The encryption key was omitted from the profile, set encryption.key to a keyfile"
+                    .to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Kernel parameters that unlock `luks_uuid` and mount it as root.
+    pub fn kernel_params(&self, luks_uuid: &str) -> Vec<String> {
+        let unlock = match self.hook {
+            EncryptHook::Encrypt => format!("cryptdevice=UUID={}:{}", luks_uuid, ROOT_MAPPER),
+            EncryptHook::SdEncrypt => format!("rd.luks.name={}={}", luks_uuid, ROOT_MAPPER),
+        };
+        vec![unlock, format!("root={}", mapper_path(ROOT_MAPPER))]
+    }
+}
+
+pub fn mapper_path(name: &str) -> String {
+    format!("/dev/mapper/{}", name)
+}
+
+/// Turn `device` into a LUKS2 container and open it, returning the mapped device.
+pub fn setup_luks(
+    runner: &dyn CommandRunner,
+    device: &str,
+    encryption: &Encryption,
+) -> Result<String> {
+    encryption.validate()?;
+
+    let format = Cmd::new("cryptsetup").args(["luksFormat", "--type", "luks2", "--batch-mode"]);
+    runner.run_checked(&encryption.key.apply(format).arg(device))?;
+
+    let open = Cmd::new("cryptsetup").arg("open");
+    runner.run_checked(&encryption.key.apply(open).args([device, ROOT_MAPPER]))?;
+
+    Ok(mapper_path(ROOT_MAPPER))
+}
+
+pub fn close_luks(runner: &dyn CommandRunner, name: &str) -> Result<()> {
+    runner.run_checked(&Cmd::new("cryptsetup").args(["close", name]))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        command::{CommandOutput, MockRunner, SystemRunner, REDACTED},
+        testing::{self, LoopDevice, TempDir},
+    };
+
+    #[test]
+    fn setup_luks_formats_then_opens() {
+        let runner = MockRunner::new();
+        let encryption = Encryption::new(LuksKey::Keyfile(PathBuf::from("/root/luks.key")));
+        let mapper = setup_luks(&runner, "/dev/sda2", &encryption).unwrap();
+
+        assert_eq!(mapper, "/dev/mapper/cryptroot");
+        assert_eq!(
+            runner.command_lines(),
+            [
+                "cryptsetup luksFormat --type luks2 --batch-mode --key-file /root/luks.key /dev/sda2",
+                "cryptsetup open --key-file /root/luks.key /dev/sda2 cryptroot",
+            ]
+        );
+
+        close_luks(&runner, ROOT_MAPPER).unwrap();
+        assert_eq!(runner.command_lines()[2], "cryptsetup close cryptroot");
+    }
+
+    #[test]
+    fn setup_luks_stops_when_formatting_fails() {
+        let runner = MockRunner::new();
+        runner.on(
+            "cryptsetup",
+            CommandOutput::failure(1, "Device /dev/sda2 is in use"),
+        );
+        let encryption = Encryption::new(LuksKey::Passphrase("hunter2".to_string()));
+        let err = setup_luks(&runner, "/dev/sda2", &encryption).unwrap_err();
+
+        assert!(err.to_string().contains("in use"));
+        assert_eq!(runner.invocations().len(), 1);
+    }
+
+    #[test]
+    fn setup_luks_reports_a_container_that_does_not_open() {
+        let runner = MockRunner::new();
+        runner.on_args(
+            "cryptsetup",
+            &[
+                "open",
+                "--key-file",
+                "/root/luks.key",
+                "/dev/sda2",
+                "cryptroot",
+            ],
+            CommandOutput::failure(2, "No key available with this passphrase."),
+        );
+        let encryption = Encryption::new(LuksKey::Keyfile(PathBuf::from("/root/luks.key")));
+        let err = setup_luks(&runner, "/dev/sda2", &encryption).unwrap_err();
+
+        assert!(err.to_string().contains("No key available"));
+        assert_eq!(runner.invocations().len(), 2);
+    }
+
+    #[test]
+    fn passphrase_goes_through_redacted_stdin() {
+        let runner = MockRunner::new();
+        let encryption = Encryption::new(LuksKey::Passphrase("hunter2".to_string()));
+        setup_luks(&runner, "/dev/sda2", &encryption).unwrap();
+
+        let invocations = runner.invocations();
+        assert_eq!(
+            invocations[0].to_string(),
+            "cryptsetup luksFormat --type luks2 --batch-mode --key-file - /dev/sda2"
+        );
+        assert_eq!(
+            invocations[1].to_string(),
+            "cryptsetup open --key-file - /dev/sda2 cryptroot"
+        );
+        for cmd in &invocations {
+            assert!(cmd.secret_stdin);
+            assert_eq!(cmd.stdin.as_deref(), Some(REDACTED));
+        }
+        assert!(!format!("{:?}", encryption).contains("hunter2"));
+    }
+
+    #[test]
+    fn kernel_params_follow_the_hook() {
+        let uuid = "0f2b7c1e-5d3a-4e8f-9b6c-2a1d4e7f8c90";
+        let mut encryption = Encryption::new(LuksKey::Passphrase("hunter2".to_string()));
+        assert_eq!(
+            encryption.kernel_params(uuid),
+            [
+                "cryptdevice=UUID=0f2b7c1e-5d3a-4e8f-9b6c-2a1d4e7f8c90:cryptroot",
+                "root=/dev/mapper/cryptroot",
+            ]
+        );
+        encryption.hook = EncryptHook::SdEncrypt;
+        assert_eq!(
+            encryption.kernel_params(uuid),
+            [
+                "rd.luks.name=0f2b7c1e-5d3a-4e8f-9b6c-2a1d4e7f8c90=cryptroot",
+                "root=/dev/mapper/cryptroot",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_keys_are_refused_before_running_anything() {
+        let runner = MockRunner::new();
+        for key in [LuksKey::Passphrase(String::new()), LuksKey::Omitted] {
+            assert!(setup_luks(&runner, "/dev/sda2", &Encryption::new(key)).is_err());
+        }
+        assert!(runner.invocations().is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs root, losetup and cryptsetup"]
+    fn keyfile_formats_opens_and_closes_a_loop_device() {
+        if !testing::privileged(&["losetup", "cryptsetup"]) {
+            return;
+        }
+        // Room for the 16 MiB LUKS2 header and a little data
+        let image = LoopDevice::new(64 << 20);
+        let keys = TempDir::new();
+        let keyfile = keys.write("luks.key", "not a passphrase, a whole key file\n");
+        let encryption = Encryption::new(LuksKey::Keyfile(keyfile));
+
+        let mapper = setup_luks(&SystemRunner, image.path(), &encryption).unwrap();
+        let opened = Path::new(&mapper).exists();
+        let is_luks = SystemRunner.run(&Cmd::new("cryptsetup").args(["isLuks", image.path()]));
+        close_luks(&SystemRunner, ROOT_MAPPER).unwrap();
+
+        assert!(opened, "{} did not show up", mapper);
+        assert!(is_luks.unwrap().is_success());
+        assert!(!Path::new(&mapper).exists());
+    }
+}
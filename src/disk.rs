@@ -0,0 +1,412 @@
+use crate::{
+    command::{Cmd, CommandRunner},
+    error::{self, InstallerError},
+};
+use serde::{Deserialize, Deserializer};
+
+// Columns requested from lsblk, kept in sync with the fields of `BlockDevice`
+pub const LSBLK_COLUMNS: &str =
+    "NAME,PATH,SIZE,MODEL,SERIAL,TRAN,ROTA,RM,RO,TYPE,FSTYPE,LABEL,UUID,PARTUUID,MOUNTPOINTS";
+
+// util-linux before 2.37 only has MOUNTPOINT, the first of several mount points
+const LSBLK_COLUMNS_LEGACY: &str =
+    "NAME,PATH,SIZE,MODEL,SERIAL,TRAN,ROTA,RM,RO,TYPE,FSTYPE,LABEL,UUID,PARTUUID,MOUNTPOINT";
+
+#[derive(Debug, Deserialize)]
+struct LsblkOutput {
+    blockdevices: Vec<BlockDevice>,
+}
+
+/// A block device as reported by `lsblk --json --bytes`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BlockDevice {
+    pub name: String,
+    pub path: String,
+    #[serde(deserialize_with = "de_size")]
+    pub size: u64,
+    #[serde(default, deserialize_with = "de_trimmed")]
+    pub model: Option<String>,
+    #[serde(default, deserialize_with = "de_trimmed")]
+    pub serial: Option<String>,
+    #[serde(default, rename = "tran")]
+    pub transport: Option<String>,
+    #[serde(default, rename = "rota", deserialize_with = "de_flag")]
+    pub rotational: bool,
+    #[serde(default, rename = "rm", deserialize_with = "de_flag")]
+    pub removable: bool,
+    #[serde(default, rename = "ro", deserialize_with = "de_flag")]
+    pub read_only: bool,
+    #[serde(rename = "type")]
+    pub device_type: String,
+    #[serde(default)]
+    pub fstype: Option<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub partuuid: Option<String>,
+    #[serde(default, alias = "mountpoint", deserialize_with = "de_mountpoints")]
+    pub mountpoints: Vec<String>,
+    #[serde(default, rename = "children")]
+    pub partitions: Vec<BlockDevice>,
+}
+
+impl BlockDevice {
+    pub fn is_disk(&self) -> bool {
+        self.device_type == "disk"
+    }
+
+    // Loop devices back disk images, e.g. set up with `losetup --partscan`
+    pub fn is_loop(&self) -> bool {
+        self.device_type == "loop"
+    }
+
+    // This device followed by everything stacked on it, depth first
+    pub fn flatten(&self) -> Vec<&BlockDevice> {
+        let mut devices = vec![self];
+        for child in &self.partitions {
+            devices.extend(child.flatten());
+        }
+        devices
+    }
+
+    // One line summary for the disk picker, e.g. "/dev/sda  500.1 GB  Samsung SSD 860"
+    pub fn summary(&self) -> String {
+        let mut line = format!("{}  {}", self.path, format_size(self.size));
+        if let Some(model) = &self.model {
+            line.push_str("  ");
+            line.push_str(model);
+        }
+        if let Some(transport) = &self.transport {
+            line.push_str(&format!(" ({})", transport));
+        }
+        line
+    }
+}
+
+/// Parse the JSON document printed by `lsblk --json --bytes -o LSBLK_COLUMNS`.
+pub fn parse_lsblk(json: &str) -> serde_json::Result<Vec<BlockDevice>> {
+    let output: LsblkOutput = serde_json::from_str(json)?;
+    Ok(output.blockdevices)
+}
+
+// Human readable size using decimal units, the way disk vendors label them
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KB", "MB", "GB", "TB", "PB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+// Older util-linux releases print numbers and flags as strings, newer ones as JSON values
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumOrString {
+    Num(u64),
+    Bool(bool),
+    Str(String),
+}
+
+fn de_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match Option::<NumOrString>::deserialize(deserializer)? {
+        None => Ok(0),
+        Some(NumOrString::Num(n)) => Ok(n),
+        Some(NumOrString::Bool(_)) => Err(serde::de::Error::custom("unexpected boolean size")),
+        Some(NumOrString::Str(s)) => s.trim().parse().map_err(serde::de::Error::custom),
+    }
+}
+
+fn de_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match Option::<NumOrString>::deserialize(deserializer)? {
+        None => Ok(false),
+        Some(NumOrString::Bool(b)) => Ok(b),
+        Some(NumOrString::Num(n)) => Ok(n != 0),
+        Some(NumOrString::Str(s)) => Ok(s.trim() == "1"),
+    }
+}
+
+fn de_trimmed<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(value
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty()))
+}
+
+// `mountpoints` is a list that contains `null` for unmounted devices, the
+// older `mountpoint` a single string or `null`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Mountpoints {
+    One(String),
+    List(Vec<Option<String>>),
+}
+
+fn de_mountpoints<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(match Option::<Mountpoints>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(Mountpoints::One(mountpoint)) => vec![mountpoint],
+        Some(Mountpoints::List(list)) => list.into_iter().flatten().collect(),
+    })
+}
+
+// Run lsblk on `device`, or on every device when `None`
+fn lsblk(runner: &dyn CommandRunner, device: Option<&str>) -> error::Result<Vec<BlockDevice>> {
+    let run = |columns: &str| {
+        let cmd = Cmd::new("lsblk")
+            .arg("--json")
+            .arg("--bytes") // Sizes as plain numbers
+            .args(["-o", columns])
+            .args(device)
+            .read_only();
+        runner.run_checked(&cmd)
+    };
+    let output = match run(LSBLK_COLUMNS) {
+        Err(InstallerError::CommandFailed { stderr, .. }) if stderr.contains("unknown column") => {
+            run(LSBLK_COLUMNS_LEGACY)?
+        }
+        result => result?,
+    };
+
+    parse_lsblk(&output.stdout).map_err(|err| InstallerError::parse("lsblk output", err))
+}
+
+/// Every top level block device (disks, loop devices, optical drives...) with
+/// whatever is stacked on it.
+pub fn list_devices(runner: &dyn CommandRunner) -> error::Result<Vec<BlockDevice>> {
+    lsblk(runner, None)
+}
+
+/// List the whole disks attached to the system, with their partitions.
+/// `with_loop` adds loop devices, so a disk image can be installed to.
+pub fn get_available_disks(
+    runner: &dyn CommandRunner,
+    with_loop: bool,
+) -> error::Result<Vec<BlockDevice>> {
+    let devices = list_devices(runner)?;
+    Ok(devices
+        .into_iter()
+        .filter(|d| d.is_disk() || (with_loop && d.is_loop()))
+        .collect())
+}
+
+/// Look up a single device (disk or partition) by path.
+pub fn read_device(runner: &dyn CommandRunner, path: &str) -> error::Result<BlockDevice> {
+    lsblk(runner, Some(path))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| InstallerError::parse("lsblk output", format!("{} is missing", path)))
+}
+
+/// Filesystem or LUKS UUID of `device`, straight from the superblock.
+pub fn uuid_of(runner: &dyn CommandRunner, device: &str) -> error::Result<String> {
+    // The device does not exist yet when only planning
+    if runner.is_dry_run() {
+        return Ok(format!("<UUID of {}>", device));
+    }
+
+    let cmd = Cmd::new("blkid")
+        .args(["-s", "UUID", "-o", "value", device])
+        .read_only();
+    let uuid = runner.run_checked(&cmd)?.stdout.trim().to_string();
+    if uuid.is_empty() {
+        return Err(InstallerError::parse(
+            "blkid output",
+            format!("{} has no UUID", device),
+        ));
+    }
+    Ok(uuid)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::command::{CommandOutput, MockRunner};
+
+    const LSBLK_2_33: &str = include_str!("../tests/fixtures/lsblk-2.33.json");
+    const LSBLK_2_38: &str = include_str!("../tests/fixtures/lsblk-2.38.json");
+
+    // A bare disk for tests that only care about its path and size
+    pub(crate) fn disk(path: &str, size: u64) -> BlockDevice {
+        BlockDevice {
+            name: path.trim_start_matches("/dev/").to_string(),
+            path: path.to_string(),
+            size,
+            model: None,
+            serial: None,
+            transport: None,
+            rotational: false,
+            removable: false,
+            read_only: false,
+            device_type: "disk".to_string(),
+            fstype: None,
+            label: None,
+            uuid: None,
+            partuuid: None,
+            mountpoints: Vec::new(),
+            partitions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parses_string_values_of_older_util_linux() {
+        let devices = parse_lsblk(LSBLK_2_33).unwrap();
+        assert_eq!(devices.len(), 2);
+
+        let sda = &devices[0];
+        assert_eq!(sda.path, "/dev/sda");
+        assert_eq!(sda.size, 500107862016);
+        assert_eq!(sda.model.as_deref(), Some("Samsung SSD 860"));
+        assert_eq!(sda.transport.as_deref(), Some("sata"));
+        assert!(!sda.rotational && !sda.removable && !sda.read_only);
+        assert!(sda.is_disk());
+        assert!(sda.mountpoints.is_empty());
+
+        let data = &sda.partitions[1];
+        assert_eq!(data.size, 499570991104);
+        assert_eq!(data.fstype.as_deref(), Some("ext4"));
+        assert_eq!(data.label.as_deref(), Some("data"));
+        assert_eq!(data.mountpoints, ["/mnt/data"]);
+
+        let rom = &devices[1];
+        assert!(rom.rotational && rom.removable);
+        assert!(!rom.is_disk());
+    }
+
+    #[test]
+    fn older_util_linux_is_asked_for_the_single_mountpoint() {
+        let runner = MockRunner::new();
+        runner.on_args(
+            "lsblk",
+            &["--json", "--bytes", "-o", LSBLK_COLUMNS],
+            CommandOutput::failure(1, "lsblk: unknown column: MOUNTPOINTS\n"),
+        );
+        runner.on_args(
+            "lsblk",
+            &["--json", "--bytes", "-o", LSBLK_COLUMNS_LEGACY],
+            CommandOutput::success(LSBLK_2_33),
+        );
+        let devices = list_devices(&runner).unwrap();
+        assert_eq!(devices[0].partitions[1].mountpoints, ["/mnt/data"]);
+        assert_eq!(runner.invocations().len(), 2);
+
+        // Any other failure is reported as it is
+        let runner = MockRunner::new();
+        runner.on(
+            "lsblk",
+            CommandOutput::failure(32, "lsblk: /dev/sdz: not a block device"),
+        );
+        assert!(read_device(&runner, "/dev/sdz").is_err());
+        assert_eq!(runner.invocations().len(), 1);
+    }
+
+    #[test]
+    fn parses_native_values_of_newer_util_linux() {
+        let devices = parse_lsblk(LSBLK_2_38).unwrap();
+        let loop0 = &devices[0];
+        assert_eq!(loop0.device_type, "loop");
+        assert!(loop0.read_only);
+        assert_eq!(loop0.size, 846348288);
+
+        let nvme = &devices[1];
+        assert_eq!(nvme.model.as_deref(), Some("WD_BLACK SN770 1TB"));
+        assert_eq!(nvme.serial.as_deref(), Some("22123A800123"));
+        assert_eq!(nvme.size, 1000204886016);
+        assert!(nvme.mountpoints.is_empty());
+        assert_eq!(
+            nvme.summary(),
+            "/dev/nvme0n1  1.0 TB  WD_BLACK SN770 1TB (nvme)"
+        );
+    }
+
+    #[test]
+    fn nested_children_are_flattened_depth_first() {
+        let devices = parse_lsblk(LSBLK_2_38).unwrap();
+        let nvme = &devices[1];
+        let paths: Vec<&str> = nvme.flatten().iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/dev/nvme0n1",
+                "/dev/nvme0n1p1",
+                "/dev/nvme0n1p2",
+                "/dev/mapper/root"
+            ]
+        );
+
+        let root = &nvme.partitions[1].partitions[0];
+        assert_eq!(root.device_type, "crypt");
+        assert_eq!(root.fstype.as_deref(), Some("btrfs"));
+        assert_eq!(root.mountpoints, ["/home", "/"]);
+    }
+
+    #[test]
+    fn missing_optional_columns_default() {
+        let json = r#"{"blockdevices": [{"name": "vda", "path": "/dev/vda", "size": null,
+            "type": "disk", "model": "  ", "mountpoints": null}]}"#;
+        let vda = &parse_lsblk(json).unwrap()[0];
+        assert_eq!(vda.size, 0);
+        assert_eq!(vda.model, None);
+        assert!(vda.mountpoints.is_empty() && vda.partitions.is_empty());
+        assert!(parse_lsblk(r#"{"blockdevices": [{"name": "x"}]}"#).is_err());
+    }
+
+    #[test]
+    fn sizes_use_decimal_units() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1_500_000), "1.5 MB");
+        assert_eq!(format_size(500107862016), "500.1 GB");
+        assert_eq!(
+            disk("/dev/vda", 8_000_000_000).summary(),
+            "/dev/vda  8.0 GB"
+        );
+    }
+
+    #[test]
+    fn lsblk_runs_read_only_with_the_known_columns() {
+        let runner = MockRunner::new();
+        runner.on("lsblk", CommandOutput::success(LSBLK_2_33));
+        let disks = get_available_disks(&runner, false).unwrap();
+        assert_eq!(disks.len(), 1);
+
+        let lsblk = &runner.invocations()[0];
+        assert!(lsblk.read_only);
+        assert_eq!(
+            lsblk.to_string(),
+            format!("lsblk --json --bytes -o {}", LSBLK_COLUMNS)
+        );
+    }
+
+    #[test]
+    fn loop_devices_are_listed_on_request() {
+        let runner = MockRunner::new();
+        runner.on("lsblk", CommandOutput::success(LSBLK_2_38));
+        let paths = |with_loop| {
+            get_available_disks(&runner, with_loop)
+                .unwrap()
+                .into_iter()
+                .map(|d| d.path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(false), ["/dev/nvme0n1"]);
+        assert_eq!(paths(true), ["/dev/loop0", "/dev/nvme0n1"]);
+    }
+
+    #[test]
+    fn uuid_of_reads_blkid() {
+        let runner = MockRunner::new();
+        runner.on("blkid", CommandOutput::success("5d1f1c3a\n"));
+        assert_eq!(uuid_of(&runner, "/dev/sda2").unwrap(), "5d1f1c3a");
+
+        let empty = MockRunner::new();
+        assert!(uuid_of(&empty, "/dev/sda2").is_err());
+    }
+}
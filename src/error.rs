@@ -0,0 +1,99 @@
+use std::{fmt, io};
+
+pub type Result<T> = std::result::Result<T, InstallerError>;
+
+#[derive(Debug)]
+pub enum InstallerError {
+    // The program could not be spawned at all, usually because it is not installed
+    CommandNotFound { program: String },
+    // The program ran but exited unsuccessfully
+    CommandFailed {
+        program: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+    // Output from a tool or a file could not be understood
+    Parse { what: String, message: String },
+    // The user backed out with Esc
+    Cancelled,
+    // Something that has to be true before continuing is not
+    PreconditionFailed(String),
+    Io(io::Error),
+}
+
+impl InstallerError {
+    // Build the right error for a failed spawn of `program`
+    pub fn spawn(program: &str, err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::NotFound {
+            InstallerError::CommandNotFound {
+                program: program.to_string(),
+            }
+        } else {
+            InstallerError::Io(err)
+        }
+    }
+
+    pub fn parse(what: impl Into<String>, message: impl fmt::Display) -> Self {
+        InstallerError::Parse {
+            what: what.into(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            InstallerError::CommandNotFound { .. } => "Command not found",
+            InstallerError::CommandFailed { .. } => "Command failed",
+            InstallerError::Parse { .. } => "Parse error",
+            InstallerError::Cancelled => "Cancelled",
+            InstallerError::PreconditionFailed(_) => "Cannot continue",
+            InstallerError::Io(_) => "I/O error",
+        }
+    }
+}
+
+impl fmt::Display for InstallerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstallerError::CommandNotFound { program } => {
+                write!(f, "`{}` was not found, is it installed?", program)
+            }
+            InstallerError::CommandFailed {
+                program,
+                code,
+                stderr,
+            } => {
+                match code {
+                    Some(code) => write!(f, "`{}` exited with status {}", program, code)?,
+                    None => write!(f, "`{}` was terminated by a signal", program)?,
+                }
+                let stderr = stderr.trim();
+                if !stderr.is_empty() {
+                    write!(f, ": {}", stderr)?;
+                }
+                Ok(())
+            }
+            InstallerError::Parse { what, message } => {
+                write!(f, "Failed to parse {}: {}", what, message)
+            }
+            InstallerError::Cancelled => write!(f, "Cancelled by user"),
+            InstallerError::PreconditionFailed(reason) => write!(f, "{}", reason),
+            InstallerError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for InstallerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InstallerError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for InstallerError {
+    fn from(err: io::Error) -> Self {
+        InstallerError::Io(err)
+    }
+}
@@ -0,0 +1,370 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{Cmd, CommandRunner},
+    disk,
+    error::{InstallerError, Result},
+    partition::{Partition, PartitionRole},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilesystemKind {
+    Ext4,
+    Btrfs,
+    Xfs,
+    F2fs,
+    Vfat,
+    Swap,
+}
+
+impl FilesystemKind {
+    pub const ALL: [FilesystemKind; 6] = [
+        FilesystemKind::Ext4,
+        FilesystemKind::Btrfs,
+        FilesystemKind::Xfs,
+        FilesystemKind::F2fs,
+        FilesystemKind::Vfat,
+        FilesystemKind::Swap,
+    ];
+
+    // Filesystem a partition gets unless the plan says otherwise
+    pub fn default_for(role: PartitionRole) -> Self {
+        match role {
+            PartitionRole::Esp => FilesystemKind::Vfat,
+            PartitionRole::Swap => FilesystemKind::Swap,
+            PartitionRole::Root | PartitionRole::Home => FilesystemKind::Ext4,
+        }
+    }
+
+    // Name as reported by lsblk/blkid and used in fstab
+    pub fn name(self) -> &'static str {
+        match self {
+            FilesystemKind::Ext4 => "ext4",
+            FilesystemKind::Btrfs => "btrfs",
+            FilesystemKind::Xfs => "xfs",
+            FilesystemKind::F2fs => "f2fs",
+            FilesystemKind::Vfat => "vfat",
+            FilesystemKind::Swap => "swap",
+        }
+    }
+
+    // Inverse of `name`, for filesystems found on existing partitions
+    pub fn from_name(name: &str) -> Option<Self> {
+        FilesystemKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == name)
+    }
+
+    fn max_label_len(self) -> usize {
+        match self {
+            FilesystemKind::Ext4 | FilesystemKind::Swap => 16,
+            FilesystemKind::Btrfs => 255,
+            FilesystemKind::Xfs => 12,
+            FilesystemKind::F2fs => 512,
+            FilesystemKind::Vfat => 11,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilesystemSpec {
+    pub kind: FilesystemKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    // Extra arguments handed to the mkfs tool
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+impl FilesystemSpec {
+    pub fn new(kind: FilesystemKind) -> Self {
+        FilesystemSpec {
+            kind,
+            label: None,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(label) = &self.label {
+            if label.len() > self.kind.max_label_len() {
+                return Err(InstallerError::PreconditionFailed(format!(
+                    "Label \"{}\" is longer than the {} characters {} allows",
+                    label,
+                    self.kind.max_label_len(),
+                    self.kind.name()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The mkfs invocation that creates this filesystem on `device`.
+    pub fn mkfs_cmd(&self, device: &str) -> Cmd {
+        let (program, base, label_flag): (&str, &[&str], &str) = match self.kind {
+            FilesystemKind::Ext4 => ("mkfs.ext4", &["-F"], "-L"),
+            FilesystemKind::Btrfs => ("mkfs.btrfs", &["-f"], "-L"),
+            FilesystemKind::Xfs => ("mkfs.xfs", &["-f"], "-L"),
+            FilesystemKind::F2fs => ("mkfs.f2fs", &["-f"], "-l"),
+            FilesystemKind::Vfat => ("mkfs.fat", &["-F", "32"], "-n"),
+            FilesystemKind::Swap => ("mkswap", &[], "-L"),
+        };
+
+        let mut cmd = Cmd::new(program).args(base.iter().copied());
+        if let Some(label) = &self.label {
+            // FAT labels are conventionally upper case
+            let label = if self.kind == FilesystemKind::Vfat {
+                label.to_uppercase()
+            } else {
+                label.clone()
+            };
+            cmd = cmd.args([label_flag.to_string(), label]);
+        }
+        cmd.args(self.options.iter().map(String::as_str)).arg(device)
+    }
+}
+
+/// Create the planned filesystem on every partition and check the result.
+/// Partitions whose filesystem is kept are skipped.
+pub fn format_partitions(runner: &dyn CommandRunner, partitions: &[Partition]) -> Result<()> {
+    let partitions: Vec<&Partition> = partitions.iter().filter(|p| p.format).collect();
+    for partition in &partitions {
+        partition.filesystem.validate()?;
+    }
+
+    for partition in &partitions {
+        runner.run_checked(&partition.filesystem.mkfs_cmd(partition.device()))?;
+    }
+
+    if runner.is_dry_run() {
+        return Ok(());
+    }
+
+    runner.run_checked(&Cmd::new("udevadm").arg("settle"))?;
+    for partition in partitions {
+        verify_filesystem(runner, partition)?;
+    }
+    Ok(())
+}
+
+// Re-read the device the same way disks are discovered and compare with the plan
+fn verify_filesystem(runner: &dyn CommandRunner, partition: &Partition) -> Result<()> {
+    let device = disk::read_device(runner, partition.device())?;
+    let expected = &partition.filesystem;
+
+    if device.fstype.as_deref() != Some(expected.kind.name()) {
+        return Err(InstallerError::PreconditionFailed(format!(
+            "{} should be {} but lsblk reports {}",
+            partition.device(),
+            expected.kind.name(),
+            device.fstype.as_deref().unwrap_or("no filesystem")
+        )));
+    }
+
+    if let Some(label) = &expected.label {
+        if !device
+            .label
+            .as_deref()
+            .is_some_and(|found| found.eq_ignore_ascii_case(label))
+        {
+            return Err(InstallerError::PreconditionFailed(format!(
+                "{} should be labelled \"{}\" but lsblk reports {:?}",
+                partition.device(),
+                label,
+                device.label
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{CommandOutput, MockRunner},
+        disk::LSBLK_COLUMNS,
+        plan::DryRunRunner,
+    };
+
+    fn partition(role: PartitionRole, path: &str, filesystem: FilesystemSpec) -> Partition {
+        Partition {
+            role,
+            number: 1,
+            path: path.to_string(),
+            filesystem,
+            mapper: None,
+            format: true,
+        }
+    }
+
+    // What lsblk reports for `path` once it is formatted
+    fn lsblk_reports(runner: &MockRunner, path: &str, fstype: &str, label: Option<&str>) {
+        let json = format!(
+            r#"{{"blockdevices": [{{"name": "x", "path": "{}", "size": 1, "type": "part", "fstype": "{}", "label": {}}}]}}"#,
+            path,
+            fstype,
+            label.map_or("null".to_string(), |l| format!("\"{}\"", l))
+        );
+        runner.on_args(
+            "lsblk",
+            &["--json", "--bytes", "-o", LSBLK_COLUMNS, path],
+            CommandOutput::success(json),
+        );
+    }
+
+    #[test]
+    fn mkfs_commands_per_filesystem() {
+        let line = |spec: FilesystemSpec| spec.mkfs_cmd("/dev/sda1").to_string();
+        assert_eq!(
+            line(FilesystemSpec::new(FilesystemKind::Ext4)),
+            "mkfs.ext4 -F /dev/sda1"
+        );
+        assert_eq!(
+            line(FilesystemSpec::new(FilesystemKind::Vfat).label("efi")),
+            "mkfs.fat -F 32 -n EFI /dev/sda1"
+        );
+        assert_eq!(
+            line(FilesystemSpec::new(FilesystemKind::F2fs).label("root")),
+            "mkfs.f2fs -f -l root /dev/sda1"
+        );
+        assert_eq!(
+            line(FilesystemSpec::new(FilesystemKind::Swap)),
+            "mkswap /dev/sda1"
+        );
+        assert_eq!(
+            line(FilesystemSpec::new(FilesystemKind::Xfs).label("home")),
+            "mkfs.xfs -f -L home /dev/sda1"
+        );
+
+        let mut btrfs = FilesystemSpec::new(FilesystemKind::Btrfs).label("arch");
+        btrfs.options = vec!["-d".to_string(), "raid1".to_string()];
+        assert_eq!(line(btrfs), "mkfs.btrfs -f -L arch -d raid1 /dev/sda1");
+    }
+
+    #[test]
+    fn labels_are_checked_against_the_filesystem_limit() {
+        assert!(FilesystemSpec::new(FilesystemKind::Vfat)
+            .label("ELEVENCHARS")
+            .validate()
+            .is_ok());
+        assert!(FilesystemSpec::new(FilesystemKind::Vfat)
+            .label("TWELVE_CHARS")
+            .validate()
+            .is_err());
+        assert!(FilesystemSpec::new(FilesystemKind::Btrfs)
+            .label("TWELVE_CHARS")
+            .validate()
+            .is_ok());
+        assert!(FilesystemSpec::new(FilesystemKind::Xfs)
+            .label("THIRTEEN_CHAR")
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn format_partitions_creates_and_verifies_each_filesystem() {
+        let runner = MockRunner::new();
+        lsblk_reports(&runner, "/dev/sda1", "vfat", Some("EFI"));
+        lsblk_reports(&runner, "/dev/mapper/cryptroot", "ext4", None);
+        let mut root = partition(
+            PartitionRole::Root,
+            "/dev/sda2",
+            FilesystemSpec::new(FilesystemKind::Ext4),
+        );
+        root.mapper = Some("/dev/mapper/cryptroot".to_string());
+        let mut kept = partition(
+            PartitionRole::Home,
+            "/dev/sda3",
+            FilesystemSpec::new(FilesystemKind::Xfs),
+        );
+        kept.format = false;
+        let partitions = [
+            partition(
+                PartitionRole::Esp,
+                "/dev/sda1",
+                FilesystemSpec::new(FilesystemKind::Vfat).label("efi"),
+            ),
+            root,
+            kept,
+        ];
+
+        format_partitions(&runner, &partitions).unwrap();
+        let lines = runner.command_lines();
+        assert_eq!(
+            lines[..3],
+            [
+                "mkfs.fat -F 32 -n EFI /dev/sda1",
+                "mkfs.ext4 -F /dev/mapper/cryptroot",
+                "udevadm settle",
+            ]
+        );
+        assert!(lines[3].starts_with("lsblk") && lines[3].ends_with("/dev/sda1"));
+        assert!(lines[4].ends_with("/dev/mapper/cryptroot"));
+        assert!(!lines.iter().any(|l| l.contains("/dev/sda3")));
+    }
+
+    #[test]
+    fn format_partitions_fails_when_the_result_differs() {
+        let runner = MockRunner::new();
+        lsblk_reports(&runner, "/dev/sda2", "ext2", None);
+        let partitions = [partition(
+            PartitionRole::Root,
+            "/dev/sda2",
+            FilesystemSpec::new(FilesystemKind::Ext4),
+        )];
+        let err = format_partitions(&runner, &partitions).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "/dev/sda2 should be ext4 but lsblk reports ext2"
+        );
+
+        lsblk_reports(&runner, "/dev/sda2", "ext4", Some("other"));
+        let partitions = [partition(
+            PartitionRole::Root,
+            "/dev/sda2",
+            FilesystemSpec::new(FilesystemKind::Ext4).label("root"),
+        )];
+        assert!(format_partitions(&runner, &partitions).is_err());
+    }
+
+    #[test]
+    fn format_partitions_only_plans_in_dry_run() {
+        let host = MockRunner::new();
+        let runner = DryRunRunner::new(&host);
+        let partitions = [partition(
+            PartitionRole::Root,
+            "/dev/sda2",
+            FilesystemSpec::new(FilesystemKind::Btrfs),
+        )];
+        format_partitions(&runner, &partitions).unwrap();
+        assert_eq!(runner.plan().actions.len(), 1);
+        assert!(host.invocations().is_empty());
+    }
+
+    #[test]
+    fn invalid_label_fails_before_anything_is_formatted() {
+        let runner = MockRunner::new();
+        let partitions = [
+            partition(
+                PartitionRole::Root,
+                "/dev/sda2",
+                FilesystemSpec::new(FilesystemKind::Ext4),
+            ),
+            partition(
+                PartitionRole::Home,
+                "/dev/sda3",
+                FilesystemSpec::new(FilesystemKind::Xfs).label("much_too_long"),
+            ),
+        ];
+        assert!(format_partitions(&runner, &partitions).is_err());
+        assert!(runner.invocations().is_empty());
+    }
+}
@@ -0,0 +1,616 @@
+use std::{fmt, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{CommandRunner, Mount},
+    disk::{self, BlockDevice},
+    error::{InstallerError, Result},
+    partition::{Partition, PartitionRole},
+};
+
+/// How devices are referred to in the generated fstab.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FstabIdentifier {
+    #[default]
+    Uuid,
+    PartUuid,
+    Label,
+}
+
+impl FstabIdentifier {
+    fn prefix(self) -> &'static str {
+        match self {
+            FstabIdentifier::Uuid => "UUID",
+            FstabIdentifier::PartUuid => "PARTUUID",
+            FstabIdentifier::Label => "LABEL",
+        }
+    }
+
+    // The identifier of `device`, or `None` if it has none of this kind
+    fn of(self, device: &BlockDevice) -> Option<&str> {
+        match self {
+            FstabIdentifier::Uuid => device.uuid.as_deref(),
+            FstabIdentifier::PartUuid => device.partuuid.as_deref(),
+            FstabIdentifier::Label => device.label.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FstabEntry {
+    // First field, e.g. UUID=... or /dev/sda1
+    pub spec: String,
+    pub mountpoint: String,
+    pub fstype: String,
+    pub options: Vec<String>,
+    pub dump: u32,
+    pub pass: u32,
+}
+
+impl fmt::Display for FstabEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let options = if self.options.is_empty() {
+            "defaults".to_string()
+        } else {
+            self.options.join(",")
+        };
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{} {}",
+            escape(&self.spec),
+            escape(&self.mountpoint),
+            self.fstype,
+            options,
+            self.dump,
+            self.pass
+        )
+    }
+}
+
+// fstab and the mount table write space, tab, newline and backslash as octal
+// escapes, e.g. LABEL=My\040Data
+fn escape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            ' ' | '\t' | '\n' | '\\' => out.push_str(&format!("\\{:03o}", c as u8)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Undo the octal escapes of an fstab or mount table field.
+pub(crate) fn unescape(field: &str) -> String {
+    let mut out = Vec::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        out.extend_from_slice(&rest.as_bytes()[..i]);
+        let code = rest.get(i + 1..i + 4);
+        match code.and_then(|c| u8::from_str_radix(c, 8).ok()) {
+            Some(byte) => {
+                out.push(byte);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                out.push(b'\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.extend_from_slice(rest.as_bytes());
+    // Escaped bytes may be parts of a UTF-8 sequence
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Build fstab entries from the mounts making up the target, plus swap partitions.
+pub fn generate(
+    runner: &dyn CommandRunner,
+    mounts: &[Mount],
+    partitions: &[Partition],
+    target: &Path,
+    identifier: FstabIdentifier,
+) -> Result<Vec<FstabEntry>> {
+    let mut entries = Vec::new();
+
+    for mount in mounts {
+        let relative = mount.target.strip_prefix(target).map_err(|_| {
+            InstallerError::PreconditionFailed(format!(
+                "{} is not below {}",
+                mount.target.display(),
+                target.display()
+            ))
+        })?;
+        let mountpoint = format!("/{}", relative.to_string_lossy());
+        let fstype = mount.fstype.clone().unwrap_or_else(|| "auto".to_string());
+
+        // Root gets checked first, btrfs and xfs have no boot time fsck
+        let pass = match fstype.as_str() {
+            "btrfs" | "xfs" => 0,
+            _ if mountpoint == "/" => 1,
+            _ => 2,
+        };
+
+        entries.push(FstabEntry {
+            spec: device_spec(runner, &mount.source, identifier)?,
+            mountpoint,
+            fstype,
+            options: mount.options.clone(),
+            dump: 0,
+            pass,
+        });
+    }
+
+    for swap in partitions.iter().filter(|p| p.role == PartitionRole::Swap) {
+        entries.push(FstabEntry {
+            spec: device_spec(runner, swap.device(), identifier)?,
+            mountpoint: "none".to_string(),
+            fstype: "swap".to_string(),
+            options: Vec::new(),
+            dump: 0,
+            pass: 0,
+        });
+    }
+
+    Ok(entries)
+}
+
+// How `device` is written in fstab, falling back to its UUID when it lacks the
+// requested identifier (device mapper nodes have no PARTUUID)
+fn device_spec(
+    runner: &dyn CommandRunner,
+    device: &str,
+    identifier: FstabIdentifier,
+) -> Result<String> {
+    if runner.is_dry_run() {
+        // Placeholder without whitespace so the result still parses
+        return Ok(format!("{}=<{}>", identifier.prefix(), device));
+    }
+
+    let info = disk::read_device(runner, device)?;
+    for identifier in [identifier, FstabIdentifier::Uuid] {
+        if let Some(value) = identifier.of(&info) {
+            return Ok(format!("{}={}", identifier.prefix(), value));
+        }
+    }
+    Err(InstallerError::PreconditionFailed(format!(
+        "{} has no UUID to put in fstab",
+        device
+    )))
+}
+
+pub fn render(entries: &[FstabEntry]) -> String {
+    let mut text = String::from("# /etc/fstab: static file system information.\n");
+    text.push_str("# Generated by archinstaller\n");
+    text.push_str("#\n# <file system>\t<dir>\t<type>\t<options>\t<dump> <pass>\n\n");
+    for entry in entries {
+        text.push_str(&entry.to_string());
+        text.push('\n');
+    }
+    text
+}
+
+/// Parse fstab text, skipping comments and blank lines.
+pub fn parse(text: &str) -> Result<Vec<FstabEntry>> {
+    let mut entries = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fail = |message: &str| {
+            InstallerError::parse("fstab", format!("line {}: {}", number + 1, message))
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if !(4..=6).contains(&fields.len()) {
+            return Err(fail("expected 4 to 6 fields"));
+        }
+        let number_field = |i: usize| -> Result<u32> {
+            fields.get(i).map_or(Ok(0), |value| {
+                value
+                    .parse()
+                    .map_err(|_| fail("dump and pass must be numbers"))
+            })
+        };
+
+        entries.push(FstabEntry {
+            spec: unescape(fields[0]),
+            mountpoint: unescape(fields[1]),
+            fstype: fields[2].to_string(),
+            options: match fields[3] {
+                "defaults" => Vec::new(),
+                options => options.split(',').map(str::to_string).collect(),
+            },
+            dump: number_field(4)?,
+            pass: number_field(5)?,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Check `entries` form a usable fstab and that every entry refers to a device in `devices`.
+pub fn validate(entries: &[FstabEntry], devices: &[BlockDevice]) -> Result<()> {
+    let fail = |reason: String| Err(InstallerError::PreconditionFailed(reason));
+
+    if entries.iter().filter(|e| e.mountpoint == "/").count() != 1 {
+        return fail("fstab needs exactly one entry for /".to_string());
+    }
+
+    let known: Vec<&BlockDevice> = devices.iter().flat_map(BlockDevice::flatten).collect();
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.fstype == "swap" {
+            if entry.mountpoint != "none" {
+                return fail(format!(
+                    "Swap entry {} must use \"none\" as mount point",
+                    entry.spec
+                ));
+            }
+        } else if !entry.mountpoint.starts_with('/') {
+            return fail(format!(
+                "Mount point {} is not an absolute path",
+                entry.mountpoint
+            ));
+        } else if entries[..i]
+            .iter()
+            .any(|e| e.mountpoint == entry.mountpoint)
+        {
+            return fail(format!("{} is mounted twice", entry.mountpoint));
+        }
+
+        if !known.iter().any(|device| resolves_to(&entry.spec, device)) {
+            return fail(format!("{} does not match any device", entry.spec));
+        }
+    }
+
+    Ok(())
+}
+
+fn resolves_to(spec: &str, device: &BlockDevice) -> bool {
+    let Some((prefix, value)) = spec.split_once('=') else {
+        return spec == device.path;
+    };
+    [
+        FstabIdentifier::Uuid,
+        FstabIdentifier::PartUuid,
+        FstabIdentifier::Label,
+    ]
+    .into_iter()
+    .any(|identifier| identifier.prefix() == prefix && identifier.of(device) == Some(value))
+}
+
+/// Write the target's /etc/fstab and check it resolves against the discovered devices.
+pub fn write_fstab(
+    runner: &dyn CommandRunner,
+    mounts: &[Mount],
+    partitions: &[Partition],
+    target: &Path,
+    identifier: FstabIdentifier,
+) -> Result<()> {
+    let entries = generate(runner, mounts, partitions, target, identifier)?;
+    let text = render(&entries);
+
+    // Parse what is about to be written rather than trusting the generator
+    let parsed = parse(&text)?;
+    if !runner.is_dry_run() {
+        validate(&parsed, &disk::list_devices(runner)?)?;
+    }
+
+    runner.write_file(&target.join("etc/fstab"), &text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{CommandOutput, MockRunner},
+        disk::LSBLK_COLUMNS,
+        format::{FilesystemKind, FilesystemSpec},
+        plan::DryRunRunner,
+    };
+
+    // sda with an ESP, an ext4 root and swap, as lsblk reports them
+    const PARTITIONS: [(&str, &str, &str, &str, &str); 3] = [
+        ("sda1", "vfat", "7E1C-22A0", "0f3d5a7e-01", "EFI"),
+        (
+            "sda2",
+            "ext4",
+            "5d1f1c3a-8e4b-4d8e-9a57-2b0c6f1e7d21",
+            "0f3d5a7e-02",
+            "root",
+        ),
+        (
+            "sda3",
+            "swap",
+            "a1b2c3d4-0000-4000-8000-000000000003",
+            "0f3d5a7e-03",
+            "swap",
+        ),
+    ];
+
+    fn lsblk_entry(
+        (name, fstype, uuid, partuuid, label): (&str, &str, &str, &str, &str),
+    ) -> String {
+        format!(
+            r#"{{"name": "{0}", "path": "/dev/{0}", "size": 1, "type": "part", "fstype": "{1}", "uuid": "{2}", "partuuid": "{3}", "label": "{4}"}}"#,
+            name, fstype, uuid, partuuid, label
+        )
+    }
+
+    fn runner() -> MockRunner {
+        let runner = MockRunner::new();
+        let children: Vec<String> = PARTITIONS.into_iter().map(lsblk_entry).collect();
+        runner.on(
+            "lsblk",
+            CommandOutput::success(format!(
+                r#"{{"blockdevices": [{{"name": "sda", "path": "/dev/sda", "size": 1, "type": "disk", "children": [{}]}}]}}"#,
+                children.join(",")
+            )),
+        );
+        for partition in PARTITIONS {
+            let path = format!("/dev/{}", partition.0);
+            runner.on_args(
+                "lsblk",
+                &["--json", "--bytes", "-o", LSBLK_COLUMNS, &path],
+                CommandOutput::success(format!(
+                    r#"{{"blockdevices": [{}]}}"#,
+                    lsblk_entry(partition)
+                )),
+            );
+        }
+        runner
+    }
+
+    fn mounts() -> Vec<Mount> {
+        vec![
+            Mount::new("/dev/sda2", "/mnt").fstype("ext4"),
+            Mount::new("/dev/sda1", "/mnt/boot")
+                .fstype("vfat")
+                .option("fmask=0077")
+                .option("dmask=0077"),
+        ]
+    }
+
+    fn swap() -> Vec<Partition> {
+        vec![Partition {
+            role: PartitionRole::Swap,
+            number: 3,
+            path: "/dev/sda3".to_string(),
+            filesystem: FilesystemSpec::new(FilesystemKind::Swap),
+            mapper: None,
+            format: true,
+        }]
+    }
+
+    #[test]
+    fn write_fstab_writes_checked_entries() {
+        let runner = runner();
+        write_fstab(
+            &runner,
+            &mounts(),
+            &swap(),
+            Path::new("/mnt"),
+            FstabIdentifier::Uuid,
+        )
+        .unwrap();
+
+        let files = runner.written_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, Path::new("/mnt/etc/fstab"));
+        let entries: Vec<&str> = files[0]
+            .1
+            .lines()
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                "UUID=5d1f1c3a-8e4b-4d8e-9a57-2b0c6f1e7d21\t/\text4\tdefaults\t0 1",
+                "UUID=7E1C-22A0\t/boot\tvfat\tfmask=0077,dmask=0077\t0 2",
+                "UUID=a1b2c3d4-0000-4000-8000-000000000003\tnone\tswap\tdefaults\t0 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn write_fstab_follows_the_identifier() {
+        for (identifier, root) in [
+            (FstabIdentifier::PartUuid, "PARTUUID=0f3d5a7e-02"),
+            (FstabIdentifier::Label, "LABEL=root"),
+        ] {
+            let runner = runner();
+            write_fstab(&runner, &mounts(), &swap(), Path::new("/mnt"), identifier).unwrap();
+            let text = &runner.written_files()[0].1;
+            assert!(text.contains(&format!("{}\t/\text4", root)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn write_fstab_uses_placeholders_in_dry_run() {
+        let host = MockRunner::new();
+        let runner = DryRunRunner::new(&host);
+        write_fstab(
+            &runner,
+            &mounts(),
+            &[],
+            Path::new("/mnt"),
+            FstabIdentifier::PartUuid,
+        )
+        .unwrap();
+
+        let plan = runner.plan().to_string();
+        assert!(plan.contains("PARTUUID=</dev/sda2>\t/\text4"));
+        assert!(host.invocations().is_empty());
+    }
+
+    #[test]
+    fn mounts_outside_the_target_are_rejected() {
+        let runner = runner();
+        let mounts = [Mount::new("/dev/sda2", "/srv").fstype("ext4")];
+        assert!(generate(
+            &runner,
+            &mounts,
+            &[],
+            Path::new("/mnt"),
+            FstabIdentifier::Uuid
+        )
+        .is_err());
+    }
+
+    fn entry(spec: &str, mountpoint: &str, fstype: &str) -> FstabEntry {
+        FstabEntry {
+            spec: spec.to_string(),
+            mountpoint: mountpoint.to_string(),
+            fstype: fstype.to_string(),
+            options: Vec::new(),
+            dump: 0,
+            pass: 0,
+        }
+    }
+
+    #[test]
+    fn generated_entries_round_trip_through_the_text() {
+        let runner = runner();
+        let devices = disk::list_devices(&runner).unwrap();
+        for identifier in [
+            FstabIdentifier::Uuid,
+            FstabIdentifier::PartUuid,
+            FstabIdentifier::Label,
+        ] {
+            let entries =
+                generate(&runner, &mounts(), &swap(), Path::new("/mnt"), identifier).unwrap();
+            assert!(entries[0].spec.starts_with(identifier.prefix()));
+            let parsed = parse(&render(&entries)).unwrap();
+            assert_eq!(parsed, entries);
+            validate(&parsed, &devices).unwrap();
+        }
+    }
+
+    #[test]
+    fn whitespace_in_fields_is_escaped() {
+        let entries = vec![
+            FstabEntry {
+                options: vec!["noatime".to_string()],
+                ..entry("LABEL=My Data", "/srv/my data", "ext4")
+            },
+            entry("LABEL=tab\there", "/srv/back\\slash", "ext4"),
+            entry("LABEL=new\nline", "/srv/plain", "ext4"),
+        ];
+        let text = render(&entries);
+        assert!(text.contains("LABEL=My\\040Data\t/srv/my\\040data\text4\tnoatime\t0 0\n"));
+        assert!(text.contains("LABEL=tab\\011here\t/srv/back\\134slash\t"));
+        assert!(text.contains("LABEL=new\\012line\t"));
+        assert_eq!(parse(&text).unwrap(), entries);
+    }
+
+    #[test]
+    fn spaced_labels_round_trip_and_resolve() {
+        let mut sda = disk::tests::disk("/dev/sda", 1 << 30);
+        let mut data = disk::tests::disk("/dev/sda1", 1 << 30);
+        data.device_type = "part".to_string();
+        data.label = Some("My Data".to_string());
+        sda.partitions.push(data);
+
+        let entries = vec![entry("LABEL=My Data", "/", "ext4")];
+        let parsed = parse(&render(&entries)).unwrap();
+        assert_eq!(parsed[0].spec, "LABEL=My Data");
+        validate(&parsed, &[sda]).unwrap();
+    }
+
+    #[test]
+    fn unescape_leaves_anything_but_octal_escapes() {
+        assert_eq!(unescape("/mnt/my\\040backup"), "/mnt/my backup");
+        assert_eq!(unescape("caf\\303\\251"), "café");
+        assert_eq!(unescape("odd\\9xx\\"), "odd\\9xx\\");
+        assert_eq!(unescape("\\04"), "\\04");
+        for field in ["plain", "My Data", "a\tb\nc", "back\\slash", "\\040"] {
+            assert_eq!(unescape(&escape(field)), field);
+        }
+    }
+
+    #[test]
+    fn parse_reads_optional_dump_and_pass() {
+        let entries = parse(
+            "# comment\n\n  /dev/sda2 / ext4 rw,noatime\nLABEL=EFI /boot vfat defaults 0\ntmpfs /tmp tmpfs defaults 0 0\n",
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].options, ["rw", "noatime"]);
+        assert_eq!((entries[0].dump, entries[0].pass), (0, 0));
+        assert!(entries[1].options.is_empty());
+        assert_eq!(entries[2].spec, "tmpfs");
+    }
+
+    #[test]
+    fn parse_rejects_malformed_lines() {
+        let error = |text: &str| parse(text).unwrap_err().to_string();
+        assert!(error("/dev/sda2 / ext4 defaults 0 one\n")
+            .contains("line 1: dump and pass must be numbers"));
+        assert!(error("# fstab\n/dev/sda2 / ext4 defaults -1 1\n")
+            .contains("line 2: dump and pass must be numbers"));
+        assert!(error("/dev/sda2 / ext4\n").contains("line 1: expected 4 to 6 fields"));
+        assert!(error("/dev/sda2 / ext4 defaults 0 1 extra\n").contains("expected 4 to 6 fields"));
+    }
+
+    #[test]
+    fn validate_resolves_every_kind_of_identifier() {
+        let devices = disk::list_devices(&runner()).unwrap();
+        let check = |entries: &[FstabEntry]| validate(entries, &devices);
+        check(&[
+            entry("UUID=5d1f1c3a-8e4b-4d8e-9a57-2b0c6f1e7d21", "/", "ext4"),
+            entry("PARTUUID=0f3d5a7e-01", "/boot", "vfat"),
+            entry("LABEL=swap", "none", "swap"),
+        ])
+        .unwrap();
+        check(&[entry("/dev/sda2", "/", "ext4")]).unwrap();
+
+        // The value has to match under the identifier it is given as
+        for spec in [
+            "UUID=0f3d5a7e-02",
+            "PARTUUID=root",
+            "LABEL=data",
+            "/dev/sdb2",
+        ] {
+            assert_eq!(
+                check(&[entry(spec, "/", "ext4")]).unwrap_err().to_string(),
+                format!("{} does not match any device", spec)
+            );
+        }
+    }
+
+    #[test]
+    fn validate_rejects_inconsistent_mount_points() {
+        let devices = disk::list_devices(&runner()).unwrap();
+        let error = |entries: &[FstabEntry]| validate(entries, &devices).unwrap_err().to_string();
+        let root = entry("LABEL=root", "/", "ext4");
+
+        assert_eq!(
+            error(&[
+                root.clone(),
+                entry("LABEL=EFI", "/boot", "vfat"),
+                entry("/dev/sda3", "/boot", "ext4")
+            ]),
+            "/boot is mounted twice"
+        );
+        assert_eq!(
+            error(&[root.clone(), root.clone()]),
+            "fstab needs exactly one entry for /"
+        );
+        assert_eq!(
+            error(&[entry("LABEL=EFI", "/boot", "vfat")]),
+            "fstab needs exactly one entry for /"
+        );
+        assert_eq!(
+            error(&[root.clone(), entry("LABEL=EFI", "boot", "vfat")]),
+            "Mount point boot is not an absolute path"
+        );
+        assert_eq!(
+            error(&[root, entry("LABEL=swap", "/swap", "swap")]),
+            "Swap entry LABEL=swap must use \"none\" as mount point"
+        );
+    }
+}
@@ -0,0 +1,1021 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{Cmd, CommandRunner},
+    disk::BlockDevice,
+    error::{InstallerError, Result},
+    format::{FilesystemKind, FilesystemSpec},
+    partition::{self, Partition, PartitionPlan, PartitionRole},
+};
+
+const MIB: u64 = 1024 * 1024;
+
+// The backup header and its 128 entries at the end of the disk
+const BACKUP_SECTORS: u64 = 33;
+
+const MAX_PARTITIONS: u32 = 128;
+
+// GPT stores names as 36 UTF-16 code units
+const MAX_NAME_LEN: usize = 36;
+
+/// A well known partition type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionType {
+    // sgdisk short code
+    pub code: &'static str,
+    pub guid: &'static str,
+    pub name: &'static str,
+}
+
+pub const TYPES: &[PartitionType] = &[
+    PartitionType {
+        code: "ef00",
+        guid: "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+        name: "EFI system",
+    },
+    PartitionType {
+        code: "ef02",
+        guid: "21686148-6449-6E6F-744E-656564454649",
+        name: "BIOS boot",
+    },
+    PartitionType {
+        code: "8300",
+        guid: "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+        name: "Linux filesystem",
+    },
+    PartitionType {
+        code: "8304",
+        guid: "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709",
+        name: "Linux x86-64 root",
+    },
+    PartitionType {
+        code: "8302",
+        guid: "933AC7E1-2EB4-4F13-B844-0E14E2AEF915",
+        name: "Linux home",
+    },
+    PartitionType {
+        code: "8200",
+        guid: "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F",
+        name: "Linux swap",
+    },
+    PartitionType {
+        code: "8e00",
+        guid: "E6D6D379-F507-44C2-A23C-238F2A3DF928",
+        name: "Linux LVM",
+    },
+    PartitionType {
+        code: "fd00",
+        guid: "A19D880F-05FC-4D3B-A006-743F0F84911E",
+        name: "Linux RAID",
+    },
+    PartitionType {
+        code: "0700",
+        guid: "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+        name: "Microsoft basic data",
+    },
+    PartitionType {
+        code: "0c01",
+        guid: "E3C9E316-0B5C-4DB8-817D-F92DF00215AE",
+        name: "Microsoft reserved",
+    },
+    PartitionType {
+        code: "2700",
+        guid: "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC",
+        name: "Windows recovery",
+    },
+];
+
+impl PartitionType {
+    pub fn by_code(code: &str) -> Option<&'static PartitionType> {
+        TYPES.iter().find(|t| t.code.eq_ignore_ascii_case(code))
+    }
+
+    pub fn by_guid(guid: &str) -> Option<&'static PartitionType> {
+        TYPES.iter().find(|t| t.guid.eq_ignore_ascii_case(guid))
+    }
+
+    pub fn of_role(role: PartitionRole) -> &'static PartitionType {
+        PartitionType::by_code(role.type_code()).expect("every role has a known type")
+    }
+}
+
+/// Name of the partition type `guid`, or the GUID itself when it is not a known one.
+pub fn type_name(guid: &str) -> &str {
+    PartitionType::by_guid(guid).map_or(guid, |t| t.name)
+}
+
+// 8-4-4-4-12 hex digits
+fn parse_guid(guid: &str) -> Result<String> {
+    let groups: Vec<&str> = guid.trim().split('-').collect();
+    let valid = groups.iter().map(|g| g.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|g| g.chars().all(|c| c.is_ascii_hexdigit()));
+    if !valid {
+        return Err(InstallerError::PreconditionFailed(format!(
+            "\"{}\" is not a GUID like C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+            guid.trim()
+        )));
+    }
+    Ok(guid.trim().to_uppercase())
+}
+
+/// GPT partition attribute bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    // Needed for the platform to work, partitioning tools leave it alone
+    Required,
+    // Booted by legacy BIOS boot code, e.g. syslinux' gptmbr
+    LegacyBoot,
+    ReadOnly,
+    Hidden,
+    // systemd-gpt-auto-generator and desktops leave it unmounted
+    NoAutomount,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 5] = [
+        Flag::Required,
+        Flag::LegacyBoot,
+        Flag::ReadOnly,
+        Flag::Hidden,
+        Flag::NoAutomount,
+    ];
+
+    pub fn bit(self) -> u32 {
+        match self {
+            Flag::Required => 0,
+            Flag::LegacyBoot => 2,
+            Flag::ReadOnly => 60,
+            Flag::Hidden => 62,
+            Flag::NoAutomount => 63,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Flag::Required => "required",
+            Flag::LegacyBoot => "legacy BIOS bootable",
+            Flag::ReadOnly => "read-only",
+            Flag::Hidden => "hidden",
+            Flag::NoAutomount => "no automount",
+        }
+    }
+}
+
+/// An inclusive range of sectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Extent {
+    pub first_lba: u64,
+    pub last_lba: u64,
+}
+
+impl Extent {
+    pub fn sectors(&self) -> u64 {
+        self.last_lba + 1 - self.first_lba
+    }
+
+    pub fn contains(&self, other: &Extent) -> bool {
+        self.first_lba <= other.first_lba && other.last_lba <= self.last_lba
+    }
+}
+
+/// One partition of a `GptTable`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GptEntry {
+    pub number: u32,
+    pub extent: Extent,
+    pub type_guid: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub attributes: u64,
+    // Unique GUID, kept for partitions already on the disk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    // Where the partition sits on the disk today, `None` for new ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub existing: Option<Extent>,
+    // Filesystem found on an existing partition
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fstype: Option<String>,
+    // What the installed system uses it for, unassigned partitions are left alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<PartitionRole>,
+    // Filesystem to create, `None` keeps the one already there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<FilesystemSpec>,
+}
+
+impl GptEntry {
+    pub fn has_flag(&self, flag: Flag) -> bool {
+        self.attributes & (1 << flag.bit()) != 0
+    }
+
+    /// Filesystem the partition ends up with, created or kept.
+    pub fn filesystem(&self) -> Option<FilesystemKind> {
+        match &self.format {
+            Some(spec) => Some(spec.kind),
+            None => self.fstype.as_deref().and_then(FilesystemKind::from_name),
+        }
+    }
+
+    // An existing partition made smaller than its filesystem
+    fn shrunk(&self) -> bool {
+        self.existing
+            .is_some_and(|e| self.extent.last_lba < e.last_lba)
+    }
+}
+
+/// In-memory GPT of a disk. Nothing touches the disk until `write`, so
+/// every edit can be made and undone freely.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GptTable {
+    pub sector_size: u64,
+    pub total_sectors: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_guid: Option<String>,
+    // Ordered by position on the disk
+    pub entries: Vec<GptEntry>,
+    // Partitions on the disk that writing the table removes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<GptEntry>,
+}
+
+impl GptTable {
+    /// An empty table for a disk of `size` bytes.
+    pub fn new(size: u64, sector_size: u64) -> Self {
+        GptTable {
+            sector_size,
+            total_sectors: size / sector_size,
+            disk_guid: None,
+            entries: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+
+    // Partitions start on 1 MiB boundaries
+    fn alignment(&self) -> u64 {
+        (MIB / self.sector_size).max(1)
+    }
+
+    fn align_up(&self, lba: u64) -> u64 {
+        lba.div_ceil(self.alignment()) * self.alignment()
+    }
+
+    pub fn first_usable(&self) -> u64 {
+        // Protective MBR, primary header and entries come first
+        self.align_up(34)
+    }
+
+    pub fn last_usable(&self) -> u64 {
+        self.total_sectors.saturating_sub(BACKUP_SECTORS + 1)
+    }
+
+    pub fn mib(&self, sectors: u64) -> u64 {
+        sectors * self.sector_size / MIB
+    }
+
+    fn sectors(&self, mib: u64) -> u64 {
+        mib * MIB / self.sector_size
+    }
+
+    pub fn entry(&self, number: u32) -> Result<&GptEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.number == number)
+            .ok_or_else(|| no_partition(number))
+    }
+
+    fn entry_mut(&mut self, number: u32) -> Result<&mut GptEntry> {
+        self.entries
+            .iter_mut()
+            .find(|e| e.number == number)
+            .ok_or_else(|| no_partition(number))
+    }
+
+    /// Unallocated regions of at least 1 MiB, aligned, in disk order.
+    pub fn free_regions(&self) -> Vec<Extent> {
+        let mut regions = Vec::new();
+        let mut start = self.first_usable();
+        let bounds = self.entries.iter().map(|e| e.extent).chain([Extent {
+            first_lba: self.last_usable() + 1,
+            last_lba: self.last_usable() + 1,
+        }]);
+        for extent in bounds {
+            if extent.first_lba >= start + self.alignment() {
+                regions.push(Extent {
+                    first_lba: start,
+                    last_lba: extent.first_lba - 1,
+                });
+            }
+            start = start.max(self.align_up(extent.last_lba + 1));
+        }
+        regions
+    }
+
+    pub fn largest_free(&self) -> Option<Extent> {
+        self.free_regions().into_iter().max_by_key(Extent::sectors)
+    }
+
+    // Lowest unused partition number
+    fn next_number(&self) -> Result<u32> {
+        (1..=MAX_PARTITIONS)
+            .find(|n| self.entries.iter().all(|e| e.number != *n))
+            .ok_or_else(|| {
+                InstallerError::PreconditionFailed(format!(
+                    "A GPT holds at most {} partitions",
+                    MAX_PARTITIONS
+                ))
+            })
+    }
+
+    /// Add a partition at the start of the free `region`, `size_mib` of
+    /// `None` fills it. Returns the new partition's number.
+    pub fn create(
+        &mut self,
+        region: Extent,
+        size_mib: Option<u64>,
+        kind: &PartitionType,
+    ) -> Result<u32> {
+        if !self.free_regions().iter().any(|r| r.contains(&region)) {
+            return Err(InstallerError::PreconditionFailed(
+                "That space is not free".to_string(),
+            ));
+        }
+        let last_lba = match size_mib {
+            Some(0) => {
+                return Err(InstallerError::PreconditionFailed(
+                    "A partition needs a size of at least 1 MiB".to_string(),
+                ))
+            }
+            Some(size) if self.sectors(size) > region.sectors() => {
+                return Err(InstallerError::PreconditionFailed(format!(
+                    "Only {} MiB are free there",
+                    self.mib(region.sectors())
+                )))
+            }
+            Some(size) => region.first_lba + self.sectors(size) - 1,
+            None => region.last_lba,
+        };
+
+        let number = self.next_number()?;
+        self.entries.push(GptEntry {
+            number,
+            extent: Extent {
+                first_lba: region.first_lba,
+                last_lba,
+            },
+            type_guid: kind.guid.to_string(),
+            name: String::new(),
+            attributes: 0,
+            uuid: None,
+            existing: None,
+            fstype: None,
+            role: None,
+            format: None,
+        });
+        self.entries.sort_by_key(|e| e.extent.first_lba);
+        Ok(number)
+    }
+
+    /// Remove partition `number`, an existing one is deleted when the table is written.
+    pub fn delete(&mut self, number: u32) -> Result<()> {
+        let index = self
+            .entries
+            .iter()
+            .position(|e| e.number == number)
+            .ok_or_else(|| no_partition(number))?;
+        let entry = self.entries.remove(index);
+        if entry.existing.is_some() {
+            self.removed.push(entry);
+        }
+        Ok(())
+    }
+
+    /// Grow or shrink partition `number` to `size_mib`, keeping its start.
+    pub fn resize(&mut self, number: u32, size_mib: u64) -> Result<()> {
+        if size_mib == 0 {
+            return Err(InstallerError::PreconditionFailed(
+                "A partition needs a size of at least 1 MiB".to_string(),
+            ));
+        }
+        let first_lba = self.entry(number)?.extent.first_lba;
+        // Up to the next partition or the backup header
+        let limit = self
+            .entries
+            .iter()
+            .map(|e| e.extent.first_lba)
+            .filter(|&lba| lba > first_lba)
+            .min()
+            .map_or(self.last_usable(), |lba| lba - 1);
+        let last_lba = first_lba + self.sectors(size_mib) - 1;
+        if last_lba > limit {
+            return Err(InstallerError::PreconditionFailed(format!(
+                "Partition {} can grow to at most {} MiB",
+                number,
+                self.mib(limit + 1 - first_lba)
+            )));
+        }
+        self.entry_mut(number)?.extent.last_lba = last_lba;
+        Ok(())
+    }
+
+    pub fn set_type(&mut self, number: u32, guid: &str) -> Result<()> {
+        let guid = parse_guid(guid)?;
+        self.entry_mut(number)?.type_guid = guid;
+        Ok(())
+    }
+
+    pub fn set_name(&mut self, number: u32, name: &str) -> Result<()> {
+        if name.encode_utf16().count() > MAX_NAME_LEN || name.contains('"') {
+            return Err(InstallerError::PreconditionFailed(format!(
+                "A partition name has at most {} characters and no double quotes",
+                MAX_NAME_LEN
+            )));
+        }
+        self.entry_mut(number)?.name = name.to_string();
+        Ok(())
+    }
+
+    /// Set `flag` on partition `number` when it is clear and clear it when set.
+    pub fn toggle_flag(&mut self, number: u32, flag: Flag) -> Result<()> {
+        self.entry_mut(number)?.attributes ^= 1 << flag.bit();
+        Ok(())
+    }
+
+    /// Use partition `number` as `role`, formatting it with `format` or
+    /// keeping its filesystem. A role also sets the matching partition type.
+    pub fn assign(
+        &mut self,
+        number: u32,
+        role: Option<PartitionRole>,
+        format: Option<FilesystemSpec>,
+    ) -> Result<()> {
+        let entry = self.entry_mut(number)?;
+        if let Some(role) = role {
+            entry.type_guid = PartitionType::of_role(role).guid.to_string();
+            if entry.name.is_empty() {
+                entry.name = role.name().to_string();
+            }
+        }
+        entry.role = role;
+        entry.format = format;
+        Ok(())
+    }
+
+    pub fn find(&self, role: PartitionRole) -> Option<&GptEntry> {
+        self.entries.iter().find(|e| e.role == Some(role))
+    }
+
+    /// Whether a BIOS boot partition for GRUB is present.
+    pub fn has_bios_boot(&self) -> bool {
+        let bios = PartitionType::by_code("ef02").map(|t| t.guid);
+        self.entries
+            .iter()
+            .any(|e| Some(e.type_guid.as_str()) == bios)
+    }
+
+    /// Whether partitions already on the disk stay, so other systems may live next to the install.
+    pub fn keeps_existing(&self) -> bool {
+        self.entries.iter().any(|e| e.existing.is_some())
+    }
+
+    /// The EFI system partition when it is an existing one that is kept.
+    pub fn shared_esp(&self) -> Option<&GptEntry> {
+        self.find(PartitionRole::Esp)
+            .filter(|e| e.existing.is_some() && e.format.is_none())
+    }
+
+    /// Lay out `plan` in the free `region`, leaving every other partition
+    /// alone. An EFI system partition already on the disk is shared instead
+    /// of creating another one.
+    pub fn install_alongside(&mut self, region: Extent, plan: &PartitionPlan) -> Result<()> {
+        let esp_type = PartitionType::of_role(PartitionRole::Esp);
+        let existing_esp = self
+            .entries
+            .iter()
+            .find(|e| e.existing.is_some() && e.type_guid.eq_ignore_ascii_case(esp_type.guid))
+            .map(|e| e.number);
+
+        // What is left of `region` after the partitions created so far
+        let remaining = |table: &GptTable| {
+            table
+                .free_regions()
+                .into_iter()
+                .find(|r| region.contains(r))
+                .ok_or_else(|| {
+                    InstallerError::PreconditionFailed(
+                        "Not enough unallocated space for the new partitions".to_string(),
+                    )
+                })
+        };
+
+        if plan.bios_boot && !self.has_bios_boot() {
+            let bios = PartitionType::by_code("ef02").expect("BIOS boot type");
+            let number = self.create(remaining(self)?, Some(1), bios)?;
+            self.set_name(number, "BIOS")?;
+        }
+        for spec in &plan.partitions {
+            if let (PartitionRole::Esp, Some(number)) = (spec.role, existing_esp) {
+                self.assign(number, Some(PartitionRole::Esp), None)?;
+                continue;
+            }
+            let number = self.create(
+                remaining(self)?,
+                spec.size_mib,
+                PartitionType::of_role(spec.role),
+            )?;
+            self.assign(number, Some(spec.role), Some(spec.filesystem.clone()))?;
+        }
+        self.validate()
+    }
+
+    /// Check the table fits the disk and describes an installable system.
+    pub fn validate(&self) -> Result<()> {
+        let fail = |reason: String| Err(InstallerError::PreconditionFailed(reason));
+
+        let mut previous_end = None;
+        for entry in &self.entries {
+            let extent = entry.extent;
+            if extent.first_lba < self.first_usable() || extent.last_lba > self.last_usable() {
+                return fail(format!("Partition {} lies outside the disk", entry.number));
+            }
+            if previous_end.is_some_and(|end| extent.first_lba <= end) {
+                return fail(format!(
+                    "Partition {} overlaps the one before it",
+                    entry.number
+                ));
+            }
+            previous_end = Some(extent.last_lba);
+        }
+
+        let count = |role| self.entries.iter().filter(|e| e.role == Some(role)).count();
+        if count(PartitionRole::Root) != 1 {
+            return fail("Exactly one partition has to be the root partition".to_string());
+        }
+        for role in [PartitionRole::Esp, PartitionRole::Swap, PartitionRole::Home] {
+            if count(role) > 1 {
+                return fail(format!("More than one {} partition", role.name()));
+            }
+        }
+
+        for entry in &self.entries {
+            // Also partitions that are kept without a role, e.g. another system's
+            if entry.shrunk() && entry.format.is_none() {
+                return fail(format!(
+                    "Partition {} is smaller than the filesystem on it, format it or restore its size",
+                    entry.number
+                ));
+            }
+            let Some(role) = entry.role else {
+                continue;
+            };
+            if role == PartitionRole::Root && entry.format.is_none() {
+                return fail("The root partition has to be formatted".to_string());
+            }
+            if let Some(spec) = &entry.format {
+                spec.validate()?;
+            }
+            match entry.filesystem() {
+                Some(kind) if role.allows(kind) => {}
+                Some(kind) => {
+                    return fail(format!(
+                        "The {} partition cannot be formatted as {}",
+                        role.name(),
+                        kind.name()
+                    ))
+                }
+                None => {
+                    return fail(format!(
+                        "Partition {} has no filesystem usable as {}, format it",
+                        entry.number,
+                        role.name()
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The table as an sfdisk script, which replaces the whole table while
+    /// keeping the partitions' numbers.
+    pub fn sfdisk_script(&self, disk_path: &str) -> String {
+        let mut script = String::from("label: gpt\n");
+        if let Some(guid) = &self.disk_guid {
+            script.push_str(&format!("label-id: {}\n", guid));
+        }
+        script.push_str("unit: sectors\n");
+        script.push_str(&format!("sector-size: {}\n\n", self.sector_size));
+
+        for entry in &self.entries {
+            let mut line = format!(
+                "{} : start={}, size={}, type={}",
+                partition::partition_path(disk_path, entry.number),
+                entry.extent.first_lba,
+                entry.extent.sectors(),
+                entry.type_guid
+            );
+            if let Some(uuid) = &entry.uuid {
+                line.push_str(&format!(", uuid={}", uuid));
+            }
+            if !entry.name.is_empty() {
+                line.push_str(&format!(", name=\"{}\"", entry.name));
+            }
+            let bits: Vec<String> = (0..64)
+                .filter(|bit| entry.attributes & (1 << bit) != 0)
+                .map(|bit| match bit {
+                    0 => "RequiredPartition".to_string(),
+                    1 => "NoBlockIOProtocol".to_string(),
+                    2 => "LegacyBIOSBootable".to_string(),
+                    bit => format!("GUID:{}", bit),
+                })
+                .collect();
+            if !bits.is_empty() {
+                line.push_str(&format!(", attrs=\"{}\"", bits.join(" ")));
+            }
+            script.push_str(&line);
+            script.push('\n');
+        }
+        script
+    }
+
+    /// The partitions with a role, as the rest of the install works with them.
+    pub fn partitions(&self, disk_path: &str) -> Vec<Partition> {
+        self.entries
+            .iter()
+            .filter_map(|entry| {
+                let role = entry.role?;
+                let filesystem = entry
+                    .format
+                    .clone()
+                    .or_else(|| entry.filesystem().map(FilesystemSpec::new))?;
+                Some(Partition {
+                    role,
+                    number: entry.number,
+                    path: partition::partition_path(disk_path, entry.number),
+                    filesystem,
+                    mapper: None,
+                    format: entry.format.is_some(),
+                })
+            })
+            .collect()
+    }
+
+    /// Replace the partition table of `disk` with this one.
+    pub fn write(&self, runner: &dyn CommandRunner, disk: &BlockDevice) -> Result<Vec<Partition>> {
+        self.validate()?;
+        partition::ensure_unmounted(disk)?;
+
+        // Leave the data of kept partitions alone, new ones are formatted anyway
+        runner.run_checked(
+            &Cmd::new("sfdisk")
+                .args(["--wipe-partitions", "never", &disk.path])
+                .stdin(self.sfdisk_script(&disk.path)),
+        )?;
+        partition::settle(runner, disk)?;
+        Ok(self.partitions(&disk.path))
+    }
+
+    /// Read the partition table of `disk`, or start an empty one on a blank disk.
+    pub fn read(runner: &dyn CommandRunner, disk: &BlockDevice) -> Result<GptTable> {
+        let output = runner.run_checked(
+            &Cmd::new("blockdev")
+                .args(["--getss", &disk.path])
+                .read_only(),
+        )?;
+        let sector_size = output
+            .stdout
+            .trim()
+            .parse()
+            .map_err(|err| InstallerError::parse("blockdev sector size", err))?;
+
+        let output = runner.run(&Cmd::new("sfdisk").args(["--json", &disk.path]).read_only())?;
+        // sfdisk fails on a disk without any partition table
+        if !output.is_success() && disk.partitions.is_empty() {
+            return Ok(GptTable::new(disk.size, sector_size));
+        }
+        let dump: SfdiskDump = serde_json::from_str(&output.stdout)
+            .map_err(|err| InstallerError::parse("sfdisk output", err))?;
+        GptTable::from_dump(dump.partitiontable, disk, sector_size)
+    }
+
+    fn from_dump(dump: SfdiskTable, disk: &BlockDevice, sector_size: u64) -> Result<GptTable> {
+        if dump.label != "gpt" {
+            return Err(InstallerError::PreconditionFailed(format!(
+                "{} has a {} partition table, only GPT can be edited",
+                disk.path, dump.label
+            )));
+        }
+        let mut table = GptTable::new(disk.size, dump.sectorsize.unwrap_or(sector_size));
+        table.disk_guid = dump.id;
+        for part in dump.partitions {
+            let number = part
+                .node
+                .trim_end_matches(|c: char| c.is_ascii_digit())
+                .len();
+            let number = part.node[number..]
+                .parse()
+                .map_err(|err| InstallerError::parse("sfdisk partition node", err))?;
+            let extent = Extent {
+                first_lba: part.start,
+                last_lba: part.start + part.size - 1,
+            };
+            table.entries.push(GptEntry {
+                number,
+                extent,
+                type_guid: part.type_guid.to_uppercase(),
+                name: part.name.unwrap_or_default(),
+                attributes: part.attrs.as_deref().map_or(0, parse_attrs),
+                uuid: part.uuid,
+                existing: Some(extent),
+                fstype: disk
+                    .partitions
+                    .iter()
+                    .find(|p| p.path == part.node)
+                    .and_then(|p| p.fstype.clone()),
+                role: None,
+                format: None,
+            });
+        }
+        table.entries.sort_by_key(|e| e.extent.first_lba);
+        Ok(table)
+    }
+}
+
+fn no_partition(number: u32) -> InstallerError {
+    InstallerError::PreconditionFailed(format!("There is no partition {}", number))
+}
+
+// sfdisk's attrs, e.g. "RequiredPartition LegacyBIOSBootable GUID:62,63"
+fn parse_attrs(attrs: &str) -> u64 {
+    attrs
+        .split([' ', ','])
+        .filter_map(|token| match token {
+            "RequiredPartition" => Some(0),
+            "NoBlockIOProtocol" => Some(1),
+            "LegacyBIOSBootable" => Some(2),
+            token => token.trim_start_matches("GUID:").parse::<u32>().ok(),
+        })
+        .filter(|&bit| bit < 64)
+        .fold(0, |attributes, bit| attributes | 1 << bit)
+}
+
+#[derive(Deserialize)]
+struct SfdiskDump {
+    partitiontable: SfdiskTable,
+}
+
+#[derive(Deserialize)]
+struct SfdiskTable {
+    label: String,
+    id: Option<String>,
+    sectorsize: Option<u64>,
+    #[serde(default)]
+    partitions: Vec<SfdiskPartition>,
+}
+
+#[derive(Deserialize)]
+struct SfdiskPartition {
+    node: String,
+    start: u64,
+    size: u64,
+    #[serde(rename = "type")]
+    type_guid: String,
+    uuid: Option<String>,
+    name: Option<String>,
+    attrs: Option<String>,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        command::{CommandOutput, MockRunner},
+        disk::tests::disk,
+    };
+
+    const DUAL_BOOT: &str = include_str!("../tests/fixtures/sfdisk-dual-boot.json");
+    const DISK_SIZE: u64 = 500107862016;
+
+    fn kind(code: &str) -> &'static PartitionType {
+        PartitionType::by_code(code).unwrap()
+    }
+
+    // The Windows disk of the fixture, read through sfdisk as the editor does
+    pub(crate) fn dual_boot() -> (GptTable, BlockDevice) {
+        let mut nvme = disk("/dev/nvme0n1", DISK_SIZE);
+        for (path, fstype) in [("/dev/nvme0n1p1", "vfat"), ("/dev/nvme0n1p3", "ntfs")] {
+            let mut part = disk(path, 0);
+            part.device_type = "part".to_string();
+            part.fstype = Some(fstype.to_string());
+            nvme.partitions.push(part);
+        }
+        let runner = MockRunner::new();
+        runner.on_args(
+            "blockdev",
+            &["--getss", "/dev/nvme0n1"],
+            CommandOutput::success("512\n"),
+        );
+        runner.on("sfdisk", CommandOutput::success(DUAL_BOOT));
+        let table = GptTable::read(&runner, &nvme).unwrap();
+        assert!(runner.invocations().iter().all(|cmd| cmd.read_only));
+        (table, nvme)
+    }
+
+    #[test]
+    fn partitions_start_on_mebibyte_boundaries() {
+        let table = GptTable::new(64 << 30, 512);
+        assert_eq!(table.first_usable(), 2048);
+        assert_eq!(table.last_usable(), (64 << 21) - 34);
+        assert_eq!(GptTable::new(64 << 30, 4096).first_usable(), 256);
+
+        // A partition ending off the grid pushes the next free region to the boundary after it
+        let mut table = GptTable::new(64 << 30, 512);
+        let free = table.largest_free().unwrap();
+        let number = table.create(free, Some(1), kind("8300")).unwrap();
+        table.entry_mut(number).unwrap().extent.last_lba = 5000;
+        let free = table.free_regions();
+        assert_eq!(free[0].first_lba, 6144);
+        assert_eq!(free[0].last_lba, table.last_usable());
+
+        let number = table.create(free[0], Some(100), kind("8300")).unwrap();
+        let extent = table.entry(number).unwrap().extent;
+        assert_eq!((extent.first_lba, extent.sectors()), (6144, 204800));
+        assert_eq!(table.free_regions()[0].first_lba, 6144 + 204800);
+    }
+
+    #[test]
+    fn overlapping_and_out_of_range_partitions_are_rejected() {
+        let mut table = GptTable::new(8 << 30, 512);
+        let free = table.largest_free().unwrap();
+        let first = table.create(free, Some(512), kind("8304")).unwrap();
+        table
+            .assign(
+                first,
+                Some(PartitionRole::Root),
+                Some(FilesystemSpec::new(FilesystemKind::Ext4)),
+            )
+            .unwrap();
+        table.validate().unwrap();
+
+        // The space the first partition took is no longer free
+        let err = table.create(free, Some(1), kind("8300")).unwrap_err();
+        assert_eq!(err.to_string(), "That space is not free");
+        let rest = table.largest_free().unwrap();
+        let err = table.create(rest, Some(8 << 10), kind("8300")).unwrap_err();
+        assert_eq!(err.to_string(), "Only 7678 MiB are free there");
+
+        table.create(rest, Some(1024), kind("8302")).unwrap();
+        let err = table.resize(first, 1024).unwrap_err();
+        assert_eq!(err.to_string(), "Partition 1 can grow to at most 512 MiB");
+        table.resize(first, 256).unwrap();
+
+        let mut overlapping = table.clone();
+        overlapping.entries[1].extent.first_lba = overlapping.entries[0].extent.last_lba;
+        assert_eq!(
+            overlapping.validate().unwrap_err().to_string(),
+            "Partition 2 overlaps the one before it"
+        );
+        let mut outside = table.clone();
+        outside.entries[1].extent.last_lba = outside.last_usable() + 1;
+        assert_eq!(
+            outside.validate().unwrap_err().to_string(),
+            "Partition 2 lies outside the disk"
+        );
+        let mut before = table;
+        before.entries[0].extent.first_lba = 34;
+        assert_eq!(
+            before.validate().unwrap_err().to_string(),
+            "Partition 1 lies outside the disk"
+        );
+    }
+
+    #[test]
+    fn deleting_frees_the_space_again() {
+        let mut table = GptTable::new(8 << 30, 512);
+        for _ in 0..3 {
+            let free = table.largest_free().unwrap();
+            table.create(free, Some(1024), kind("8300")).unwrap();
+        }
+        assert_eq!(table.free_regions().len(), 1);
+
+        table.delete(2).unwrap();
+        let free = table.free_regions();
+        assert_eq!(free.len(), 2);
+        assert_eq!(free[0].first_lba, 2048 + 2097152);
+        assert_eq!(free[0].sectors(), 2097152);
+        // Never on the disk, so there is nothing to remove there
+        assert!(table.removed.is_empty());
+        // The lowest free number is reused
+        assert_eq!(table.create(free[0], None, kind("8300")).unwrap(), 2);
+        assert_eq!(table.free_regions().len(), 1);
+
+        let (mut table, _) = dual_boot();
+        table.delete(3).unwrap();
+        assert_eq!(table.removed.len(), 1);
+        assert_eq!(table.free_regions()[0].first_lba, 239616);
+        assert_eq!(
+            table.delete(3).unwrap_err().to_string(),
+            "There is no partition 3"
+        );
+    }
+
+    #[test]
+    fn sfdisk_dump_round_trips() {
+        let (table, _) = dual_boot();
+        assert_eq!(table.sector_size, 512);
+        assert_eq!(table.total_sectors, 976773168);
+        assert_eq!(table.entries[0].fstype.as_deref(), Some("vfat"));
+        assert!(table.entries[0].has_flag(Flag::NoAutomount));
+        assert!(table.entries[3].has_flag(Flag::Required));
+        assert_eq!(type_name(&table.entries[3].type_guid), "Windows recovery");
+        assert!(table.entries.iter().all(|e| e.existing == Some(e.extent)));
+
+        assert_eq!(
+            table.sfdisk_script("/dev/nvme0n1"),
+            "label: gpt
+label-id: 3C1D2A6E-8F41-4B2C-9A57-0E6B1D4C7F21
+unit: sectors
+sector-size: 512
+
+/dev/nvme0n1p1 : start=2048, size=204800, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, uuid=5A0C3E1B-6D2F-4E8A-B1C4-7F9D2E6A3B10, name=\"EFI system partition\", attrs=\"GUID:63\"
+/dev/nvme0n1p2 : start=206848, size=32768, type=E3C9E316-0B5C-4DB8-817D-F92DF00215AE, uuid=8E4B2F7C-1A3D-4C5E-9F60-2B7D8C1E4A93, name=\"Microsoft reserved partition\"
+/dev/nvme0n1p3 : start=239616, size=409600000, type=EBD0A0A2-B9E5-4433-87C0-68B6B72699C7, uuid=C7D1E2F3-4A5B-4C6D-8E7F-9A0B1C2D3E4F, name=\"Basic data partition\"
+/dev/nvme0n1p4 : start=409839616, size=1048576, type=DE94BBA4-06D1-4D40-A16A-BFD50179D6AC, uuid=0F1E2D3C-4B5A-4968-8776-A5B4C3D2E1F0, attrs=\"RequiredPartition GUID:63\"
+"
+        );
+    }
+
+    #[test]
+    fn attrs_are_parsed_into_bits() {
+        assert_eq!(parse_attrs(""), 0);
+        assert_eq!(parse_attrs("RequiredPartition"), 1);
+        assert_eq!(parse_attrs("NoBlockIOProtocol LegacyBIOSBootable"), 0b110);
+        assert_eq!(parse_attrs("GUID:62,63"), 3 << 62);
+        assert_eq!(parse_attrs("GUID:64 bogus"), 0);
+    }
+
+    #[test]
+    fn alongside_keeps_the_existing_partitions_in_the_script() {
+        let (mut table, nvme) = dual_boot();
+        let free = table.largest_free().unwrap();
+        table
+            .install_alongside(free, &PartitionPlan::default())
+            .unwrap();
+        assert_eq!(table.shared_esp().map(|e| e.number), Some(1));
+
+        let runner = MockRunner::new();
+        let partitions = table.write(&runner, &nvme).unwrap();
+        let sfdisk = &runner.invocations()[0];
+        assert_eq!(
+            sfdisk.to_string(),
+            "sfdisk --wipe-partitions never /dev/nvme0n1"
+        );
+        let script = sfdisk.stdin.as_deref().unwrap();
+        let lines: Vec<&str> = script.lines().collect();
+        assert_eq!(lines.len(), 5 + 5);
+        // Kept partitions keep their place, identity and attributes
+        for line in &lines[5..9] {
+            assert!(line.contains(", uuid="), "{}", line);
+        }
+        assert!(lines[5].ends_with(", attrs=\"GUID:63\""));
+        assert_eq!(
+            lines[9],
+            "/dev/nvme0n1p5 : start=410888192, size=565884943, type=4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709, name=\"root\""
+        );
+
+        let roles: Vec<(PartitionRole, &str, bool)> = partitions
+            .iter()
+            .map(|p| (p.role, p.path.as_str(), p.format))
+            .collect();
+        assert_eq!(
+            roles,
+            [
+                (PartitionRole::Esp, "/dev/nvme0n1p1", false),
+                (PartitionRole::Root, "/dev/nvme0n1p5", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn shrinking_a_kept_partition_is_refused() {
+        let (mut table, _) = dual_boot();
+        let free = table.largest_free().unwrap();
+        table
+            .install_alongside(free, &PartitionPlan::default())
+            .unwrap();
+
+        // Windows stays unassigned, cutting it would break its filesystem
+        table.resize(3, 100 << 10).unwrap();
+        assert!(table.entry(3).unwrap().role.is_none());
+        assert_eq!(
+            table.validate().unwrap_err().to_string(),
+            "Partition 3 is smaller than the filesystem on it, format it or restore its size"
+        );
+
+        // Growing back to the original size is fine again
+        table.resize(3, 200000).unwrap();
+        table.validate().unwrap();
+    }
+}
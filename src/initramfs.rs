@@ -0,0 +1,143 @@
+use std::path::Path;
+
+use crate::{
+    command::{target_path, Cmd, CommandRunner},
+    config::InstallConfig,
+    crypt::EncryptHook,
+    error::Result,
+};
+
+// Drop-in read by mkinitcpio after /etc/mkinitcpio.conf
+const DROP_IN: &str = "/etc/mkinitcpio.conf.d/archinstaller.conf";
+
+/// mkinitcpio HOOKS for the chosen setup.
+pub fn hooks(config: &InstallConfig) -> Vec<&'static str> {
+    let systemd = matches!(
+        config.encryption.as_ref().map(|e| e.hook),
+        Some(EncryptHook::SdEncrypt)
+    );
+
+    let mut hooks = if systemd {
+        vec!["base", "systemd", "autodetect", "microcode", "modconf", "kms", "keyboard", "sd-vconsole", "block"]
+    } else {
+        vec!["base", "udev", "autodetect", "microcode", "modconf", "kms", "keyboard", "keymap", "consolefont", "block"]
+    };
+
+    // Arrays are assembled before anything on them can be unlocked or mounted
+    if config.uses_mdadm() {
+        hooks.push("mdadm_udev");
+    }
+
+    // Unlocking has to happen after block devices show up and before filesystems are mounted
+    match config.encryption.as_ref().map(|e| e.hook) {
+        Some(EncryptHook::Encrypt) => hooks.push("encrypt"),
+        Some(EncryptHook::SdEncrypt) => hooks.push("sd-encrypt"),
+        None => {}
+    }
+
+    hooks.extend(["filesystems", "fsck"]);
+    hooks
+}
+
+/// Write the HOOKS drop-in into the target and rebuild every initramfs.
+pub fn configure(runner: &dyn CommandRunner, target: &Path, config: &InstallConfig) -> Result<()> {
+    let contents = format!("HOOKS=({})\n", hooks(config).join(" "));
+    runner.write_file(&target_path(target, DROP_IN), &contents)?;
+    runner.run_checked(&Cmd::chroot(target, "mkinitcpio").arg("-P"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::MockRunner,
+        crypt::{Encryption, LuksKey},
+        raid::{MirrorKind, MultiDisk},
+    };
+
+    fn encrypted(hook: EncryptHook) -> InstallConfig {
+        let mut encryption = Encryption::new(LuksKey::Keyfile("/root/luks.key".into()));
+        encryption.hook = hook;
+        InstallConfig {
+            encryption: Some(encryption),
+            ..InstallConfig::default()
+        }
+    }
+
+    fn mirrored(mut config: InstallConfig, raid: MirrorKind) -> InstallConfig {
+        config.multi_disk = Some(MultiDisk::Mirror {
+            disks: vec!["/dev/sdb".to_string()],
+            raid,
+        });
+        config
+    }
+
+    fn position(hooks: &[&str], hook: &str) -> usize {
+        hooks
+            .iter()
+            .position(|h| *h == hook)
+            .unwrap_or_else(|| panic!("no {} in {:?}", hook, hooks))
+    }
+
+    #[test]
+    fn busybox_initramfs_unless_sd_encrypt_is_chosen() {
+        let plain = hooks(&InstallConfig::default());
+        assert_eq!(
+            plain,
+            ["base", "udev", "autodetect", "microcode", "modconf", "kms", "keyboard", "keymap", "consolefont", "block", "filesystems", "fsck"]
+        );
+        assert_eq!(hooks(&encrypted(EncryptHook::Encrypt))[1], "udev");
+
+        let systemd = hooks(&encrypted(EncryptHook::SdEncrypt));
+        assert_eq!(systemd[1], "systemd");
+        assert!(systemd.contains(&"sd-vconsole"));
+        for busybox_only in ["udev", "keymap", "consolefont", "encrypt"] {
+            assert!(!systemd.contains(&busybox_only), "{} in {:?}", busybox_only, systemd);
+        }
+    }
+
+    #[test]
+    fn unlocking_happens_between_block_and_filesystems() {
+        for (hook, unlock) in [
+            (EncryptHook::Encrypt, "encrypt"),
+            (EncryptHook::SdEncrypt, "sd-encrypt"),
+        ] {
+            let hooks = hooks(&encrypted(hook));
+            let at = position(&hooks, unlock);
+            assert!(position(&hooks, "keyboard") < at, "{:?}", hooks);
+            assert!(position(&hooks, "block") < at, "{:?}", hooks);
+            assert_eq!(hooks[at + 1], "filesystems");
+        }
+    }
+
+    #[test]
+    fn mdadm_mirrors_assemble_before_unlocking() {
+        assert!(!hooks(&InstallConfig::default()).contains(&"mdadm_udev"));
+        let btrfs = mirrored(InstallConfig::default(), MirrorKind::Btrfs);
+        assert!(!hooks(&btrfs).contains(&"mdadm_udev"));
+
+        let hooks = hooks(&mirrored(encrypted(EncryptHook::Encrypt), MirrorKind::Mdadm));
+        let mdadm = position(&hooks, "mdadm_udev");
+        assert!(position(&hooks, "block") < mdadm);
+        assert!(mdadm < position(&hooks, "encrypt"));
+        assert!(mdadm < position(&hooks, "filesystems"));
+    }
+
+    #[test]
+    fn configure_writes_the_drop_in_and_rebuilds() {
+        let runner = MockRunner::new();
+        configure(&runner, Path::new("/mnt"), &encrypted(EncryptHook::SdEncrypt)).unwrap();
+        let files = runner.written_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            files[0].0,
+            Path::new("/mnt/etc/mkinitcpio.conf.d/archinstaller.conf")
+        );
+        assert_eq!(
+            files[0].1,
+            "HOOKS=(base systemd autodetect microcode modconf kms keyboard sd-vconsole block sd-encrypt filesystems fsck)\n"
+        );
+        assert_eq!(runner.command_lines(), ["arch-chroot /mnt mkinitcpio -P"]);
+    }
+}
@@ -0,0 +1,525 @@
+use std::path::Path;
+
+use crate::{
+    bootloader::{self, BootMode, Bootloader},
+    btrfs,
+    command::{mount_depth, target_path, CommandRunner, Mount},
+    config::InstallConfig,
+    crypt,
+    disk::BlockDevice,
+    error::{InstallerError, Result},
+    format::{self, FilesystemKind},
+    fstab, initramfs, locale,
+    mount::MountManager,
+    network, pacstrap,
+    partition::{self, Partition, PartitionRole},
+    raid::{self, MultiDisk},
+    users,
+};
+
+// Where the new system is assembled
+pub const TARGET: &str = "/mnt";
+
+/// Receives progress while installing and gets a chance to abort between steps.
+pub trait Progress {
+    fn stage(&mut self, _name: &str) {}
+
+    // A line of output from a long running command
+    fn log(&mut self, _line: &str) {}
+
+    // Returning true stops the install and unwinds everything mounted so far
+    fn cancelled(&mut self) -> bool {
+        false
+    }
+}
+
+// For callers that do not report progress
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+fn begin(progress: &mut dyn Progress, stage: &str) -> Result<()> {
+    if progress.cancelled() {
+        return Err(InstallerError::Cancelled);
+    }
+    progress.stage(stage);
+    Ok(())
+}
+
+/// Run every install stage against `disk`. In dry-run mode `runner` only
+/// records what would happen, so this is also how the install plan is built.
+/// The boot mode and network profiles are taken from the live system at `live_root`.
+pub fn install(
+    runner: &dyn CommandRunner,
+    live_root: &Path,
+    disk: &BlockDevice,
+    config: &InstallConfig,
+    progress: &mut dyn Progress,
+) -> Result<()> {
+    config.validate()?;
+
+    let extra = match &config.multi_disk {
+        Some(multi) => raid::extra_disks(runner, multi)?,
+        None => Vec::new(),
+    };
+    if let Some(disk) = std::iter::once(disk).chain(&extra).find(|d| d.read_only) {
+        return Err(InstallerError::PreconditionFailed(format!(
+            "{} is read-only",
+            disk.path
+        )));
+    }
+
+    let mode = bootloader::detect_boot_mode(live_root);
+    let config = &prepare(config, mode)?;
+    let chosen = Bootloader::choose(config.bootloader, mode)?;
+
+    let root_is_btrfs = config.root_filesystem() == Some(FilesystemKind::Btrfs);
+    if root_is_btrfs {
+        // Catch a bad layout before anything is written
+        config.btrfs.validate()?;
+    }
+
+    let target = Path::new(TARGET);
+    // Anything mounted or opened below is released again if a later step fails
+    let mut mounts = MountManager::new(runner);
+
+    begin(progress, "Partitioning")?;
+    let mut partitions = match (&config.manual_partitions, &config.multi_disk) {
+        (Some(table), _) => table.write(runner, disk)?,
+        (None, Some(multi)) => {
+            raid::partition_disks(runner, &mut mounts, disk, &extra, config, multi)?
+        }
+        (None, None) => partition::partition_disk(runner, disk, &config.partitions)?,
+    };
+
+    let root = partitions
+        .iter_mut()
+        .find(|p| p.role == PartitionRole::Root)
+        .ok_or_else(|| InstallerError::PreconditionFailed("No root partition".to_string()))?;
+    if let Some(encryption) = &config.encryption {
+        begin(progress, "Encrypting")?;
+        root.mapper = Some(crypt::setup_luks(runner, &root.path, encryption)?);
+        mounts.track_luks(crypt::ROOT_MAPPER);
+    }
+    let root = root.clone();
+
+    begin(progress, "Formatting")?;
+    format::format_partitions(runner, &partitions)?;
+
+    if root_is_btrfs {
+        begin(progress, "Creating subvolumes")?;
+        let layout = btrfs_layout(&partitions, config);
+        btrfs::create_subvolumes(runner, &mut mounts, root.device(), &layout, target)?;
+    }
+
+    begin(progress, "Mounting")?;
+    for mount in target_mounts(&partitions, config, target) {
+        mounts.mount(&mount)?;
+    }
+
+    begin(progress, "Installing packages")?;
+    pacstrap::pacstrap(runner, target, config, progress)?;
+
+    begin(progress, "Writing fstab")?;
+    fstab::write_fstab(
+        runner,
+        mounts.mounts(),
+        &partitions,
+        target,
+        config.fstab_identifier,
+    )?;
+
+    begin(progress, "Configuring system")?;
+    locale::configure(runner, target, &config.locale)?;
+    network::configure(runner, target, live_root, config)?;
+
+    if config.uses_mdadm() {
+        raid::write_mdadm_conf(runner, target)?;
+    }
+    if config.encryption.is_some() || config.uses_mdadm() {
+        begin(progress, "Configuring initramfs")?;
+        initramfs::configure(runner, target, config)?;
+    }
+
+    begin(progress, "Creating users")?;
+    users::configure(runner, target, config)?;
+
+    begin(progress, "Installing bootloader")?;
+    let params = bootloader::kernel_params(runner, &root, config)?;
+    // A mirror has to boot from whichever disk is left
+    let boot_disks: Vec<BlockDevice> = match &config.multi_disk {
+        Some(MultiDisk::Mirror { .. }) => std::iter::once(disk).chain(&extra).cloned().collect(),
+        _ => vec![disk.clone()],
+    };
+    bootloader::install_bootloader(
+        runner,
+        target,
+        chosen,
+        mode,
+        &boot_disks,
+        &params,
+        config.keeps_other_systems(),
+    )?;
+
+    begin(progress, "Unmounting")?;
+    mounts.release()?;
+
+    Ok(())
+}
+
+/// `config` with everything that depends on how the live system was booted
+/// settled: the bootloader, a BIOS boot partition and the bootloader packages.
+/// A manual partition table is checked for what this boot mode needs.
+pub fn prepare(config: &InstallConfig, mode: BootMode) -> Result<InstallConfig> {
+    let chosen = Bootloader::choose(config.bootloader, mode)?;
+    let mut config = config.clone();
+    config.bootloader = Some(chosen);
+    config.partitions.bios_boot = mode == BootMode::Bios;
+    if let Some(table) = &config.manual_partitions {
+        table.validate()?;
+        // The automatic layout adds these itself, a manual one has to have them
+        match mode {
+            BootMode::Uefi if table.find(PartitionRole::Esp).is_none() => {
+                return Err(InstallerError::PreconditionFailed(
+                    "Booting in UEFI mode needs a partition used as the EFI system partition"
+                        .to_string(),
+                ))
+            }
+            BootMode::Bios if !table.has_bios_boot() => {
+                return Err(InstallerError::PreconditionFailed(
+                    "GRUB on a GPT disk booted in BIOS mode needs a BIOS boot partition (type ef02)"
+                        .to_string(),
+                ))
+            }
+            _ => {}
+        }
+    }
+    config
+        .packages
+        .extra
+        .extend(chosen.packages(mode).iter().map(|p| p.to_string()));
+    if chosen == Bootloader::Grub && config.keeps_other_systems() {
+        // Finds the other systems for GRUB's menu
+        config.packages.extra.push("os-prober".to_string());
+    }
+    Ok(config)
+}
+
+// The configured btrfs layout minus anything a dedicated partition takes over
+fn btrfs_layout(partitions: &[Partition], config: &InstallConfig) -> btrfs::BtrfsLayout {
+    match Partition::find(partitions, PartitionRole::Home) {
+        Some(_) => config.btrfs.without_mountpoint("/home"),
+        None => config.btrfs.clone(),
+    }
+}
+
+/// Every mount that makes up the target system, parents before children.
+pub fn target_mounts(
+    partitions: &[Partition],
+    config: &InstallConfig,
+    target: &Path,
+) -> Vec<Mount> {
+    let mut mounts = Vec::new();
+
+    for partition in partitions {
+        let kind = partition.filesystem.kind;
+        match (partition.role, kind) {
+            (PartitionRole::Swap, _) => {}
+            (PartitionRole::Root, FilesystemKind::Btrfs) => {
+                mounts.extend(btrfs_layout(partitions, config).mounts(partition.device(), target));
+            }
+            (role, kind) => {
+                let mountpoint = role.mountpoint().unwrap_or("/");
+                let mut mount = Mount::new(partition.device(), target_path(target, mountpoint))
+                    .fstype(kind.name());
+                if role == PartitionRole::Esp {
+                    // Keep the loader and its random seed private
+                    mount = mount.option("fmask=0077").option("dmask=0077");
+                }
+                mounts.push(mount);
+            }
+        }
+    }
+
+    // Stable sort keeps the btrfs subvolume order for equal depths
+    mounts.sort_by_key(|m| {
+        let relative = m.target.strip_prefix(target).unwrap_or(&m.target);
+        mount_depth(&relative.to_string_lossy())
+    });
+    mounts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{MockRunner, REDACTED},
+        crypt::{EncryptHook, Encryption, LuksKey},
+        disk::tests::disk,
+        network::NetworkStack,
+        plan::{DryRunRunner, PlannedAction},
+        testing::TempDir,
+    };
+
+    // Live system booted in UEFI mode
+    fn uefi_live() -> TempDir {
+        let live = TempDir::new();
+        live.mkdir("sys/firmware/efi");
+        live
+    }
+
+    // Install `config` onto a blank 64 GiB /dev/sda from a UEFI booted live
+    // system without touching anything
+    fn plan(
+        config: &InstallConfig,
+        progress: &mut dyn Progress,
+    ) -> (Result<()>, Vec<PlannedAction>) {
+        plan_from(&uefi_live(), config, progress)
+    }
+
+    fn plan_from(
+        live: &TempDir,
+        config: &InstallConfig,
+        progress: &mut dyn Progress,
+    ) -> (Result<()>, Vec<PlannedAction>) {
+        let host = MockRunner::new();
+        let runner = DryRunRunner::new(&host);
+        let config = InstallConfig {
+            disk: Some("/dev/sda".to_string()),
+            ..config.clone()
+        };
+        let sda = disk("/dev/sda", 64 << 30);
+        let result = install(&runner, live.path(), &sda, &config, progress);
+        // Only read-only commands may reach the host while planning
+        assert!(host.invocations().iter().all(|cmd| cmd.read_only));
+        (result, runner.plan().actions)
+    }
+
+    fn lines(actions: &[PlannedAction]) -> Vec<String> {
+        actions.iter().map(PlannedAction::to_string).collect()
+    }
+
+    fn position(lines: &[String], prefix: &str) -> usize {
+        lines
+            .iter()
+            .position(|line| line.starts_with(prefix))
+            .unwrap_or_else(|| panic!("no step starting with {:?} in {:#?}", prefix, lines))
+    }
+
+    #[test]
+    fn default_install_runs_every_stage_in_order() {
+        let (result, actions) = plan(&InstallConfig::default(), &mut NoProgress);
+        result.unwrap();
+        let lines = lines(&actions);
+
+        let order = [
+            "run    wipefs --all /dev/sda",
+            "run    sgdisk --new=2:0:0 --typecode=2:8304 --change-name=2:root /dev/sda",
+            "run    mkfs.fat -F 32 /dev/sda1",
+            "run    mkfs.ext4 -F /dev/sda2",
+            "mount  /dev/sda2 on /mnt type ext4",
+            "mount  /dev/sda1 on /mnt/boot type vfat (fmask=0077,dmask=0077)",
+            "run    pacstrap -K /mnt base linux linux-firmware",
+            "write  /mnt/etc/fstab",
+            "write  /mnt/etc/hostname",
+            "run    arch-chroot /mnt passwd -l root",
+            "run    umount /mnt/boot",
+        ];
+        let positions: Vec<usize> = order.iter().map(|step| position(&lines, step)).collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]), "{:#?}", lines);
+        assert_eq!(lines.last().unwrap(), "run    umount /mnt");
+        // Nothing to unlock at boot, so the stock initramfs is kept
+        assert!(!lines.iter().any(|l| l.contains("mkinitcpio")));
+    }
+
+    #[test]
+    fn encrypted_root_is_formatted_through_the_mapper() {
+        let config = InstallConfig {
+            encryption: Some(Encryption::new(LuksKey::Passphrase("hunter2".to_string()))),
+            ..InstallConfig::default()
+        };
+        let (result, actions) = plan(&config, &mut NoProgress);
+        result.unwrap();
+
+        let luks_format = actions
+            .iter()
+            .find_map(|action| match action {
+                PlannedAction::Command {
+                    program,
+                    args,
+                    stdin,
+                } if program == "cryptsetup" && args[0] == "luksFormat" => {
+                    Some((args.clone(), stdin.clone()))
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(luks_format.0.last().unwrap(), "/dev/sda2");
+        assert_eq!(luks_format.1.as_deref(), Some(REDACTED));
+        assert!(!format!("{:?}", actions).contains("hunter2"));
+
+        let lines = lines(&actions);
+        assert!(
+            position(&lines, "run    cryptsetup open")
+                < position(&lines, "run    mkfs.ext4 -F /dev/mapper/cryptroot")
+        );
+        position(&lines, "mount  /dev/mapper/cryptroot on /mnt type ext4");
+        let hooks = position(
+            &lines,
+            "write  /mnt/etc/mkinitcpio.conf.d/archinstaller.conf",
+        );
+        assert!(lines[hooks].contains("block encrypt filesystems"));
+        position(&lines, "run    arch-chroot /mnt mkinitcpio -P");
+        // The container is closed after everything on it is unmounted
+        assert_eq!(lines.last().unwrap(), "run    cryptsetup close cryptroot");
+    }
+
+    #[test]
+    fn sd_encrypt_root_is_unlocked_by_name() {
+        let mut encryption = Encryption::new(LuksKey::Passphrase("hunter2".to_string()));
+        encryption.hook = EncryptHook::SdEncrypt;
+        let config = InstallConfig {
+            encryption: Some(encryption),
+            ..InstallConfig::default()
+        };
+        let (result, actions) = plan(&config, &mut NoProgress);
+        result.unwrap();
+        let lines = lines(&actions);
+
+        let hooks = position(
+            &lines,
+            "write  /mnt/etc/mkinitcpio.conf.d/archinstaller.conf",
+        );
+        assert!(lines[hooks].contains("sd-encrypt"));
+        let entry = position(&lines, "write  /mnt/boot/loader/entries/arch.conf");
+        assert!(lines[entry].contains(
+            "options rd.luks.name=<UUID of /dev/sda2>=cryptroot root=/dev/mapper/cryptroot rw"
+        ));
+    }
+
+    #[test]
+    fn btrfs_root_gets_its_subvolumes_mounted() {
+        let mut config = InstallConfig::default();
+        config
+            .partitions
+            .find_mut(PartitionRole::Root)
+            .unwrap()
+            .filesystem = format::FilesystemSpec::new(FilesystemKind::Btrfs);
+        let (result, actions) = plan(&config, &mut NoProgress);
+        result.unwrap();
+        let lines = lines(&actions);
+
+        position(&lines, "run    btrfs subvolume create /mnt/@home");
+        let root = position(&lines, "mount  /dev/sda2 on /mnt type btrfs (subvol=/@,");
+        let home = position(
+            &lines,
+            "mount  /dev/sda2 on /mnt/home type btrfs (subvol=/@home,",
+        );
+        assert!(root < home);
+        assert!(lines
+            .iter()
+            .any(|l| l.starts_with("run    pacstrap") && l.contains("btrfs-progs")));
+    }
+
+    // Cancels once `stage` begins
+    struct CancelAt(&'static str, bool);
+
+    impl Progress for CancelAt {
+        fn stage(&mut self, name: &str) {
+            self.1 |= name == self.0;
+        }
+
+        fn cancelled(&mut self) -> bool {
+            self.1
+        }
+    }
+
+    #[test]
+    fn cancelling_unwinds_the_mounts() {
+        let (result, actions) = plan(
+            &InstallConfig::default(),
+            &mut CancelAt("Installing packages", false),
+        );
+        assert!(matches!(result, Err(InstallerError::Cancelled)));
+        let lines = lines(&actions);
+        assert!(!lines.iter().any(|l| l.contains("write  /mnt/etc/fstab")));
+        assert_eq!(
+            lines[lines.len() - 2..],
+            ["run    umount /mnt/boot", "run    umount /mnt"]
+        );
+    }
+
+    #[test]
+    fn cancelling_before_mounting_still_closes_the_container() {
+        let config = InstallConfig {
+            encryption: Some(Encryption::new(LuksKey::Passphrase("hunter2".to_string()))),
+            ..InstallConfig::default()
+        };
+        let (result, actions) = plan(&config, &mut CancelAt("Formatting", false));
+        assert!(matches!(result, Err(InstallerError::Cancelled)));
+        let lines = lines(&actions);
+        assert!(!lines.iter().any(|l| l.starts_with("mount  ")));
+        assert!(!lines.iter().any(|l| l.contains("umount")));
+        assert_eq!(lines.last().unwrap(), "run    cryptsetup close cryptroot");
+    }
+
+    #[test]
+    fn read_only_disk_is_refused() {
+        let runner = MockRunner::new();
+        let mut sda = disk("/dev/sda", 64 << 30);
+        sda.read_only = true;
+        let config = InstallConfig {
+            disk: Some(sda.path.clone()),
+            ..InstallConfig::default()
+        };
+        let result = install(&runner, Path::new("/"), &sda, &config, &mut NoProgress);
+        assert!(matches!(result, Err(InstallerError::PreconditionFailed(_))));
+        assert!(runner.invocations().is_empty());
+    }
+
+    #[test]
+    fn boot_mode_comes_from_the_live_system() {
+        let (result, actions) = plan(&InstallConfig::default(), &mut NoProgress);
+        result.unwrap();
+        let uefi = lines(&actions);
+        position(
+            &uefi,
+            "run    arch-chroot /mnt bootctl --esp-path=/boot install",
+        );
+        assert!(!uefi.iter().any(|l| l.contains("grub")));
+
+        let bios_live = TempDir::new();
+        let (result, actions) = plan_from(&bios_live, &InstallConfig::default(), &mut NoProgress);
+        result.unwrap();
+        let bios = lines(&actions);
+        position(
+            &bios,
+            "run    sgdisk --set-alignment=1 --new=128:34:2047 --typecode=128:ef02",
+        );
+        position(
+            &bios,
+            "run    arch-chroot /mnt grub-install --target=i386-pc /dev/sda",
+        );
+        assert!(!bios.iter().any(|l| l.contains("bootctl")));
+    }
+
+    #[test]
+    fn network_profiles_are_copied_from_the_live_system() {
+        let live = uefi_live();
+        live.write(
+            "etc/systemd/network/50-static.network",
+            "[Match]\nName=enp1s0\n",
+        );
+        let mut config = InstallConfig::default();
+        config.network.stack = Some(NetworkStack::Networkd);
+        config.network.copy_live_profiles = true;
+        let (result, actions) = plan_from(&live, &config, &mut NoProgress);
+        result.unwrap();
+
+        let copy = format!(
+            "run    cp -a {}/etc/systemd/network/. /mnt/etc/systemd/network",
+            live.path().display()
+        );
+        position(&lines(&actions), &copy);
+    }
+}
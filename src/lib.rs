@@ -0,0 +1,27 @@
+pub mod bootloader;
+pub mod btrfs;
+pub mod command;
+pub mod config;
+pub mod crypt;
+pub mod disk;
+pub mod error;
+pub mod format;
+pub mod fstab;
+pub mod gpt;
+pub mod initramfs;
+pub mod install;
+pub mod locale;
+pub mod mount;
+pub mod network;
+pub mod pacstrap;
+pub mod partition;
+pub mod plan;
+pub mod probe;
+pub mod raid;
+pub mod review;
+pub mod safety;
+#[cfg(test)]
+mod testing;
+pub mod ui;
+pub mod users;
+pub mod wizard;
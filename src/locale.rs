@@ -0,0 +1,410 @@
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{target_path, Cmd, CommandRunner},
+    error::{InstallerError, Result},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LocaleSettings {
+    // Locales to generate, the first one becomes LANG
+    pub locales: Vec<String>,
+    // Console keymap
+    pub keymap: String,
+    // Zone name below /usr/share/zoneinfo, e.g. Europe/Berlin
+    pub timezone: String,
+}
+
+impl Default for LocaleSettings {
+    fn default() -> Self {
+        LocaleSettings {
+            locales: vec!["en_US.UTF-8".to_string()],
+            keymap: "us".to_string(),
+            timezone: "UTC".to_string(),
+        }
+    }
+}
+
+impl LocaleSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.locales.is_empty() {
+            return Err(InstallerError::PreconditionFailed(
+                "At least one locale is needed".to_string(),
+            ));
+        }
+        if self.timezone.is_empty()
+            || self.timezone.starts_with('/')
+            || self.timezone.contains("..")
+        {
+            return Err(InstallerError::PreconditionFailed(format!(
+                "Invalid timezone \"{}\"",
+                self.timezone
+            )));
+        }
+        Ok(())
+    }
+
+    // locale.gen line for `locale`, e.g. "en_US.UTF-8 UTF-8". The charset comes
+    // from `supported` when listed there, otherwise from the name
+    fn locale_gen_line(locale: &str, supported: &[(String, String)]) -> String {
+        if let Some((_, charset)) = supported.iter().find(|(name, _)| name == locale) {
+            return format!("{} {}", locale, charset);
+        }
+        match locale.split_once('.') {
+            // Drop any modifier, be_BY.UTF-8@latin is generated as UTF-8
+            Some((_, rest)) => format!("{} {}", locale, rest.split('@').next().unwrap_or(rest)),
+            None => format!("{} ISO-8859-1", locale),
+        }
+    }
+}
+
+/// Where the locales, keymaps and timezones on offer are read from. `root` is
+/// `/` on the live system and can point at a fixture tree instead.
+#[derive(Debug, Clone)]
+pub struct LocaleSources {
+    root: PathBuf,
+}
+
+impl LocaleSources {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocaleSources { root: root.into() }
+    }
+
+    /// Every locale glibc can generate with its charset, from usr/share/i18n/SUPPORTED.
+    pub fn supported(&self) -> Result<Vec<(String, String)>> {
+        let text = fs::read_to_string(self.root.join("usr/share/i18n/SUPPORTED"))?;
+        Ok(parse_supported(&text))
+    }
+
+    pub fn locales(&self) -> Result<Vec<String>> {
+        Ok(self
+            .supported()?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
+
+    /// Console keymap names, found as *.map.gz files below usr/share/kbd/keymaps.
+    pub fn keymaps(&self) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        walk(&self.root.join("usr/share/kbd/keymaps"), &mut files)?;
+
+        let mut keymaps: Vec<String> = files
+            .iter()
+            .filter_map(|path| path.file_name()?.to_str())
+            .filter_map(|name| {
+                name.strip_suffix(".map.gz")
+                    .or_else(|| name.strip_suffix(".map"))
+            })
+            .map(str::to_string)
+            .collect();
+        keymaps.sort();
+        keymaps.dedup();
+        Ok(keymaps)
+    }
+
+    /// Zone names like Europe/Berlin, every TZif file below usr/share/zoneinfo.
+    pub fn timezones(&self) -> Result<Vec<String>> {
+        let zoneinfo = self.root.join("usr/share/zoneinfo");
+        let mut files = Vec::new();
+        walk(&zoneinfo, &mut files)?;
+
+        let mut zones: Vec<String> = files
+            .iter()
+            .filter(|path| is_tzif(path))
+            .filter_map(|path| path.strip_prefix(&zoneinfo).ok())
+            .map(|zone| zone.to_string_lossy().into_owned())
+            // posix/ and right/ duplicate every zone with different leap second handling
+            .filter(|zone| !(zone.starts_with("posix/") || zone.starts_with("right/")))
+            .collect();
+        zones.sort();
+        Ok(zones)
+    }
+
+    /// Check `settings` only names locales, a keymap and a timezone offered
+    /// here. A list that cannot be read is not checked.
+    pub fn validate(&self, settings: &LocaleSettings) -> Result<()> {
+        let unknown = |what: &str, value: &str| {
+            Err(InstallerError::PreconditionFailed(format!(
+                "Unknown {} \"{}\"",
+                what, value
+            )))
+        };
+        if let Ok(locales) = self.locales() {
+            if let Some(locale) = settings.locales.iter().find(|l| !locales.contains(l)) {
+                return unknown("locale", locale);
+            }
+        }
+        if let Ok(keymaps) = self.keymaps() {
+            if !keymaps.contains(&settings.keymap) {
+                return unknown("keymap", &settings.keymap);
+            }
+        }
+        if let Ok(timezones) = self.timezones() {
+            if !timezones.contains(&settings.timezone) {
+                return unknown("timezone", &settings.timezone);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Parse the SUPPORTED list. Lines are "name charset", older glibc versions
+// write "name/charset \" below a SUPPORTED-LOCALES= header
+fn parse_supported(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .map(|line| line.trim().trim_end_matches('\\').trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.ends_with('='))
+        .filter_map(|line| {
+            let (name, charset) = line
+                .split_once('/')
+                .or_else(|| line.split_once(char::is_whitespace))?;
+            Some((name.trim().to_string(), charset.trim().to_string()))
+        })
+        .collect()
+}
+
+// Collect every regular file below `dir`
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        // Follow symlinks, zoneinfo links aliases to their canonical zone
+        match fs::metadata(&path) {
+            Ok(meta) if meta.is_dir() => walk(&path, files)?,
+            Ok(meta) if meta.is_file() => files.push(path),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Compiled zone files start with the TZif magic, which skips zone.tab and friends
+fn is_tzif(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && &magic == b"TZif"
+}
+
+/// Write locale, console keymap and timezone configuration into the target.
+pub fn configure(
+    runner: &dyn CommandRunner,
+    target: &Path,
+    settings: &LocaleSettings,
+) -> Result<()> {
+    settings.validate()?;
+
+    // The target's own list knows the right charset, it is missing in dry-run
+    let supported = LocaleSources::new(target).supported().unwrap_or_default();
+    let locale_gen: String = settings
+        .locales
+        .iter()
+        .map(|locale| LocaleSettings::locale_gen_line(locale, &supported) + "\n")
+        .collect();
+    runner.write_file(&target_path(target, "/etc/locale.gen"), &locale_gen)?;
+    runner.run_checked(&Cmd::chroot(target, "locale-gen"))?;
+
+    runner.write_file(
+        &target_path(target, "/etc/locale.conf"),
+        &format!("LANG={}\n", settings.locales[0]),
+    )?;
+    runner.write_file(
+        &target_path(target, "/etc/vconsole.conf"),
+        &format!("KEYMAP={}\n", settings.keymap),
+    )?;
+
+    let zoneinfo = format!("/usr/share/zoneinfo/{}", settings.timezone);
+    runner.run_checked(&Cmd::chroot(target, "ln").args(["-sf", &zoneinfo, "/etc/localtime"]))?;
+    runner.run_checked(&Cmd::chroot(target, "hwclock").arg("--systohc"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{command::MockRunner, testing::TempDir};
+
+    // Just enough of /usr/share for every list
+    fn fixture() -> TempDir {
+        let root = TempDir::new();
+        root.write(
+            "usr/share/i18n/SUPPORTED",
+            "en_US.UTF-8 UTF-8\nen_US ISO-8859-1\nde_DE.UTF-8 UTF-8\nbe_BY@latin UTF-8\n",
+        );
+        for keymap in [
+            "i386/qwerty/us.map.gz",
+            "i386/qwertz/de-latin1.map.gz",
+            "i386/qwertz/de-latin1-nodeadkeys.map.gz",
+            "i386/include/linux-keys-bare.inc",
+            "sun/sunkeymap.map",
+            "mac/all/mac-us.map.gz",
+        ] {
+            root.write(&format!("usr/share/kbd/keymaps/{}", keymap), "");
+        }
+        for zone in [
+            "UTC",
+            "Europe/Berlin",
+            "America/Argentina/Salta",
+            "posix/Europe/Berlin",
+            "right/UTC",
+        ] {
+            root.write(&format!("usr/share/zoneinfo/{}", zone), "TZif2");
+        }
+        root.write(
+            "usr/share/zoneinfo/zone.tab",
+            "DE\t+5230+01322\tEurope/Berlin\n",
+        );
+        std::os::unix::fs::symlink(
+            root.path().join("usr/share/zoneinfo/Europe/Berlin"),
+            root.path().join("usr/share/zoneinfo/Europe/Busingen"),
+        )
+        .unwrap();
+        root
+    }
+
+    fn settings(locale: &str, keymap: &str, timezone: &str) -> LocaleSettings {
+        LocaleSettings {
+            locales: vec![locale.to_string()],
+            keymap: keymap.to_string(),
+            timezone: timezone.to_string(),
+        }
+    }
+
+    #[test]
+    fn sources_list_what_the_fixture_offers() {
+        let root = fixture();
+        let sources = LocaleSources::new(root.path());
+        assert_eq!(
+            sources.locales().unwrap(),
+            ["en_US.UTF-8", "en_US", "de_DE.UTF-8", "be_BY@latin"]
+        );
+        assert_eq!(
+            sources.keymaps().unwrap(),
+            [
+                "de-latin1",
+                "de-latin1-nodeadkeys",
+                "mac-us",
+                "sunkeymap",
+                "us"
+            ]
+        );
+        assert_eq!(
+            sources.timezones().unwrap(),
+            [
+                "America/Argentina/Salta",
+                "Europe/Berlin",
+                "Europe/Busingen",
+                "UTC"
+            ]
+        );
+    }
+
+    #[test]
+    fn old_supported_lists_are_understood() {
+        let text = "SUPPORTED-LOCALES=\\\nen_US.UTF-8/UTF-8 \\\nen_US/ISO-8859-1 \\\n";
+        assert_eq!(
+            parse_supported(text),
+            [
+                ("en_US.UTF-8".to_string(), "UTF-8".to_string()),
+                ("en_US".to_string(), "ISO-8859-1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_unknown_values() {
+        let root = fixture();
+        let sources = LocaleSources::new(root.path());
+        sources
+            .validate(&settings("de_DE.UTF-8", "de-latin1", "Europe/Berlin"))
+            .unwrap();
+        sources.validate(&LocaleSettings::default()).unwrap();
+
+        let error = |settings: LocaleSettings| sources.validate(&settings).unwrap_err().to_string();
+        assert_eq!(
+            error(settings("de_DE", "us", "UTC")),
+            "Unknown locale \"de_DE\""
+        );
+        assert_eq!(
+            error(settings("en_US", "dvorak", "UTC")),
+            "Unknown keymap \"dvorak\""
+        );
+        assert_eq!(
+            error(settings("en_US", "us", "Mars/Olympus")),
+            "Unknown timezone \"Mars/Olympus\""
+        );
+        assert_eq!(
+            error(settings("en_US", "us", "posix/Europe/Berlin")),
+            "Unknown timezone \"posix/Europe/Berlin\""
+        );
+        assert_eq!(
+            error(settings("en_US", "us", "zone.tab")),
+            "Unknown timezone \"zone.tab\""
+        );
+
+        // Without the lists there is nothing to check against
+        LocaleSources::new(root.path().join("missing"))
+            .validate(&settings("xx_XX", "dvorak", "Mars/Olympus"))
+            .unwrap();
+    }
+
+    #[test]
+    fn settings_are_checked_for_shape() {
+        assert!(settings("en_US.UTF-8", "us", "Europe/Berlin")
+            .validate()
+            .is_ok());
+        let no_locales = LocaleSettings {
+            locales: Vec::new(),
+            ..LocaleSettings::default()
+        };
+        assert!(no_locales.validate().is_err());
+        for timezone in ["", "/etc/passwd", "../../etc/passwd"] {
+            assert!(settings("en_US.UTF-8", "us", timezone).validate().is_err());
+        }
+    }
+
+    #[test]
+    fn configure_writes_the_target_files() {
+        let root = fixture();
+        let runner = MockRunner::new();
+        let settings = LocaleSettings {
+            locales: vec![
+                "de_DE.UTF-8".to_string(),
+                "en_US".to_string(),
+                "be_BY.UTF-8@latin".to_string(),
+            ],
+            keymap: "de-latin1".to_string(),
+            timezone: "Europe/Berlin".to_string(),
+        };
+        configure(&runner, root.path(), &settings).unwrap();
+
+        let files = runner.written_files();
+        let target = |path: &str| target_path(root.path(), path);
+        assert_eq!(
+            files,
+            [
+                (
+                    target("/etc/locale.gen"),
+                    "de_DE.UTF-8 UTF-8\nen_US ISO-8859-1\nbe_BY.UTF-8@latin UTF-8\n".to_string()
+                ),
+                (target("/etc/locale.conf"), "LANG=de_DE.UTF-8\n".to_string()),
+                (
+                    target("/etc/vconsole.conf"),
+                    "KEYMAP=de-latin1\n".to_string()
+                ),
+            ]
+        );
+        let lines = runner.command_lines();
+        assert!(lines[0].ends_with("locale-gen"));
+        assert!(lines[1].ends_with("ln -sf /usr/share/zoneinfo/Europe/Berlin /etc/localtime"));
+        assert!(lines[2].ends_with("hwclock --systohc"));
+    }
+}
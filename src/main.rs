@@ -1,180 +1,212 @@
-use std::{io, thread, time::Duration};
+use std::{env, io, panic, path::{Path, PathBuf}, process};
 use crossterm::{
-    event::{self, Event, KeyCode},
+    event::{self, DisableMouseCapture, EnableMouseCapture},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 
+use archinstaller::{
+    command::{CommandRunner, SystemRunner},
+    config::InstallConfig,
+    disk,
+    error::{InstallerError, Result},
+    install::{self, Progress},
+    locale::LocaleSources,
+    plan::DryRunRunner,
+    safety::Safety,
+    ui::{self, select_list::Action, SelectList},
+    wizard::Wizard,
+};
+
 use tui::{
-    backend::{Backend, CrosstermBackend}, layout::{Constraint, Direction, Layout}, widgets::{Block, Borders, List, ListItem}, Terminal
+    backend::{Backend, CrosstermBackend}, layout::{Constraint, Direction, Layout}, Terminal
 };
 
-fn main() -> Result<(), io::Error> {
+#[derive(Debug, Default)]
+struct Args {
+    // Record the install plan instead of touching the system
+    dry_run: bool,
+    // Print the dry-run plan as JSON
+    json: bool,
+    // Install profile for an unattended install
+    config: Option<PathBuf>,
+    // Allow disks that are mounted, swap, LVM or RAID members, or the boot medium
+    expert: bool,
+}
+
+fn parse_args() -> std::result::Result<Args, String> {
+    let mut args = Args::default();
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--dry-run" => args.dry_run = true,
+            "--json" => args.json = true,
+            "--expert" => args.expert = true,
+            "--config" => match argv.next() {
+                Some(path) => args.config = Some(PathBuf::from(path)),
+                None => return Err("--config needs a path".to_string()),
+            },
+            "-h" | "--help" => {
+                println!("Usage: archinstaller [--config profile.toml] [--dry-run [--json]] [--expert]");
+                process::exit(0);
+            }
+            other => return Err(format!("Unknown argument: {}", other)),
+        }
+    }
+    if args.json && !args.dry_run {
+        return Err("--json can only be used together with --dry-run".to_string());
+    }
+    Ok(args)
+}
+
+fn main() -> io::Result<()> {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+    };
+
+    let system = SystemRunner;
+    let dry_run = DryRunRunner::new(&system);
+    let runner: &dyn CommandRunner = if args.dry_run { &dry_run } else { &system };
+
+    let res = match &args.config {
+        Some(path) => run_unattended(runner, path, args.expert),
+        None => run_interactive(runner, args.expert),
+    };
+
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+
+    if args.dry_run {
+        let plan = dry_run.plan();
+        if args.json {
+            println!("{}", plan.to_json());
+        } else {
+            print!("{}", plan);
+        }
+    }
+
+    Ok(())
+}
+
+fn run_interactive(runner: &dyn CommandRunner, expert: bool) -> Result<()> {
+    // Make sure a panic never leaves the terminal in raw mode on the alternate screen
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = restore_terminal();
+        default_hook(info);
+    }));
+
     // Setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
     // Run the app
-    let res = run_app(&mut terminal);
+    let res = run_app(&mut terminal, runner, expert);
 
     // Restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-    )?;
+    restore_terminal()?;
     terminal.show_cursor()?;
 
-    if let Err(err) = res {
-        println!("{:?}", err)
-    }
-
-    Ok(())
+    res
 }
 
-fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> io::Result<()> {
-    let menu_items = vec!["Install Arch Linux", "Exit"];
-    let mut selected_index = 0;
-
-    loop {
-        terminal.draw(|f| {
-            let size = f.size();
-
-            // Create layout
-            let chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints(
-                    [Constraint::Percentage(80), Constraint::Percentage(20)].as_ref(),
-                )
-                .split(size);
-
-            // Create menu items
-            let items: Vec<ListItem> = menu_items
-                .iter()
-                .map(|m| ListItem::new(*m))
-                .collect();
-
-            // Highlight the selected menu item
-            let menu = List::new(items)
-                .block(Block::default().borders(Borders::ALL).title("Main Menu"))
-                .highlight_symbol(">> ");
-
-            // Render menu
-            let mut state = tui::widgets::ListState::default();
-            state.select(Some(selected_index));
-            f.render_stateful_widget(menu, chunks[0], &mut state);
-        })?;
-
-        // Handle user input
-        if let Event::Key(key) = event::read()? {
-            match key.code {
-                KeyCode::Up => {
-                    if selected_index > 0 {
-                        selected_index -= 1;
-                    }
-                }
-                KeyCode::Down => {
-                    if selected_index < menu_items.len() - 1 {
-                        selected_index += 1;
-                    }
-                }
-                KeyCode::Enter => match selected_index {
-                    0 => {
-                        let selected_disk = select_disk(terminal)?;
-                        println!("Selected disk: {}", selected_disk);
-                        // TODO: Proceed with formating
-                    }
-                    2 => break, // Exit
-                    _ => {}
-                },
-                KeyCode::Esc => break,
-                _ => {}
-            }
-        }
+// Prints progress of an unattended install to stderr, keeping stdout for the dry-run plan
+struct ConsoleProgress;
 
-        thread::sleep(Duration::from_millis(100));
+impl Progress for ConsoleProgress {
+    fn stage(&mut self, name: &str) {
+        eprintln!(":: {}", name);
     }
 
-    Ok(())
+    fn log(&mut self, line: &str) {
+        eprintln!("   {}", line);
+    }
 }
 
-fn get_available_disks() -> Vec<String> {
-    let output = std::process::Command::new("lsblk")
-        .arg("-d") // List only top level devices
-        .arg("-n") // Ignore headings
-        .arg("-o") // Output only name and type
-        .arg("NAME")
-        .arg("TYPE")
-        .output()
-        .expect("Failed to get disks");
-
-
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    stdout
-        .lines()
-        .filter_map(|line| {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() == 2 && parts[1] == "disk" {
-                Some(format!("/dev/{}", parts[0])) // Add the dev prefix
+// Install straight from a profile without any interaction
+fn run_unattended(runner: &dyn CommandRunner, path: &Path, expert: bool) -> Result<()> {
+    let live_root = Path::new("/");
+    let config = InstallConfig::load(path)?;
+    config.validate()?;
+    // The wizard only offers what the live system has, a profile may name anything
+    LocaleSources::new(live_root).validate(&config.locale)?;
+
+    let wanted = config.disk.as_deref().ok_or_else(|| {
+        InstallerError::PreconditionFailed("The profile does not name a disk".to_string())
+    })?;
+    // Loop devices are only offered to experts, like disks that are in use
+    let disks = disk::get_available_disks(runner, expert)?;
+    let find = |wanted: &str| {
+        disks.iter().find(|d| d.path == wanted).cloned().ok_or_else(|| {
+            let hint = if wanted.starts_with("/dev/loop") && !expert {
+                ", loop devices need --expert"
             } else {
-                None
-            }
+                ""
+            };
+            InstallerError::PreconditionFailed(format!("Disk {} not found{}", wanted, hint))
         })
-        .collect()
+    };
+    let selected_disk = find(wanted)?;
+    let safety = Safety::probe(live_root)?;
+    safety.check(&selected_disk, expert)?;
+    // Disks for /home or a mirror are erased just the same
+    for other in config.multi_disk.iter().flat_map(|m| m.disks()) {
+        safety.check(&find(other)?, expert)?;
+    }
+
+    install::install(runner, live_root, &selected_disk, &config, &mut ConsoleProgress)
 }
 
-fn select_disk<B: Backend>(terminal: &mut Terminal<B>) -> io::Result<String> {
-    let disks = get_available_disks();
-    if disks.is_empty() {
-        return Err(io::Error::new(io::ErrorKind::NotFound, "No disks found"));
-    }
+fn restore_terminal() -> io::Result<()> {
+    disable_raw_mode()?;
+    execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture, crossterm::cursor::Show)
+}
 
-    let mut selected_index = 0;
+fn run_app<B: Backend>(terminal: &mut Terminal<B>, runner: &dyn CommandRunner, expert: bool) -> Result<()> {
+    let mut menu = SelectList::new("Main Menu", vec!["Install Arch Linux", "Exit"], |m| m.to_string());
+    let live_root = Path::new("/");
+    let mut wizard = Wizard::new(runner).with_root(live_root).allow_unsafe(expert);
 
     loop {
         terminal.draw(|f| {
-            let size = f.size();
-
-            // Create Disk menu
-            let items: Vec<ListItem> = disks
-                .iter()
-                .map(|disk| ListItem::new(disk.as_str()))
-                .collect();
-
-            let menu = List::new(items)
-                .block(Block::default().borders(Borders::ALL).title("Select a disk"))
-                .highlight_symbol(">> ");
+            // Create layout
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(
+                    [Constraint::Percentage(80), Constraint::Percentage(20)].as_ref(),
+                )
+                .split(f.size());
 
-            let mut state = tui::widgets::ListState::default();
-            state.select(Some(selected_index));
-            f.render_stateful_widget(menu, size, &mut state);
+            menu.render(f, chunks[0]);
         })?;
 
-        // Handle input
-        if let Event::Key(key) = event::read()? {
-            match key.code {
-                KeyCode::Up => {
-                    if selected_index > 0 {
-                        selected_index -= 1;
-                    }
-                }
-                KeyCode::Down => {
-                    if selected_index < disks.len() - 1 {
-                        selected_index += 1;
+        // Handle user input
+        match menu.handle_event(&event::read()?) {
+            Some(Action::Confirm) if menu.selected_index() == Some(0) => {
+                // Backing out of the first step returns to this menu
+                if let Some((selected_disk, config)) = wizard.run(terminal)? {
+                    let mut progress = ui::InstallScreen::new(terminal);
+                    match install::install(runner, live_root, &selected_disk, &config, &mut progress) {
+                        Ok(()) => break,
+                        // The wizard resumes on its summary with every answer intact
+                        Err(err) => ui::show_error(terminal, &err)?,
                     }
                 }
-                KeyCode::Enter => {
-                    return Ok(disks[selected_index].clone());
-                }
-                KeyCode::Esc => {
-                    return Err(io::Error::new(io::ErrorKind::Interrupted, "Disk selection canceled"));
-                }
-                _ => {}
             }
+            // Exit, or Esc
+            Some(_) => break,
+            None => {}
         }
     }
+
+    Ok(())
 }
@@ -0,0 +1,266 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    command::{Cmd, CommandRunner, Mount},
+    crypt,
+    error::Result,
+    raid,
+};
+
+// Something that has to be undone when the install stops
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Teardown {
+    Unmount(PathBuf),
+    CloseLuks(String),
+    StopArray(String),
+}
+
+/// Tracks every mount (and opened LUKS container or assembled array) made
+/// for the target and releases them in reverse order. Dropping the manager
+/// without calling `release` unwinds everything, so errors, cancellation and
+/// panics never leave the target busy.
+pub struct MountManager<'a> {
+    runner: &'a dyn CommandRunner,
+    stack: Vec<Teardown>,
+    // Mounts still held, in order, for building fstab before the release
+    mounts: Vec<Mount>,
+}
+
+impl<'a> MountManager<'a> {
+    pub fn new(runner: &'a dyn CommandRunner) -> Self {
+        MountManager {
+            runner,
+            stack: Vec::new(),
+            mounts: Vec::new(),
+        }
+    }
+
+    pub fn mount(&mut self, mount: &Mount) -> Result<()> {
+        self.runner.mount(mount)?;
+        self.stack.push(Teardown::Unmount(mount.target.clone()));
+        self.mounts.push(mount.clone());
+        Ok(())
+    }
+
+    // Unmount `target` early, e.g. a scratch mount
+    pub fn unmount(&mut self, target: &Path) -> Result<()> {
+        let teardown = Teardown::Unmount(target.to_path_buf());
+        if let Some(pos) = self.stack.iter().rposition(|t| *t == teardown) {
+            self.stack.remove(pos);
+            self.mounts.retain(|m| m.target != target);
+            undo(self.runner, &teardown)?;
+        }
+        Ok(())
+    }
+
+    // Close the LUKS container `name` on teardown
+    pub fn track_luks(&mut self, name: &str) {
+        self.stack.push(Teardown::CloseLuks(name.to_string()));
+    }
+
+    // Stop the mdadm array `device` on teardown
+    pub fn track_array(&mut self, device: &str) {
+        self.stack.push(Teardown::StopArray(device.to_string()));
+    }
+
+    /// Mounts currently held, in the order they were made.
+    pub fn mounts(&self) -> &[Mount] {
+        &self.mounts
+    }
+
+    /// Undo everything in reverse order. Keeps going past failures and
+    /// reports the first one.
+    pub fn release(&mut self) -> Result<()> {
+        let mut first_error = None;
+        while let Some(teardown) = self.stack.pop() {
+            if let Err(err) = undo(self.runner, &teardown) {
+                first_error.get_or_insert(err);
+            }
+        }
+        self.mounts.clear();
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for MountManager<'_> {
+    fn drop(&mut self) {
+        // Nothing sensible can be done with a failure here
+        let _ = self.release();
+    }
+}
+
+fn undo(runner: &dyn CommandRunner, teardown: &Teardown) -> Result<()> {
+    match teardown {
+        Teardown::Unmount(target) => {
+            runner.run_checked(&Cmd::new("umount").arg(target.to_string_lossy()))?;
+        }
+        Teardown::CloseLuks(name) => crypt::close_luks(runner, name)?,
+        Teardown::StopArray(device) => raid::stop_array(runner, device)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::*;
+    use crate::{
+        command::{CommandOutput, MockRunner},
+        error::InstallerError,
+    };
+
+    // Root, then /boot on it, then a LUKS container and an array tracked after them
+    fn hold(runner: &MockRunner) -> MountManager<'_> {
+        let mut mounts = MountManager::new(runner);
+        mounts
+            .mount(&Mount::new("/dev/sda2", "/mnt").fstype("ext4"))
+            .unwrap();
+        mounts
+            .mount(&Mount::new("/dev/sda1", "/mnt/boot").fstype("vfat"))
+            .unwrap();
+        mounts.track_luks("cryptroot");
+        mounts.track_array("/dev/md/root");
+        mounts
+    }
+
+    // What ran after the mounts were made
+    fn teardown(runner: &MockRunner) -> Vec<String> {
+        runner
+            .command_lines()
+            .into_iter()
+            .filter(|line| !line.starts_with("mkdir") && !line.starts_with("mount"))
+            .collect()
+    }
+
+    const REVERSED: [&str; 4] = [
+        "mdadm --stop /dev/md/root",
+        "cryptsetup close cryptroot",
+        "umount /mnt/boot",
+        "umount /mnt",
+    ];
+
+    #[test]
+    fn release_undoes_everything_in_reverse() {
+        let runner = MockRunner::new();
+        let mut mounts = hold(&runner);
+        assert_eq!(mounts.mounts().len(), 2);
+        assert_eq!(mounts.mounts()[1].target, Path::new("/mnt/boot"));
+
+        mounts.release().unwrap();
+        assert_eq!(teardown(&runner), REVERSED);
+        assert!(mounts.mounts().is_empty());
+    }
+
+    #[test]
+    fn containers_are_closed_after_what_is_mounted_on_them() {
+        let runner = MockRunner::new();
+        let mut mounts = MountManager::new(&runner);
+        mounts.track_array("/dev/md/root");
+        mounts.track_luks("cryptroot");
+        mounts
+            .mount(&Mount::new("/dev/mapper/cryptroot", "/mnt"))
+            .unwrap();
+        mounts.mount(&Mount::new("/dev/sda1", "/mnt/boot")).unwrap();
+        mounts.release().unwrap();
+        assert_eq!(
+            teardown(&runner),
+            [
+                "umount /mnt/boot",
+                "umount /mnt",
+                "cryptsetup close cryptroot",
+                "mdadm --stop /dev/md/root",
+            ]
+        );
+    }
+
+    #[test]
+    fn release_goes_on_past_failures_and_reports_the_first() {
+        let runner = MockRunner::new();
+        runner.on_args(
+            "umount",
+            &["/mnt/boot"],
+            CommandOutput::failure(32, "/mnt/boot: target is busy"),
+        );
+        runner.on_args(
+            "umount",
+            &["/mnt"],
+            CommandOutput::failure(32, "/mnt: target is busy"),
+        );
+        let mut mounts = hold(&runner);
+
+        match mounts.release() {
+            Err(InstallerError::CommandFailed {
+                program, stderr, ..
+            }) => {
+                assert_eq!(program, "umount");
+                assert_eq!(stderr, "/mnt/boot: target is busy");
+            }
+            other => panic!("expected the first umount failure, got {:?}", other),
+        }
+        assert_eq!(teardown(&runner), REVERSED);
+    }
+
+    #[test]
+    fn a_second_release_does_nothing() {
+        let runner = MockRunner::new();
+        let mut mounts = hold(&runner);
+        mounts.release().unwrap();
+        mounts.release().unwrap();
+        drop(mounts);
+        assert_eq!(teardown(&runner), REVERSED);
+    }
+
+    #[test]
+    fn early_unmounts_are_not_repeated() {
+        let runner = MockRunner::new();
+        let mut mounts = hold(&runner);
+        mounts.unmount(Path::new("/mnt/boot")).unwrap();
+        assert_eq!(mounts.mounts().len(), 1);
+        // Not held, so nothing to do
+        mounts.unmount(Path::new("/mnt/home")).unwrap();
+        mounts.release().unwrap();
+        assert_eq!(
+            teardown(&runner),
+            [
+                "umount /mnt/boot",
+                "mdadm --stop /dev/md/root",
+                "cryptsetup close cryptroot",
+                "umount /mnt",
+            ]
+        );
+    }
+
+    // An install step that fails half way, leaving its mounts to the drop
+    fn failing_step(runner: &MockRunner) -> crate::error::Result<()> {
+        let mut mounts = hold(runner);
+        mounts.mount(&Mount::new("/dev/sda3", "/mnt/home"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn dropping_unwinds_after_an_error() {
+        let runner = MockRunner::new();
+        runner.on_args(
+            "mount",
+            &["/dev/sda3", "/mnt/home"],
+            CommandOutput::failure(32, "wrong fs type"),
+        );
+        assert!(failing_step(&runner).is_err());
+        assert_eq!(teardown(&runner), REVERSED);
+    }
+
+    #[test]
+    fn dropping_unwinds_during_a_panic() {
+        let runner = MockRunner::new();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _mounts = hold(&runner);
+            panic!("install step panicked");
+        }));
+        assert!(result.is_err());
+        assert_eq!(teardown(&runner), REVERSED);
+    }
+}
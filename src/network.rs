@@ -0,0 +1,265 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{target_path, Cmd, CommandRunner},
+    config::InstallConfig,
+    error::{InstallerError, Result},
+};
+
+/// What manages the network on the installed system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NetworkStack {
+    NetworkManager,
+    // systemd-networkd with systemd-resolved
+    Networkd,
+    // iwd's built-in network configuration with systemd-resolved
+    Iwd,
+}
+
+impl NetworkStack {
+    pub const ALL: [NetworkStack; 3] = [
+        NetworkStack::NetworkManager,
+        NetworkStack::Networkd,
+        NetworkStack::Iwd,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NetworkStack::NetworkManager => "NetworkManager",
+            NetworkStack::Networkd => "systemd-networkd + systemd-resolved",
+            NetworkStack::Iwd => "iwd",
+        }
+    }
+
+    pub fn packages(self) -> &'static [&'static str] {
+        match self {
+            NetworkStack::NetworkManager => &["networkmanager"],
+            // Both ship with systemd
+            NetworkStack::Networkd => &[],
+            NetworkStack::Iwd => &["iwd"],
+        }
+    }
+
+    pub fn units(self) -> &'static [&'static str] {
+        match self {
+            NetworkStack::NetworkManager => &["NetworkManager.service"],
+            NetworkStack::Networkd => &["systemd-networkd.service", "systemd-resolved.service"],
+            NetworkStack::Iwd => &["iwd.service", "systemd-resolved.service"],
+        }
+    }
+
+    // Directories holding connection profiles, the same path on the live ISO and the target
+    fn profile_dirs(self) -> &'static [&'static str] {
+        match self {
+            NetworkStack::NetworkManager => &["/etc/NetworkManager/system-connections"],
+            NetworkStack::Networkd => &["/etc/systemd/network"],
+            NetworkStack::Iwd => &["/var/lib/iwd"],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkSettings {
+    // Only the hostname is configured when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack: Option<NetworkStack>,
+    // Bring the live system's wired and wireless profiles along
+    pub copy_live_profiles: bool,
+}
+
+/// Check `hostname` is a valid RFC 1123 host name: dot separated labels of
+/// letters, digits and `-` that neither start nor end with `-`, each at most
+/// 63 characters and 253 in total.
+pub fn validate_hostname(hostname: &str) -> Result<()> {
+    let fail = |reason: &str| {
+        Err(InstallerError::PreconditionFailed(format!(
+            "Invalid hostname \"{}\": {}",
+            hostname, reason
+        )))
+    };
+
+    if hostname.is_empty() {
+        return fail("it cannot be empty");
+    }
+    if hostname.len() > 253 {
+        return fail("it is longer than 253 characters");
+    }
+    for label in hostname.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return fail("every part between dots needs 1 to 63 characters");
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return fail("only letters, digits, - and . are allowed");
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return fail("parts cannot start or end with -");
+        }
+    }
+    Ok(())
+}
+
+// /etc/hosts resolving the machine's own name, short form included for FQDNs
+pub fn hosts(hostname: &str) -> String {
+    let short = hostname.split('.').next().unwrap_or(hostname);
+    let names = if short == hostname {
+        hostname.to_string()
+    } else {
+        format!("{} {}", hostname, short)
+    };
+    format!(
+        "127.0.0.1\tlocalhost\n::1\t\tlocalhost\n127.0.1.1\t{}\n",
+        names
+    )
+}
+
+/// Profile directories of `stack` that exist on the live system below `live_root`.
+pub fn live_profiles(live_root: &Path, stack: NetworkStack) -> Vec<PathBuf> {
+    stack
+        .profile_dirs()
+        .iter()
+        .map(|dir| target_path(live_root, dir))
+        .filter(|dir| dir.is_dir())
+        .collect()
+}
+
+// Wired interfaces get DHCP, the same default archiso uses
+const NETWORKD_WIRED: &str = "[Match]\nName=en*\nName=eth*\n\n[Network]\nDHCP=yes\n";
+
+const IWD_MAIN: &str =
+    "[General]\nEnableNetworkConfiguration=true\n\n[Network]\nNameResolvingService=systemd\n";
+
+/// Write /etc/hostname and /etc/hosts into the target and set up the chosen
+/// network stack. Profiles are copied from the live system mounted at `live_root`.
+pub fn configure(
+    runner: &dyn CommandRunner,
+    target: &Path,
+    live_root: &Path,
+    config: &InstallConfig,
+) -> Result<()> {
+    validate_hostname(&config.hostname)?;
+    runner.write_file(
+        &target_path(target, "/etc/hostname"),
+        &format!("{}\n", config.hostname),
+    )?;
+    runner.write_file(&target_path(target, "/etc/hosts"), &hosts(&config.hostname))?;
+
+    let Some(stack) = config.network.stack else {
+        return Ok(());
+    };
+
+    match stack {
+        NetworkStack::NetworkManager => {}
+        NetworkStack::Networkd => runner.write_file(
+            &target_path(target, "/etc/systemd/network/20-wired.network"),
+            NETWORKD_WIRED,
+        )?,
+        NetworkStack::Iwd => {
+            runner.write_file(&target_path(target, "/etc/iwd/main.conf"), IWD_MAIN)?
+        }
+    }
+
+    if stack != NetworkStack::NetworkManager {
+        // arch-chroot bind mounts resolv.conf, so link it from outside the chroot
+        let resolv = target_path(target, "/etc/resolv.conf");
+        runner.run_checked(&Cmd::new("ln").args([
+            "-sf",
+            "../run/systemd/resolve/stub-resolv.conf",
+            &resolv.to_string_lossy(),
+        ]))?;
+    }
+
+    if config.network.copy_live_profiles {
+        for source in live_profiles(live_root, stack) {
+            let relative = source.strip_prefix(live_root).unwrap_or(&source);
+            let destination = target.join(relative);
+            runner.run_checked(
+                &Cmd::new("mkdir")
+                    .arg("-p")
+                    .arg(destination.to_string_lossy()),
+            )?;
+            // -a keeps the root-only permissions on files holding secrets
+            runner.run_checked(&Cmd::new("cp").args([
+                "-a".to_string(),
+                format!("{}/.", source.display()),
+                destination.to_string_lossy().into_owned(),
+            ]))?;
+        }
+    }
+
+    runner.run_checked(
+        &Cmd::chroot(target, "systemctl")
+            .arg("enable")
+            .args(stack.units().iter().copied()),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_hostname_follows_rfc_1123() {
+        let longest_label = "a".repeat(63);
+        // Three full labels and one of 61 make 253 with the dots
+        let longest = format!("{0}.{0}.{0}.{1}", "b".repeat(63), "b".repeat(61));
+        for hostname in [
+            "archlinux",
+            "a",
+            "web-01",
+            "4chan",
+            "node1.example.org",
+            "UPPER.case",
+            longest_label.as_str(),
+            longest.as_str(),
+        ] {
+            assert!(validate_hostname(hostname).is_ok(), "{}", hostname);
+        }
+
+        let too_long_label = format!("{}a", longest_label);
+        let too_long = format!("{}b", longest);
+        for (hostname, reason) in [
+            ("", "it cannot be empty"),
+            (too_long.as_str(), "it is longer than 253 characters"),
+            (
+                too_long_label.as_str(),
+                "every part between dots needs 1 to 63 characters",
+            ),
+            (
+                "node1..example",
+                "every part between dots needs 1 to 63 characters",
+            ),
+            (
+                ".example",
+                "every part between dots needs 1 to 63 characters",
+            ),
+            (
+                "example.",
+                "every part between dots needs 1 to 63 characters",
+            ),
+            ("my_host", "only letters, digits, - and . are allowed"),
+            ("my host", "only letters, digits, - and . are allowed"),
+            ("höst", "only letters, digits, - and . are allowed"),
+            ("-archlinux", "parts cannot start or end with -"),
+            ("node1.example-", "parts cannot start or end with -"),
+        ] {
+            assert_eq!(
+                validate_hostname(hostname).unwrap_err().to_string(),
+                format!("Invalid hostname \"{}\": {}", hostname, reason)
+            );
+        }
+    }
+
+    #[test]
+    fn hosts_names_the_short_form_too() {
+        assert_eq!(
+            hosts("archlinux"),
+            "127.0.0.1\tlocalhost\n::1\t\tlocalhost\n127.0.1.1\tarchlinux\n"
+        );
+        assert!(hosts("node1.example.org").ends_with("127.0.1.1\tnode1.example.org node1\n"));
+    }
+}
@@ -0,0 +1,265 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{Cmd, CommandRunner},
+    config::InstallConfig,
+    error::{InstallerError, Result},
+    format::FilesystemKind,
+    install::Progress,
+    users,
+};
+
+/// Packages installed into the target.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PackageSet {
+    pub base: Vec<String>,
+    pub extra: Vec<String>,
+    // Alternative pacman.conf, e.g. one pointing at a local file:// repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pacman_config: Option<PathBuf>,
+}
+
+impl Default for PackageSet {
+    fn default() -> Self {
+        PackageSet {
+            base: ["base", "linux", "linux-firmware"]
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+            extra: Vec::new(),
+            pacman_config: None,
+        }
+    }
+}
+
+/// Every package the install needs: the configured set plus the tools the
+/// chosen filesystems, user accounts and network stack rely on, without duplicates.
+pub fn packages(config: &InstallConfig) -> Vec<String> {
+    let mut packages: Vec<String> = Vec::new();
+    let mut add = |package: &str| {
+        if !packages.iter().any(|p| p == package) {
+            packages.push(package.to_string());
+        }
+    };
+
+    for package in config.packages.base.iter().chain(&config.packages.extra) {
+        add(package);
+    }
+    for package in users::packages(config) {
+        add(package);
+    }
+    if let Some(stack) = config.network.stack {
+        for package in stack.packages() {
+            add(package);
+        }
+    }
+    if config.uses_mdadm() {
+        add("mdadm");
+    }
+    for kind in config.filesystems() {
+        match kind {
+            FilesystemKind::Btrfs => add("btrfs-progs"),
+            FilesystemKind::Xfs => add("xfsprogs"),
+            FilesystemKind::F2fs => add("f2fs-tools"),
+            FilesystemKind::Vfat => add("dosfstools"),
+            FilesystemKind::Ext4 => add("e2fsprogs"),
+            FilesystemKind::Swap => {}
+        }
+    }
+    packages
+}
+
+/// Install the package set into `target`, streaming pacstrap's output to `progress`.
+pub fn pacstrap(
+    runner: &dyn CommandRunner,
+    target: &Path,
+    config: &InstallConfig,
+    progress: &mut dyn Progress,
+) -> Result<()> {
+    let packages = packages(config);
+    if packages.is_empty() {
+        return Err(InstallerError::PreconditionFailed(
+            "No packages selected for installation".to_string(),
+        ));
+    }
+
+    // -K sets up a fresh pacman keyring inside the target
+    let mut cmd = Cmd::new("pacstrap").arg("-K");
+    if let Some(pacman_config) = &config.packages.pacman_config {
+        cmd = cmd.arg("-C").arg(pacman_config.to_string_lossy());
+    }
+    let cmd = cmd.arg(target.to_string_lossy()).args(packages);
+
+    runner.run_streaming(&cmd, &mut |line| {
+        progress.log(line);
+        !progress.cancelled()
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::{
+        command::{CommandOutput, MockRunner, SystemRunner},
+        network::NetworkStack,
+        partition::PartitionRole,
+        raid::{MirrorKind, MultiDisk},
+        testing::{self, TempDir},
+        users::User,
+    };
+
+    // Keeps what pacstrap printed and cancels after `cancel_after` lines
+    #[derive(Default)]
+    struct Log {
+        lines: Vec<String>,
+        cancel_after: Option<usize>,
+    }
+
+    impl Progress for Log {
+        fn log(&mut self, line: &str) {
+            self.lines.push(line.to_string());
+        }
+
+        fn cancelled(&mut self) -> bool {
+            self.cancel_after.is_some_and(|n| self.lines.len() >= n)
+        }
+    }
+
+    #[test]
+    fn packages_are_listed_once_in_order() {
+        let mut config = InstallConfig::default();
+        config.packages.extra = vec!["vim".to_string(), "linux".to_string(), "sudo".to_string()];
+        config.sudo_wheel = true;
+        config.users.push(User {
+            name: "alice".to_string(),
+            groups: Vec::new(),
+            shell: Some("/usr/bin/zsh".to_string()),
+            password_hash: None,
+        });
+        config.network.stack = Some(NetworkStack::NetworkManager);
+        config.multi_disk = Some(MultiDisk::Mirror {
+            disks: vec!["/dev/sdb".to_string()],
+            raid: MirrorKind::Mdadm,
+        });
+        config
+            .partitions
+            .find_mut(PartitionRole::Root)
+            .unwrap()
+            .filesystem
+            .kind = FilesystemKind::Btrfs;
+
+        assert_eq!(
+            packages(&config),
+            [
+                "base",
+                "linux",
+                "linux-firmware",
+                "vim",
+                "sudo",
+                "zsh",
+                "networkmanager",
+                "mdadm",
+                "dosfstools",
+                "btrfs-progs",
+            ]
+        );
+    }
+
+    #[test]
+    fn pacstrap_sets_up_the_keyring_and_uses_the_given_pacman_conf() {
+        let runner = MockRunner::new();
+        let mut config = InstallConfig::default();
+        pacstrap(&runner, Path::new("/mnt"), &config, &mut Log::default()).unwrap();
+        config.packages.pacman_config = Some("/root/local repo.conf".into());
+        pacstrap(&runner, Path::new("/mnt"), &config, &mut Log::default()).unwrap();
+        assert_eq!(
+            runner.command_lines(),
+            [
+                "pacstrap -K /mnt base linux linux-firmware dosfstools e2fsprogs",
+                "pacstrap -K -C '/root/local repo.conf' /mnt base linux linux-firmware dosfstools e2fsprogs",
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_to_install_is_refused() {
+        let runner = MockRunner::new();
+        let mut config = InstallConfig::default();
+        config.packages.base.clear();
+        config.partitions.partitions.clear();
+        let result = pacstrap(&runner, Path::new("/mnt"), &config, &mut Log::default());
+        assert!(matches!(result, Err(InstallerError::PreconditionFailed(_))));
+        assert!(runner.invocations().is_empty());
+    }
+
+    #[test]
+    fn output_is_streamed_to_the_progress_log() {
+        let runner = MockRunner::new();
+        runner.on(
+            "pacstrap",
+            CommandOutput::success(":: Synchronizing package databases...\ninstalling base\n"),
+        );
+        let mut log = Log::default();
+        pacstrap(
+            &runner,
+            Path::new("/mnt"),
+            &InstallConfig::default(),
+            &mut log,
+        )
+        .unwrap();
+        assert_eq!(
+            log.lines,
+            [":: Synchronizing package databases...", "installing base"]
+        );
+    }
+
+    #[test]
+    fn cancelling_stops_pacstrap() {
+        let runner = MockRunner::new();
+        runner.on("pacstrap", CommandOutput::success("one\ntwo\nthree\n"));
+        let mut log = Log {
+            cancel_after: Some(2),
+            ..Log::default()
+        };
+        let result = pacstrap(
+            &runner,
+            Path::new("/mnt"),
+            &InstallConfig::default(),
+            &mut log,
+        );
+        assert!(matches!(result, Err(InstallerError::Cancelled)));
+        assert_eq!(log.lines, ["one", "two"]);
+    }
+
+    // Installs `filesystem` from the file:// repository configured in the
+    // pacman.conf named by ARCHINSTALLER_TEST_PACMAN_CONF, so it runs offline
+    #[test]
+    #[ignore = "needs root, pacstrap and a local package repository"]
+    fn pacstrap_installs_from_a_local_repository() {
+        let Some(pacman_config) = env::var_os("ARCHINSTALLER_TEST_PACMAN_CONF") else {
+            eprintln!("skipped, ARCHINSTALLER_TEST_PACMAN_CONF is not set");
+            return;
+        };
+        if !testing::privileged(&["pacstrap"]) {
+            return;
+        }
+
+        let target = TempDir::new();
+        let mut config = InstallConfig::default();
+        config.packages.base = vec!["filesystem".to_string()];
+        config.packages.pacman_config = Some(pacman_config.into());
+        // Only the package set matters here
+        config.partitions.partitions.clear();
+        let mut log = Log::default();
+        pacstrap(&SystemRunner, target.path(), &config, &mut log).unwrap();
+
+        assert!(target.path().join("etc/passwd").is_file());
+        assert!(log.lines.iter().any(|line| line.contains("filesystem")));
+    }
+}
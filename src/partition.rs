@@ -0,0 +1,567 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{Cmd, CommandRunner},
+    disk::BlockDevice,
+    error::{InstallerError, Result},
+    format::{FilesystemKind, FilesystemSpec},
+};
+
+const MIB: u64 = 1024 * 1024;
+
+// Last GPT entry, keeps the regular partitions numbered from 1
+pub const BIOS_BOOT_NUMBER: u32 = 128;
+
+// Linux RAID, set on the members of an mdadm array
+const RAID_TYPE_CODE: &str = "fd00";
+
+// Room sgdisk needs for the protective MBR, both GPT headers and alignment
+const GPT_OVERHEAD_MIB: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionRole {
+    Esp,
+    Swap,
+    Root,
+    Home,
+}
+
+impl PartitionRole {
+    // sgdisk type code
+    pub fn type_code(self) -> &'static str {
+        match self {
+            PartitionRole::Esp => "ef00",
+            PartitionRole::Swap => "8200",
+            PartitionRole::Root => "8304", // Linux x86-64 root, picked up by systemd-gpt-auto-generator
+            PartitionRole::Home => "8302",
+        }
+    }
+
+    // GPT partition name
+    pub fn name(self) -> &'static str {
+        match self {
+            PartitionRole::Esp => "EFI",
+            PartitionRole::Swap => "swap",
+            PartitionRole::Root => "root",
+            PartitionRole::Home => "home",
+        }
+    }
+
+    // Where the partition is mounted in the installed system, swap is not mounted
+    pub fn mountpoint(self) -> Option<&'static str> {
+        match self {
+            PartitionRole::Esp => Some("/boot"),
+            PartitionRole::Swap => None,
+            PartitionRole::Root => Some("/"),
+            PartitionRole::Home => Some("/home"),
+        }
+    }
+
+    // Whether a partition in this role can hold a `kind` filesystem
+    pub fn allows(self, kind: FilesystemKind) -> bool {
+        match self {
+            PartitionRole::Esp => kind == FilesystemKind::Vfat,
+            PartitionRole::Swap => kind == FilesystemKind::Swap,
+            PartitionRole::Root | PartitionRole::Home => {
+                !matches!(kind, FilesystemKind::Vfat | FilesystemKind::Swap)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "PartialPartitionSpec")]
+pub struct PartitionSpec {
+    pub role: PartitionRole,
+    // `None` takes the rest of the disk, only allowed for the last partition
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_mib: Option<u64>,
+    pub filesystem: FilesystemSpec,
+}
+
+// A partition as written in a profile, where the filesystem may be left out
+#[derive(Deserialize)]
+struct PartialPartitionSpec {
+    role: PartitionRole,
+    size_mib: Option<u64>,
+    filesystem: Option<FilesystemSpec>,
+}
+
+impl From<PartialPartitionSpec> for PartitionSpec {
+    fn from(partial: PartialPartitionSpec) -> Self {
+        let spec = PartitionSpec::new(partial.role, partial.size_mib);
+        match partial.filesystem {
+            Some(filesystem) => spec.filesystem(filesystem),
+            None => spec,
+        }
+    }
+}
+
+impl PartitionSpec {
+    pub fn new(role: PartitionRole, size_mib: Option<u64>) -> Self {
+        PartitionSpec {
+            role,
+            size_mib,
+            filesystem: FilesystemSpec::new(FilesystemKind::default_for(role)),
+        }
+    }
+
+    pub fn filesystem(mut self, filesystem: FilesystemSpec) -> Self {
+        self.filesystem = filesystem;
+        self
+    }
+}
+
+/// Declarative GPT layout, partitions are created in order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartitionPlan {
+    #[serde(rename = "layout")]
+    pub partitions: Vec<PartitionSpec>,
+    // Add a BIOS boot partition for GRUB in the gap before the first partition,
+    // decided at install time from the boot mode
+    #[serde(skip)]
+    pub bios_boot: bool,
+    // Give every partition but the ESP the Linux RAID type, for the members
+    // of an mdadm mirror so nothing tries to mount them directly
+    #[serde(skip)]
+    pub raid_members: bool,
+}
+
+impl Default for PartitionPlan {
+    // ESP and a root partition filling the disk
+    fn default() -> Self {
+        PartitionPlan::new(1024, None, None)
+    }
+}
+
+impl PartitionPlan {
+    // ESP, optional swap, then either root filling the disk or a fixed size root followed by home
+    pub fn new(esp_mib: u64, swap_mib: Option<u64>, root_mib_with_home: Option<u64>) -> Self {
+        let mut partitions = vec![PartitionSpec::new(PartitionRole::Esp, Some(esp_mib))];
+        if let Some(swap) = swap_mib {
+            partitions.push(PartitionSpec::new(PartitionRole::Swap, Some(swap)));
+        }
+        match root_mib_with_home {
+            Some(root) => {
+                partitions.push(PartitionSpec::new(PartitionRole::Root, Some(root)));
+                partitions.push(PartitionSpec::new(PartitionRole::Home, None));
+            }
+            None => partitions.push(PartitionSpec::new(PartitionRole::Root, None)),
+        }
+        PartitionPlan {
+            partitions,
+            bios_boot: false,
+            raid_members: false,
+        }
+    }
+
+    pub fn find(&self, role: PartitionRole) -> Option<&PartitionSpec> {
+        self.partitions.iter().find(|p| p.role == role)
+    }
+
+    pub fn find_mut(&mut self, role: PartitionRole) -> Option<&mut PartitionSpec> {
+        self.partitions.iter_mut().find(|p| p.role == role)
+    }
+
+    /// Size of every partition in MiB on a disk of `disk_size` bytes, with the
+    /// last partition taking whatever is left.
+    pub fn sizes_mib(&self, disk_size: u64) -> Vec<u64> {
+        let fixed: u64 = self.partitions.iter().filter_map(|p| p.size_mib).sum();
+        let rest = (disk_size / MIB)
+            .saturating_sub(GPT_OVERHEAD_MIB)
+            .saturating_sub(fixed);
+        self.partitions
+            .iter()
+            .map(|p| p.size_mib.unwrap_or(rest))
+            .collect()
+    }
+
+    /// Check the plan is well formed and fits on a disk of `disk_size` bytes.
+    pub fn validate(&self, disk_size: u64) -> Result<()> {
+        let fail = |reason: String| Err(InstallerError::PreconditionFailed(reason));
+
+        for role in [PartitionRole::Esp, PartitionRole::Root] {
+            let count = self.partitions.iter().filter(|p| p.role == role).count();
+            if count != 1 {
+                return fail(format!(
+                    "Partition plan needs exactly one {} partition, found {}",
+                    role.name(),
+                    count
+                ));
+            }
+        }
+        for role in [PartitionRole::Swap, PartitionRole::Home] {
+            if self.partitions.iter().filter(|p| p.role == role).count() > 1 {
+                return fail(format!("Partition plan has more than one {} partition", role.name()));
+            }
+        }
+
+        let last = self.partitions.len() - 1;
+        for (i, spec) in self.partitions.iter().enumerate() {
+            match spec.size_mib {
+                Some(0) => return fail(format!("The {} partition has a size of 0", spec.role.name())),
+                None if i != last => {
+                    return fail(format!(
+                        "Only the last partition can fill the disk, not {}",
+                        spec.role.name()
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        for spec in &self.partitions {
+            spec.filesystem.validate()?;
+            let kind = spec.filesystem.kind;
+            if !spec.role.allows(kind) {
+                return fail(format!(
+                    "The {} partition cannot be formatted as {}",
+                    spec.role.name(),
+                    kind.name()
+                ));
+            }
+        }
+
+        let fixed: u64 = self.partitions.iter().filter_map(|p| p.size_mib).sum();
+        let available = (disk_size / MIB).saturating_sub(GPT_OVERHEAD_MIB);
+        // A partition filling the rest of the disk needs at least a little space
+        let needed = fixed + u64::from(self.partitions[last].size_mib.is_none());
+        if needed > available {
+            return fail(format!(
+                "Partition plan needs {} MiB but the disk only has {} MiB",
+                needed, available
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// A partition created on the target disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub role: PartitionRole,
+    pub number: u32,
+    pub path: String,
+    pub filesystem: FilesystemSpec,
+    // Opened LUKS container on top of `path`, if encrypted
+    pub mapper: Option<String>,
+    // False keeps the filesystem already on the partition
+    pub format: bool,
+}
+
+impl Partition {
+    // Device holding the filesystem, the mapper device for encrypted partitions
+    pub fn device(&self) -> &str {
+        self.mapper.as_deref().unwrap_or(&self.path)
+    }
+
+    pub fn find(partitions: &[Partition], role: PartitionRole) -> Option<&Partition> {
+        partitions.iter().find(|p| p.role == role)
+    }
+}
+
+// Device node of partition `number`, e.g. /dev/sda1 or /dev/nvme0n1p1
+pub fn partition_path(disk_path: &str, number: u32) -> String {
+    // Kernel names ending in a digit get a "p" separator (nvme0n1, mmcblk0, loop0)
+    if disk_path.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", disk_path, number)
+    } else {
+        format!("{}{}", disk_path, number)
+    }
+}
+
+/// Wipe `disk` and write a fresh GPT following `plan`.
+pub fn partition_disk(
+    runner: &dyn CommandRunner,
+    disk: &BlockDevice,
+    plan: &PartitionPlan,
+) -> Result<Vec<Partition>> {
+    plan.validate(disk.size)?;
+    ensure_unmounted(disk)?;
+
+    // Clear old filesystem signatures and partition tables
+    runner.run_checked(&Cmd::new("wipefs").args(["--all", &disk.path]))?;
+    runner.run_checked(&Cmd::new("sgdisk").args(["--zap-all", &disk.path]))?;
+
+    let mut partitions = Vec::new();
+    for (i, spec) in plan.partitions.iter().enumerate() {
+        let number = i as u32 + 1;
+        let end = match spec.size_mib {
+            Some(size) => format!("+{}M", size),
+            None => "0".to_string(), // Largest available block
+        };
+
+        let type_code = match spec.role {
+            PartitionRole::Esp => spec.role.type_code(),
+            _ if plan.raid_members => RAID_TYPE_CODE,
+            role => role.type_code(),
+        };
+
+        runner.run_checked(&Cmd::new("sgdisk").args([
+            format!("--new={}:0:{}", number, end),
+            format!("--typecode={}:{}", number, type_code),
+            format!("--change-name={}:{}", number, spec.role.name()),
+            disk.path.clone(),
+        ]))?;
+
+        partitions.push(Partition {
+            role: spec.role,
+            number,
+            path: partition_path(&disk.path, number),
+            filesystem: spec.filesystem.clone(),
+            mapper: None,
+            format: true,
+        });
+    }
+
+    if plan.bios_boot {
+        // Sectors 34-2047 are left free by the 1 MiB alignment of the other partitions
+        runner.run_checked(&Cmd::new("sgdisk").args([
+            "--set-alignment=1",
+            &format!("--new={}:34:2047", BIOS_BOOT_NUMBER),
+            &format!("--typecode={}:ef02", BIOS_BOOT_NUMBER),
+            &format!("--change-name={}:BIOS", BIOS_BOOT_NUMBER),
+            &disk.path,
+        ]))?;
+    }
+
+    settle(runner, disk)?;
+    Ok(partitions)
+}
+
+// Refuse to repartition a disk while anything on it is mounted
+pub(crate) fn ensure_unmounted(disk: &BlockDevice) -> Result<()> {
+    let mounted = disk
+        .partitions
+        .iter()
+        .chain(std::iter::once(disk))
+        .find(|d| !d.mountpoints.is_empty());
+    if let Some(mounted) = mounted {
+        return Err(InstallerError::PreconditionFailed(format!(
+            "{} is mounted on {}",
+            mounted.path,
+            mounted.mountpoints.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+// Make sure the kernel and udev know about the new table before formatting
+pub(crate) fn settle(runner: &dyn CommandRunner, disk: &BlockDevice) -> Result<()> {
+    runner.run_checked(&Cmd::new("partprobe").arg(disk.path.as_str()))?;
+    runner.run_checked(&Cmd::new("udevadm").arg("settle"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{CommandOutput, MockRunner, SystemRunner},
+        disk::{self, tests::disk},
+        testing::{self, LoopDevice},
+    };
+
+    #[test]
+    fn partition_disk_creates_the_plan_in_order() {
+        let runner = MockRunner::new();
+        let mut plan = PartitionPlan::new(512, Some(4096), Some(32768));
+        plan.partitions[3].filesystem = FilesystemSpec::new(FilesystemKind::Xfs);
+        let partitions = partition_disk(&runner, &disk("/dev/nvme0n1", 256 << 30), &plan).unwrap();
+
+        assert_eq!(
+            runner.command_lines(),
+            [
+                "wipefs --all /dev/nvme0n1",
+                "sgdisk --zap-all /dev/nvme0n1",
+                "sgdisk --new=1:0:+512M --typecode=1:ef00 --change-name=1:EFI /dev/nvme0n1",
+                "sgdisk --new=2:0:+4096M --typecode=2:8200 --change-name=2:swap /dev/nvme0n1",
+                "sgdisk --new=3:0:+32768M --typecode=3:8304 --change-name=3:root /dev/nvme0n1",
+                "sgdisk --new=4:0:0 --typecode=4:8302 --change-name=4:home /dev/nvme0n1",
+                "partprobe /dev/nvme0n1",
+                "udevadm settle",
+            ]
+        );
+        let paths: Vec<&str> = partitions.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/dev/nvme0n1p1",
+                "/dev/nvme0n1p2",
+                "/dev/nvme0n1p3",
+                "/dev/nvme0n1p4"
+            ]
+        );
+        assert_eq!(partitions[3].filesystem.kind, FilesystemKind::Xfs);
+        assert!(partitions.iter().all(|p| p.format && p.mapper.is_none()));
+    }
+
+    #[test]
+    fn partition_disk_adds_the_bios_boot_partition() {
+        let runner = MockRunner::new();
+        let plan = PartitionPlan {
+            bios_boot: true,
+            ..PartitionPlan::default()
+        };
+        partition_disk(&runner, &disk("/dev/sda", 64 << 30), &plan).unwrap();
+
+        let lines = runner.command_lines();
+        assert_eq!(
+            lines[4],
+            "sgdisk --set-alignment=1 --new=128:34:2047 --typecode=128:ef02 --change-name=128:BIOS /dev/sda"
+        );
+        assert_eq!(lines.last().unwrap(), "udevadm settle");
+    }
+
+    #[test]
+    fn partition_disk_stops_at_the_first_failing_step() {
+        let runner = MockRunner::new();
+        runner.on(
+            "sgdisk",
+            CommandOutput::failure(2, "Problem opening /dev/sda for writing!"),
+        );
+        let sda = disk("/dev/sda", 64 << 30);
+        let err = partition_disk(&runner, &sda, &PartitionPlan::default()).unwrap_err();
+
+        assert!(err.to_string().contains("Problem opening /dev/sda"));
+        assert_eq!(
+            runner.command_lines(),
+            ["wipefs --all /dev/sda", "sgdisk --zap-all /dev/sda"]
+        );
+    }
+
+    #[test]
+    fn partition_disk_refuses_before_touching_the_disk() {
+        let runner = MockRunner::new();
+        let mut sda = disk("/dev/sda", 64 << 30);
+        let mut sda1 = disk("/dev/sda1", 1 << 30);
+        sda1.mountpoints = vec!["/mnt/data".to_string()];
+        sda.partitions.push(sda1);
+        let err = partition_disk(&runner, &sda, &PartitionPlan::default()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("/dev/sda1 is mounted on /mnt/data"));
+
+        let tiny = disk("/dev/sdb", 512 << 20);
+        assert!(partition_disk(&runner, &tiny, &PartitionPlan::default()).is_err());
+        assert!(runner.invocations().is_empty());
+    }
+
+    #[test]
+    fn last_partition_takes_the_rest_of_the_disk() {
+        let plan = PartitionPlan::new(512, Some(4096), Some(32768));
+        // 64 GiB less the 2 MiB GPT overhead
+        assert_eq!(plan.sizes_mib(64 << 30), [512, 4096, 32768, 65534 - 37376]);
+        assert_eq!(PartitionPlan::default().sizes_mib(4 << 30), [1024, 3070]);
+        // Too small a disk leaves nothing rather than underflowing
+        assert_eq!(PartitionPlan::default().sizes_mib(512 << 20), [1024, 0]);
+    }
+
+    #[test]
+    fn validate_rejects_malformed_plans() {
+        let size = 64 << 30;
+        assert!(PartitionPlan::default().validate(size).is_ok());
+
+        let error = |plan: PartitionPlan| plan.validate(size).unwrap_err().to_string();
+        let mut plan = PartitionPlan::default();
+        plan.partitions.remove(0);
+        assert_eq!(
+            error(plan),
+            "Partition plan needs exactly one EFI partition, found 0"
+        );
+
+        let mut plan = PartitionPlan::new(512, Some(1024), None);
+        plan.partitions
+            .push(PartitionSpec::new(PartitionRole::Swap, Some(1024)));
+        assert_eq!(
+            error(plan),
+            "Partition plan has more than one swap partition"
+        );
+
+        let mut plan = PartitionPlan::default();
+        plan.partitions
+            .insert(1, PartitionSpec::new(PartitionRole::Home, None));
+        assert_eq!(
+            error(plan),
+            "Only the last partition can fill the disk, not home"
+        );
+
+        let mut plan = PartitionPlan::default();
+        plan.partitions[0].size_mib = Some(0);
+        assert_eq!(error(plan), "The EFI partition has a size of 0");
+
+        let mut plan = PartitionPlan::default();
+        plan.partitions[1].filesystem = FilesystemSpec::new(FilesystemKind::Swap);
+        assert_eq!(
+            error(plan),
+            "The root partition cannot be formatted as swap"
+        );
+
+        let plan = PartitionPlan::new(512, None, Some(8192));
+        assert_eq!(
+            plan.validate(8 << 30).unwrap_err().to_string(),
+            "Partition plan needs 8705 MiB but the disk only has 8190 MiB"
+        );
+    }
+
+    #[test]
+    fn partition_paths_follow_the_kernel_names() {
+        assert_eq!(partition_path("/dev/sda", 1), "/dev/sda1");
+        assert_eq!(partition_path("/dev/vdb", 12), "/dev/vdb12");
+        assert_eq!(partition_path("/dev/nvme0n1", 1), "/dev/nvme0n1p1");
+        assert_eq!(partition_path("/dev/mmcblk0", 2), "/dev/mmcblk0p2");
+        assert_eq!(partition_path("/dev/loop0", 3), "/dev/loop0p3");
+    }
+
+    #[test]
+    #[ignore = "needs root, losetup, sgdisk and sfdisk"]
+    fn partition_disk_lays_out_a_loop_device() {
+        let tools = ["losetup", "wipefs", "sgdisk", "partprobe", "udevadm", "sfdisk"];
+        if !testing::privileged(&tools) {
+            return;
+        }
+        let image = LoopDevice::new(2 << 30);
+        let device = disk::read_device(&SystemRunner, image.path()).unwrap();
+        let mut plan = PartitionPlan::new(256, Some(512), None);
+        plan.bios_boot = true;
+        let partitions = partition_disk(&SystemRunner, &device, &plan).unwrap();
+        assert_eq!(partitions[2].path, format!("{}p3", image.path()));
+
+        let dump = SystemRunner
+            .run_checked(&Cmd::new("sfdisk").args(["--json", image.path()]))
+            .unwrap();
+        let dump: serde_json::Value = serde_json::from_str(&dump.stdout).unwrap();
+        let table = &dump["partitiontable"];
+        assert_eq!(table["label"], "gpt");
+        let layout: Vec<(String, u64, u64, String)> = table["partitions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| {
+                (
+                    p["node"].as_str().unwrap().to_string(),
+                    p["start"].as_u64().unwrap(),
+                    p["size"].as_u64().unwrap(),
+                    p["type"].as_str().unwrap().to_uppercase(),
+                )
+            })
+            .collect();
+        let node = |number: u32| format!("{}p{}", image.path(), number);
+        let esp = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B".to_string();
+        let swap = "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F".to_string();
+        let root = "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709".to_string();
+        let bios = "21686148-6449-6E6F-744E-656564454649".to_string();
+        // 512 byte sectors, starting on mebibyte boundaries
+        assert_eq!(layout[0], (node(1), 2048, 256 << 11, esp));
+        assert_eq!(layout[1], (node(2), 526336, 512 << 11, swap));
+        assert_eq!((&layout[2].0, layout[2].1, &layout[2].3), (&node(3), 1574912, &root));
+        // Root takes the rest, up to the backup header in the last 33 sectors
+        let root_end = layout[2].1 + layout[2].2 - 1;
+        let last_usable = (2 << 21) - 34;
+        assert!(root_end <= last_usable && root_end > last_usable - 2048, "{:?}", layout);
+        assert_eq!(layout[3], (node(128), 34, 2014, bios));
+        assert_eq!(layout.len(), 4);
+    }
+}
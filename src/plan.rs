@@ -0,0 +1,266 @@
+use std::{fmt, path::Path, sync::Mutex};
+
+use serde::Serialize;
+
+use crate::{
+    command::{Cmd, CommandOutput, CommandRunner, Mount},
+    error::Result,
+};
+
+/// One step the installer would perform against the target system.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PlannedAction {
+    Command {
+        program: String,
+        args: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stdin: Option<String>,
+    },
+    WriteFile {
+        path: String,
+        contents: String,
+    },
+    Mount {
+        source: String,
+        target: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fstype: Option<String>,
+        options: Vec<String>,
+    },
+}
+
+impl fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlannedAction::Command {
+                program,
+                args,
+                stdin,
+            } => {
+                let cmd = Cmd::new(program.as_str()).args(args.iter().map(String::as_str));
+                write!(f, "run    {}", cmd)?;
+                if stdin.is_some() {
+                    write!(f, " < (stdin)")?;
+                }
+                Ok(())
+            }
+            PlannedAction::WriteFile { path, contents } => {
+                write!(f, "write  {} ({} bytes)", path, contents.len())?;
+                for line in contents.lines() {
+                    write!(f, "\n         | {}", line)?;
+                }
+                Ok(())
+            }
+            PlannedAction::Mount {
+                source,
+                target,
+                fstype,
+                options,
+            } => {
+                write!(f, "mount  {} on {}", source, target)?;
+                if let Some(fstype) = fstype {
+                    write!(f, " type {}", fstype)?;
+                }
+                if !options.is_empty() {
+                    write!(f, " ({})", options.join(","))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Ordered list of everything a real run would do.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct InstallPlan {
+    pub actions: Vec<PlannedAction>,
+}
+
+impl InstallPlan {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("install plan is always serializable")
+    }
+}
+
+impl fmt::Display for InstallPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.actions.is_empty() {
+            return writeln!(f, "Nothing to do");
+        }
+        for (i, action) in self.actions.iter().enumerate() {
+            writeln!(f, "{:>3}. {}", i + 1, action)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records every change instead of performing it. Read-only commands such as
+/// disk discovery still go to `inner` so the flow sees the real system.
+pub struct DryRunRunner<'a> {
+    inner: &'a dyn CommandRunner,
+    actions: Mutex<Vec<PlannedAction>>,
+}
+
+impl<'a> DryRunRunner<'a> {
+    pub fn new(inner: &'a dyn CommandRunner) -> Self {
+        DryRunRunner {
+            inner,
+            actions: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn plan(&self) -> InstallPlan {
+        InstallPlan {
+            actions: self.actions.lock().unwrap().clone(),
+        }
+    }
+
+    fn record(&self, action: PlannedAction) {
+        self.actions.lock().unwrap().push(action);
+    }
+}
+
+impl CommandRunner for DryRunRunner<'_> {
+    fn run(&self, cmd: &Cmd) -> Result<CommandOutput> {
+        if cmd.read_only {
+            return self.inner.run(cmd);
+        }
+        let cmd = cmd.redacted();
+        self.record(PlannedAction::Command {
+            program: cmd.program,
+            args: cmd.args,
+            stdin: cmd.stdin,
+        });
+        Ok(CommandOutput::success(""))
+    }
+
+    fn write_file(&self, path: &Path, contents: &str) -> Result<()> {
+        self.record(PlannedAction::WriteFile {
+            path: path.to_string_lossy().into_owned(),
+            contents: contents.to_string(),
+        });
+        Ok(())
+    }
+
+    fn mount(&self, mount: &Mount) -> Result<()> {
+        self.record(PlannedAction::Mount {
+            source: mount.source.clone(),
+            target: mount.target.to_string_lossy().into_owned(),
+            fstype: mount.fstype.clone(),
+            options: mount.options.clone(),
+        });
+        Ok(())
+    }
+
+    fn is_dry_run(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::MockRunner;
+
+    // One action of each kind, as a partitioning and mounting run records them
+    fn plan() -> InstallPlan {
+        let host = MockRunner::new();
+        let runner = DryRunRunner::new(&host);
+        runner
+            .run(&Cmd::new("sgdisk").args(["--zap-all", "/dev/sda"]))
+            .unwrap();
+        runner
+            .run(&Cmd::new("cryptsetup").secret_stdin("hunter2"))
+            .unwrap();
+        runner
+            .write_file(Path::new("/mnt/etc/hostname"), "arch\n")
+            .unwrap();
+        runner
+            .mount(
+                &Mount::new("/dev/sda1", "/mnt/boot")
+                    .fstype("vfat")
+                    .option("fmask=0077"),
+            )
+            .unwrap();
+        runner.plan()
+    }
+
+    #[test]
+    fn plan_lists_numbered_steps() {
+        assert_eq!(
+            plan().to_string(),
+            concat!(
+                "  1. run    sgdisk --zap-all /dev/sda\n",
+                "  2. run    cryptsetup < (stdin)\n",
+                "  3. write  /mnt/etc/hostname (5 bytes)\n",
+                "         | arch\n",
+                "  4. mount  /dev/sda1 on /mnt/boot type vfat (fmask=0077)\n",
+            )
+        );
+        assert_eq!(InstallPlan::default().to_string(), "Nothing to do\n");
+    }
+
+    // What `--dry-run --json` prints, scripts rely on this shape
+    #[test]
+    fn json_tags_every_action_with_its_kind() {
+        let json: serde_json::Value = serde_json::from_str(&plan().to_json()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "actions": [
+                    {
+                        "kind": "command",
+                        "program": "sgdisk",
+                        "args": ["--zap-all", "/dev/sda"],
+                    },
+                    {
+                        "kind": "command",
+                        "program": "cryptsetup",
+                        "args": [],
+                        "stdin": "<redacted>",
+                    },
+                    {
+                        "kind": "write_file",
+                        "path": "/mnt/etc/hostname",
+                        "contents": "arch\n",
+                    },
+                    {
+                        "kind": "mount",
+                        "source": "/dev/sda1",
+                        "target": "/mnt/boot",
+                        "fstype": "vfat",
+                        "options": ["fmask=0077"],
+                    },
+                ]
+            })
+        );
+        assert!(!plan().to_json().contains("hunter2"));
+    }
+
+    #[test]
+    fn read_only_commands_reach_the_host() {
+        let host = MockRunner::new();
+        host.on("lsblk", CommandOutput::success("{\"blockdevices\": []}"));
+        let runner = DryRunRunner::new(&host);
+
+        let output = runner
+            .run(&Cmd::new("lsblk").arg("--json").read_only())
+            .unwrap();
+        assert_eq!(output.stdout, "{\"blockdevices\": []}");
+        assert_eq!(host.command_lines(), ["lsblk --json"]);
+        assert!(runner.plan().actions.is_empty());
+
+        // Everything else is only recorded and reported as successful
+        assert!(runner
+            .run(&Cmd::new("wipefs").arg("--all"))
+            .unwrap()
+            .is_success());
+        runner.mount(&Mount::new("/dev/sda2", "/mnt")).unwrap();
+        runner.write_file(Path::new("/mnt/etc/fstab"), "").unwrap();
+        assert_eq!(host.invocations().len(), 1);
+        assert!(host.written_files().is_empty());
+        assert_eq!(runner.plan().actions.len(), 3);
+        assert!(runner.is_dry_run());
+    }
+}
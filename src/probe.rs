@@ -0,0 +1,179 @@
+use std::{fs, path::Path};
+
+use crate::{
+    command::{Cmd, CommandRunner, Mount},
+    disk::BlockDevice,
+    error::Result,
+};
+
+// Temporary mount point for looking inside existing partitions, away from the target
+pub const SCRATCH: &str = "/run/archinstaller/probe";
+
+// Filesystems worth looking into, everything else is reported by type alone
+const PROBED: &[&str] = &[
+    "ext2", "ext3", "ext4", "btrfs", "xfs", "f2fs", "vfat", "exfat", "ntfs", "ntfs3",
+];
+
+/// Name the operating system or boot manager found in the filesystem tree at `root`.
+pub fn identify(root: &Path) -> Option<String> {
+    // A btrfs top level usually keeps the system in the @ subvolume
+    for prefix in ["", "@/"] {
+        for file in ["etc/os-release", "usr/lib/os-release"] {
+            if let Ok(text) = fs::read_to_string(root.join(prefix).join(file)) {
+                if let Some(name) = os_release_name(&text) {
+                    return Some(name);
+                }
+            }
+        }
+    }
+
+    if root.join("Windows/System32/ntoskrnl.exe").is_file() {
+        return Some("Windows".to_string());
+    }
+    // vfat lookups ignore case, so this also finds EFI/MICROSOFT/BOOT/BOOTMGFW.EFI
+    if root.join("EFI/Microsoft/Boot/bootmgfw.efi").is_file() || root.join("bootmgr").is_file() {
+        return Some("Windows Boot Manager".to_string());
+    }
+
+    // An ESP shared with other systems, named after their loader directories
+    let loaders: Vec<String> = fs::read_dir(root.join("EFI"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| !name.eq_ignore_ascii_case("boot"))
+        .collect();
+    if !loaders.is_empty() {
+        return Some(format!("EFI loaders: {}", loaders.join(", ")));
+    }
+    None
+}
+
+// PRETTY_NAME, or NAME when there is no pretty one
+fn os_release_name(text: &str) -> Option<String> {
+    let value = |key: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .map(|v| v.trim().trim_matches('"').to_string())
+            .filter(|v| !v.is_empty())
+    };
+    value("PRETTY_NAME").or_else(|| value("NAME"))
+}
+
+/// What is installed on `device`, looked up where it is already mounted or
+/// through a temporary read-only mount on `scratch`.
+pub fn detect_os(
+    runner: &dyn CommandRunner,
+    device: &BlockDevice,
+    scratch: &Path,
+) -> Result<Option<String>> {
+    let Some(fstype) = device.fstype.as_deref() else {
+        return Ok(None);
+    };
+    if !PROBED.contains(&fstype) {
+        return Ok(None);
+    }
+    if let Some(mountpoint) = device.mountpoints.iter().find(|m| m.starts_with('/')) {
+        return Ok(identify(Path::new(mountpoint)));
+    }
+
+    // These mounts run for real even in dry-run, so nothing may be written:
+    // a plain ro mount would still replay an ext3/ext4 or xfs journal
+    let mut mount = Mount::new(device.path.as_str(), scratch)
+        .option("ro")
+        .option("noexec")
+        .option("nosuid")
+        .option("nodev");
+    match fstype {
+        "ext3" | "ext4" => mount = mount.option("noload"),
+        "xfs" => mount = mount.option("norecovery"),
+        _ => {}
+    }
+    runner.run_checked(
+        &Cmd::new("mkdir")
+            .args(["-p", &scratch.to_string_lossy()])
+            .read_only(),
+    )?;
+    runner.run_checked(&mount.to_cmd().read_only())?;
+    let found = identify(scratch);
+    runner.run_checked(
+        &Cmd::new("umount")
+            .arg(scratch.to_string_lossy())
+            .read_only(),
+    )?;
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{command::MockRunner, disk::tests::disk, testing::TempDir};
+
+    fn partition(path: &str, fstype: &str) -> BlockDevice {
+        let mut part = disk(path, 1 << 30);
+        part.device_type = "part".to_string();
+        part.fstype = Some(fstype.to_string());
+        part
+    }
+
+    #[test]
+    fn probe_mounts_never_replay_a_journal() {
+        let scratch = TempDir::new();
+        let mount_line = |fstype: &str| {
+            let runner = MockRunner::new();
+            detect_os(&runner, &partition("/dev/sda2", fstype), scratch.path()).unwrap();
+            assert!(runner.invocations().iter().all(|cmd| cmd.read_only));
+            runner.command_lines()[1].clone()
+        };
+        let target = scratch.path().display();
+        assert_eq!(
+            mount_line("ext4"),
+            format!(
+                "mount -o ro,noexec,nosuid,nodev,noload /dev/sda2 {}",
+                target
+            )
+        );
+        assert!(mount_line("ext3").contains(",noload "));
+        assert!(mount_line("xfs").contains(",nodev,norecovery "));
+        assert!(mount_line("vfat").contains(",nodev "));
+    }
+
+    #[test]
+    fn mounted_and_unknown_filesystems_are_not_mounted_again() {
+        let root = TempDir::new();
+        root.write("etc/os-release", "NAME=\"Arch Linux\"\n");
+        let mut mounted = partition("/dev/sda2", "ext4");
+        mounted.mountpoints = vec![root.path().to_string_lossy().into_owned()];
+        let runner = MockRunner::new();
+
+        let found = detect_os(&runner, &mounted, Path::new(SCRATCH)).unwrap();
+        assert_eq!(found.as_deref(), Some("Arch Linux"));
+        let found = detect_os(&runner, &partition("/dev/sda3", "swap"), Path::new(SCRATCH));
+        assert_eq!(found.unwrap(), None);
+        assert!(runner.invocations().is_empty());
+    }
+
+    #[test]
+    fn identify_names_what_it_finds() {
+        let root = TempDir::new();
+        assert_eq!(identify(root.path()), None);
+        root.mkdir("EFI/BOOT");
+        root.mkdir("EFI/ubuntu");
+        assert_eq!(
+            identify(root.path()).as_deref(),
+            Some("EFI loaders: ubuntu")
+        );
+        root.write("EFI/Microsoft/Boot/bootmgfw.efi", "");
+        assert_eq!(
+            identify(root.path()).as_deref(),
+            Some("Windows Boot Manager")
+        );
+
+        let btrfs = TempDir::new();
+        btrfs.write(
+            "@/usr/lib/os-release",
+            "NAME=Fedora\nPRETTY_NAME=\"Fedora Linux 40\"\n",
+        );
+        assert_eq!(identify(btrfs.path()).as_deref(), Some("Fedora Linux 40"));
+    }
+}
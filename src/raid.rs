@@ -0,0 +1,560 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{target_path, Cmd, CommandRunner},
+    config::InstallConfig,
+    disk::{self, BlockDevice},
+    error::{InstallerError, Result},
+    format::{FilesystemKind, FilesystemSpec},
+    mount::MountManager,
+    partition::{self, partition_path, Partition, PartitionPlan, PartitionRole},
+};
+
+// Written into the target so the arrays are assembled under the same names at boot
+const MDADM_CONF: &str = "/etc/mdadm.conf";
+
+/// How a mirror is built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MirrorKind {
+    // One mdadm RAID1 array per partition, any filesystem on top
+    Mdadm,
+    // btrfs' own raid1 for data and metadata, only for btrfs partitions.
+    // Swap cannot be mirrored this way, every disk gets a swap partition instead
+    Btrfs,
+}
+
+impl MirrorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            MirrorKind::Mdadm => "mdadm RAID1",
+            MirrorKind::Btrfs => "btrfs raid1",
+        }
+    }
+}
+
+/// What disks beyond the primary one are used for. The primary disk holds
+/// the EFI system partition and the bootloader either way.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "layout", rename_all = "snake_case")]
+pub enum MultiDisk {
+    // /home fills a disk of its own
+    SeparateHome {
+        disk: String,
+    },
+    // Every partition but the ESP is mirrored onto these disks
+    Mirror {
+        disks: Vec<String>,
+        raid: MirrorKind,
+    },
+}
+
+impl MultiDisk {
+    /// Paths of the disks besides the primary one.
+    pub fn disks(&self) -> Vec<&str> {
+        match self {
+            MultiDisk::SeparateHome { disk } => vec![disk.as_str()],
+            MultiDisk::Mirror { disks, .. } => disks.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// Whether the target needs mdadm to assemble its arrays at boot.
+    pub fn uses_mdadm(&self) -> bool {
+        matches!(
+            self,
+            MultiDisk::Mirror {
+                raid: MirrorKind::Mdadm,
+                ..
+            }
+        )
+    }
+
+    /// Check the layout fits the rest of `config`.
+    pub fn validate(&self, config: &InstallConfig) -> Result<()> {
+        let fail = |reason: String| Err(InstallerError::PreconditionFailed(reason));
+
+        let disks = self.disks();
+        if disks.is_empty() {
+            return fail("A mirror needs at least one more disk".to_string());
+        }
+        for (i, path) in disks.iter().enumerate() {
+            if config.disk.as_deref() == Some(*path) || disks[..i].contains(path) {
+                return fail(format!("{} is used twice", path));
+            }
+        }
+        if config.manual_partitions.is_some() {
+            return fail(
+                "Several disks can only be used with the automatic partition layout".to_string(),
+            );
+        }
+
+        match self {
+            MultiDisk::SeparateHome { .. } => {
+                if config.partitions.find(PartitionRole::Home).is_some() {
+                    return fail(
+                        "The partition plan already has a home partition on the first disk"
+                            .to_string(),
+                    );
+                }
+            }
+            MultiDisk::Mirror {
+                raid: MirrorKind::Btrfs,
+                ..
+            } => {
+                if config.encryption.is_some() {
+                    return fail(
+                        "An encrypted root cannot be mirrored with btrfs raid1, use mdadm RAID1"
+                            .to_string(),
+                    );
+                }
+                for spec in &config.partitions.partitions {
+                    if !matches!(spec.role, PartitionRole::Esp | PartitionRole::Swap)
+                        && spec.filesystem.kind != FilesystemKind::Btrfs
+                    {
+                        return fail(format!(
+                            "btrfs raid1 only mirrors btrfs, but the {} partition is {}",
+                            spec.role.name(),
+                            spec.filesystem.kind.name()
+                        ));
+                    }
+                }
+            }
+            MultiDisk::Mirror { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Block devices of the disks besides the primary one.
+pub fn extra_disks(runner: &dyn CommandRunner, multi: &MultiDisk) -> Result<Vec<BlockDevice>> {
+    multi
+        .disks()
+        .into_iter()
+        .map(|path| disk::read_device(runner, path))
+        .collect()
+}
+
+/// Partition `primary` and `extra` for `multi`, returning the partitions
+/// the install works with. Mirrors are assembled here, so a root partition
+/// of an mdadm mirror is the array rather than a partition.
+pub fn partition_disks(
+    runner: &dyn CommandRunner,
+    mounts: &mut MountManager,
+    primary: &BlockDevice,
+    extra: &[BlockDevice],
+    config: &InstallConfig,
+    multi: &MultiDisk,
+) -> Result<Vec<Partition>> {
+    let plan = PartitionPlan {
+        raid_members: multi.uses_mdadm(),
+        ..config.partitions.clone()
+    };
+    let mut partitions = partition::partition_disk(runner, primary, &plan)?;
+
+    match multi {
+        MultiDisk::SeparateHome { .. } => {
+            for disk in extra {
+                partitions.push(home_disk(runner, disk)?);
+            }
+        }
+        MultiDisk::Mirror { raid, .. } => {
+            // Every disk gets the same layout, the ESPs beyond the first one stay unused
+            let mut mirrors = Vec::new();
+            for disk in extra {
+                mirrors.push(partition::partition_disk(runner, disk, &plan)?);
+            }
+
+            let mut swaps = Vec::new();
+            for partition in partitions.iter_mut() {
+                if partition.role == PartitionRole::Esp {
+                    continue;
+                }
+                let members: Vec<&Partition> = mirrors
+                    .iter()
+                    .filter_map(|parts| Partition::find(parts, partition.role))
+                    .collect();
+
+                match (raid, partition.role) {
+                    (MirrorKind::Btrfs, PartitionRole::Swap) => {
+                        swaps.extend(members.into_iter().cloned());
+                    }
+                    (MirrorKind::Mdadm, _) => {
+                        let others: Vec<String> = members.iter().map(|p| p.path.clone()).collect();
+                        let array = create_array(runner, partition.role, &partition.path, &others)?;
+                        mounts.track_array(&array);
+                        partition.path = array;
+                    }
+                    (MirrorKind::Btrfs, _) => {
+                        // mkfs.btrfs takes the other devices after its options
+                        let spec = &mut partition.filesystem;
+                        spec.options
+                            .extend(["-d", "raid1", "-m", "raid1"].map(String::from));
+                        spec.options.extend(members.iter().map(|p| p.path.clone()));
+                    }
+                }
+            }
+            partitions.extend(swaps);
+        }
+    }
+    Ok(partitions)
+}
+
+// Wipe `disk` and give it a single home partition
+fn home_disk(runner: &dyn CommandRunner, disk: &BlockDevice) -> Result<Partition> {
+    partition::ensure_unmounted(disk)?;
+    let role = PartitionRole::Home;
+    runner.run_checked(&Cmd::new("wipefs").args(["--all", &disk.path]))?;
+    runner.run_checked(&Cmd::new("sgdisk").args(["--zap-all", &disk.path]))?;
+    runner.run_checked(&Cmd::new("sgdisk").args([
+        "--new=1:0:0".to_string(),
+        format!("--typecode=1:{}", role.type_code()),
+        format!("--change-name=1:{}", role.name()),
+        disk.path.clone(),
+    ]))?;
+    partition::settle(runner, disk)?;
+
+    Ok(Partition {
+        role,
+        number: 1,
+        path: partition_path(&disk.path, 1),
+        filesystem: FilesystemSpec::new(FilesystemKind::default_for(role)),
+        mapper: None,
+        format: true,
+    })
+}
+
+// Build a RAID1 array named after `role` from `first` and `others`
+fn create_array(
+    runner: &dyn CommandRunner,
+    role: PartitionRole,
+    first: &str,
+    others: &[String],
+) -> Result<String> {
+    let array = format!("/dev/md/{}", role.name());
+    runner.run_checked(
+        &Cmd::new("mdadm")
+            .args([
+                "--create".to_string(),
+                array.clone(),
+                "--run".to_string(),
+                "--level=1".to_string(),
+                "--metadata=1.2".to_string(),
+                format!("--raid-devices={}", others.len() + 1),
+                first.to_string(),
+            ])
+            .args(others.iter().map(String::as_str)),
+    )?;
+    Ok(array)
+}
+
+/// Stop the array `device`.
+pub fn stop_array(runner: &dyn CommandRunner, device: &str) -> Result<()> {
+    runner.run_checked(&Cmd::new("mdadm").args(["--stop", device]))?;
+    Ok(())
+}
+
+/// Record the arrays assembled on the live system in the target's mdadm.conf.
+pub fn write_mdadm_conf(runner: &dyn CommandRunner, target: &Path) -> Result<()> {
+    let scan = runner.run_checked(&Cmd::new("mdadm").args(["--detail", "--scan"]))?;
+    let mut contents = String::from("# Arrays assembled at boot, from mdadm --detail --scan\n");
+    contents.push_str(&scan.stdout);
+    runner.write_file(&target_path(target, MDADM_CONF), &contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{CommandOutput, MockRunner},
+        crypt::{Encryption, LuksKey},
+        disk::tests::disk,
+        gpt::GptTable,
+    };
+
+    const ESP: &str = "--new=1:0:+512M --typecode=1:ef00 --change-name=1:EFI";
+
+    // Everything partition_disk runs on `disk` for the sgdisk `partitions`
+    fn layout(disk: &str, partitions: &[&str]) -> Vec<String> {
+        let mut lines = vec![
+            format!("wipefs --all {}", disk),
+            format!("sgdisk --zap-all {}", disk),
+        ];
+        lines.extend(partitions.iter().map(|p| format!("sgdisk {} {}", p, disk)));
+        lines.push(format!("partprobe {}", disk));
+        lines.push("udevadm settle".to_string());
+        lines
+    }
+
+    fn config(partitions: PartitionPlan, multi: &MultiDisk) -> InstallConfig {
+        InstallConfig {
+            disk: Some("/dev/sda".to_string()),
+            partitions,
+            multi_disk: Some(multi.clone()),
+            ..InstallConfig::default()
+        }
+    }
+
+    // ESP, 1 GiB swap and a root filling the disk, as `kind`
+    fn swap_and_root(kind: FilesystemKind) -> PartitionPlan {
+        let mut plan = PartitionPlan::new(512, Some(1024), None);
+        plan.partitions[2].filesystem = FilesystemSpec::new(kind);
+        plan
+    }
+
+    fn mirror(disks: &[&str], raid: MirrorKind) -> MultiDisk {
+        MultiDisk::Mirror {
+            disks: disks.iter().map(|d| d.to_string()).collect(),
+            raid,
+        }
+    }
+
+    fn paths(partitions: &[Partition]) -> Vec<(PartitionRole, &str)> {
+        partitions
+            .iter()
+            .map(|p| (p.role, p.path.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn validate_checks_the_disks_and_the_layout() {
+        let error = |config: &InstallConfig| {
+            let multi = config.multi_disk.as_ref().unwrap();
+            multi.validate(config).unwrap_err().to_string()
+        };
+        let home = MultiDisk::SeparateHome {
+            disk: "/dev/sdb".to_string(),
+        };
+        home.validate(&config(PartitionPlan::default(), &home))
+            .unwrap();
+
+        let empty = mirror(&[], MirrorKind::Mdadm);
+        assert_eq!(
+            error(&config(PartitionPlan::default(), &empty)),
+            "A mirror needs at least one more disk"
+        );
+        let primary = MultiDisk::SeparateHome {
+            disk: "/dev/sda".to_string(),
+        };
+        assert_eq!(
+            error(&config(PartitionPlan::default(), &primary)),
+            "/dev/sda is used twice"
+        );
+        let twice = mirror(&["/dev/sdb", "/dev/sdb"], MirrorKind::Mdadm);
+        assert_eq!(
+            error(&config(PartitionPlan::default(), &twice)),
+            "/dev/sdb is used twice"
+        );
+
+        let manual = InstallConfig {
+            manual_partitions: Some(GptTable::new(64 << 30, 512)),
+            ..config(PartitionPlan::default(), &home)
+        };
+        assert_eq!(
+            error(&manual),
+            "Several disks can only be used with the automatic partition layout"
+        );
+        assert_eq!(
+            error(&config(PartitionPlan::new(512, None, Some(8192)), &home)),
+            "The partition plan already has a home partition on the first disk"
+        );
+    }
+
+    #[test]
+    fn btrfs_mirrors_only_take_btrfs() {
+        let raid1 = mirror(&["/dev/sdb"], MirrorKind::Btrfs);
+        let error = |config: &InstallConfig| raid1.validate(config).unwrap_err().to_string();
+
+        // Swap is fine, every disk gets its own
+        raid1
+            .validate(&config(swap_and_root(FilesystemKind::Btrfs), &raid1))
+            .unwrap();
+        assert_eq!(
+            error(&config(swap_and_root(FilesystemKind::Ext4), &raid1)),
+            "btrfs raid1 only mirrors btrfs, but the root partition is ext4"
+        );
+        let encrypted = InstallConfig {
+            encryption: Some(Encryption::new(LuksKey::Passphrase("hunter2".to_string()))),
+            ..config(swap_and_root(FilesystemKind::Btrfs), &raid1)
+        };
+        assert_eq!(
+            error(&encrypted),
+            "An encrypted root cannot be mirrored with btrfs raid1, use mdadm RAID1"
+        );
+        // mdadm puts any filesystem on top of the array
+        let md = mirror(&["/dev/sdb"], MirrorKind::Mdadm);
+        md.validate(&config(swap_and_root(FilesystemKind::Ext4), &md))
+            .unwrap();
+    }
+
+    #[test]
+    fn separate_home_fills_the_second_disk() {
+        let runner = MockRunner::new();
+        let multi = MultiDisk::SeparateHome {
+            disk: "/dev/sdb".to_string(),
+        };
+        let config = config(PartitionPlan::new(512, None, None), &multi);
+        let mut mounts = MountManager::new(&runner);
+        let partitions = partition_disks(
+            &runner,
+            &mut mounts,
+            &disk("/dev/sda", 64 << 30),
+            &[disk("/dev/sdb", 256 << 30)],
+            &config,
+            &multi,
+        )
+        .unwrap();
+
+        let mut expected = layout(
+            "/dev/sda",
+            &[ESP, "--new=2:0:0 --typecode=2:8304 --change-name=2:root"],
+        );
+        expected.extend(layout(
+            "/dev/sdb",
+            &["--new=1:0:0 --typecode=1:8302 --change-name=1:home"],
+        ));
+        assert_eq!(runner.command_lines(), expected);
+        assert_eq!(
+            paths(&partitions),
+            [
+                (PartitionRole::Esp, "/dev/sda1"),
+                (PartitionRole::Root, "/dev/sda2"),
+                (PartitionRole::Home, "/dev/sdb1"),
+            ]
+        );
+        assert_eq!(partitions[2].filesystem.kind, FilesystemKind::Ext4);
+    }
+
+    #[test]
+    fn mdadm_mirror_builds_an_array_per_partition() {
+        let runner = MockRunner::new();
+        let multi = mirror(&["/dev/sdb", "/dev/sdc"], MirrorKind::Mdadm);
+        let config = config(swap_and_root(FilesystemKind::Xfs), &multi);
+        let mut mounts = MountManager::new(&runner);
+        let disks = [
+            disk("/dev/sda", 64 << 30),
+            disk("/dev/sdb", 64 << 30),
+            disk("/dev/sdc", 64 << 30),
+        ];
+        let partitions = partition_disks(
+            &runner,
+            &mut mounts,
+            &disks[0],
+            &disks[1..],
+            &config,
+            &multi,
+        )
+        .unwrap();
+
+        // Members get the RAID type while partitioning, before the kernel rereads the table
+        let mut expected = Vec::new();
+        for disk in ["/dev/sda", "/dev/sdb", "/dev/sdc"] {
+            expected.extend(layout(
+                disk,
+                &[
+                    ESP,
+                    "--new=2:0:+1024M --typecode=2:fd00 --change-name=2:swap",
+                    "--new=3:0:0 --typecode=3:fd00 --change-name=3:root",
+                ],
+            ));
+        }
+        expected.push(
+            "mdadm --create /dev/md/swap --run --level=1 --metadata=1.2 --raid-devices=3 /dev/sda2 /dev/sdb2 /dev/sdc2"
+                .to_string(),
+        );
+        expected.push(
+            "mdadm --create /dev/md/root --run --level=1 --metadata=1.2 --raid-devices=3 /dev/sda3 /dev/sdb3 /dev/sdc3"
+                .to_string(),
+        );
+        assert_eq!(runner.command_lines(), expected);
+        assert_eq!(
+            paths(&partitions),
+            [
+                (PartitionRole::Esp, "/dev/sda1"),
+                (PartitionRole::Swap, "/dev/md/swap"),
+                (PartitionRole::Root, "/dev/md/root"),
+            ]
+        );
+        assert!(partitions[2].filesystem.options.is_empty());
+
+        // The arrays are stopped again in reverse order
+        mounts.release().unwrap();
+        assert_eq!(
+            runner.command_lines()[expected.len()..],
+            ["mdadm --stop /dev/md/root", "mdadm --stop /dev/md/swap"]
+        );
+    }
+
+    #[test]
+    fn btrfs_mirror_hands_the_members_to_mkfs() {
+        let runner = MockRunner::new();
+        let multi = mirror(&["/dev/sdb"], MirrorKind::Btrfs);
+        let config = config(swap_and_root(FilesystemKind::Btrfs), &multi);
+        let mut mounts = MountManager::new(&runner);
+        let partitions = partition_disks(
+            &runner,
+            &mut mounts,
+            &disk("/dev/sda", 64 << 30),
+            &[disk("/dev/sdb", 64 << 30)],
+            &config,
+            &multi,
+        )
+        .unwrap();
+
+        let parts = [
+            ESP,
+            "--new=2:0:+1024M --typecode=2:8200 --change-name=2:swap",
+            "--new=3:0:0 --typecode=3:8304 --change-name=3:root",
+        ];
+        let mut expected = layout("/dev/sda", &parts);
+        expected.extend(layout("/dev/sdb", &parts));
+        assert_eq!(runner.command_lines(), expected);
+
+        // Each disk keeps a swap partition of its own
+        assert_eq!(
+            paths(&partitions),
+            [
+                (PartitionRole::Esp, "/dev/sda1"),
+                (PartitionRole::Swap, "/dev/sda2"),
+                (PartitionRole::Root, "/dev/sda3"),
+                (PartitionRole::Swap, "/dev/sdb2"),
+            ]
+        );
+        assert_eq!(
+            partitions[2]
+                .filesystem
+                .mkfs_cmd(&partitions[2].path)
+                .to_string(),
+            "mkfs.btrfs -f -d raid1 -m raid1 /dev/sdb3 /dev/sda3"
+        );
+        mounts.release().unwrap();
+        assert_eq!(runner.command_lines().len(), expected.len());
+    }
+
+    #[test]
+    fn mdadm_conf_records_the_assembled_arrays() {
+        let runner = MockRunner::new();
+        runner.on(
+            "mdadm",
+            CommandOutput::success(
+                "ARRAY /dev/md/root metadata=1.2 UUID=3aa5c2e1:0b7d4f92:9c1e6a37:5d28b0f4\n",
+            ),
+        );
+        write_mdadm_conf(&runner, Path::new("/mnt")).unwrap();
+        stop_array(&runner, "/dev/md/root").unwrap();
+
+        assert_eq!(
+            runner.command_lines(),
+            ["mdadm --detail --scan", "mdadm --stop /dev/md/root"]
+        );
+        assert_eq!(
+            runner.written_files(),
+            [(
+                Path::new("/mnt/etc/mdadm.conf").to_path_buf(),
+                "# Arrays assembled at boot, from mdadm --detail --scan\nARRAY /dev/md/root metadata=1.2 UUID=3aa5c2e1:0b7d4f92:9c1e6a37:5d28b0f4\n".to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use crate::{
+    bootloader::{BootMode, Bootloader},
+    config::InstallConfig,
+    crypt::EncryptHook,
+    disk::{format_size, BlockDevice},
+    format::FilesystemKind,
+    gpt::{self, GptTable},
+    pacstrap,
+    partition::{partition_path, PartitionRole, BIOS_BOOT_NUMBER},
+    raid::{MirrorKind, MultiDisk},
+};
+
+// Smallest ESP that comfortably holds another system's loader next to the kernel images
+const SHARED_ESP_MIN_MIB: u64 = 300;
+
+/// Everything the install is about to do, one line each, for the final
+/// review. `config` is expected to be settled by `install::prepare`.
+pub fn summary(disk: &BlockDevice, config: &InstallConfig, mode: BootMode) -> Vec<String> {
+    let mut lines = vec!["Target disk".to_string()];
+    lines.push(format!("  {}", disk.summary()));
+    if let Some(serial) = &disk.serial {
+        lines.push(format!("  Serial {}", serial));
+    }
+    match &config.manual_partitions {
+        Some(table) => manual_table(&mut lines, disk, table, config),
+        None => automatic_layout(&mut lines, disk, config),
+    }
+
+    if config.root_filesystem() == Some(FilesystemKind::Btrfs) {
+        let subvolumes: Vec<String> = config
+            .btrfs
+            .subvolumes
+            .iter()
+            .map(|s| format!("{} on {}", s.name, s.mountpoint))
+            .collect();
+        lines.push(format!("  btrfs subvolumes: {}", subvolumes.join(", ")));
+    }
+
+    match &config.multi_disk {
+        Some(MultiDisk::SeparateHome { disk: home }) => {
+            let role = PartitionRole::Home;
+            lines.push(String::new());
+            lines.push(format!("Home disk {}", home));
+            lines.push(format!("  Everything on {} will be erased", home));
+            lines.push(format!(
+                "  {:<16}{:>14}  {:<6}{:<8}/home",
+                partition_path(home, 1),
+                "whole disk",
+                role.name(),
+                FilesystemKind::default_for(role).name()
+            ));
+        }
+        Some(MultiDisk::Mirror { disks, raid }) => {
+            lines.push(String::new());
+            lines.push(format!("Mirror ({})", raid.name()));
+            lines.push(format!(
+                "  Everything on {} will be erased and laid out like {}",
+                disks.join(", "),
+                disk.path
+            ));
+            lines.push(match raid {
+                MirrorKind::Mdadm => {
+                    "  Every partition but the ESP becomes an array /dev/md/<role>".to_string()
+                }
+                MirrorKind::Btrfs => {
+                    "  Data and metadata of every btrfs filesystem are kept on each disk, swap is used on all of them"
+                        .to_string()
+                }
+            });
+            if config.partitions.bios_boot {
+                lines.push("  GRUB goes on every disk, so any of them can boot".to_string());
+            } else {
+                lines.push(format!("  The system boots from {}", disk.path));
+            }
+        }
+        None => {}
+    }
+
+    lines.push(String::new());
+    lines.push(format!(
+        "Encryption   {}",
+        match config.encryption.as_ref().map(|e| e.hook) {
+            Some(EncryptHook::Encrypt) => "LUKS2 on root, unlocked by the encrypt hook",
+            Some(EncryptHook::SdEncrypt) => "LUKS2 on root, unlocked by the sd-encrypt hook",
+            None => "none",
+        }
+    ));
+    lines.push(format!(
+        "Bootloader   {} ({})",
+        match config.bootloader {
+            Some(Bootloader::SystemdBoot) => "systemd-boot",
+            Some(Bootloader::Grub) => "GRUB",
+            None => "not chosen",
+        },
+        match mode {
+            BootMode::Uefi => "UEFI",
+            BootMode::Bios => "BIOS",
+        }
+    ));
+
+    let packages = pacstrap::packages(config);
+    lines.push(format!(
+        "Packages     {} ({})",
+        packages.join(" "),
+        packages.len()
+    ));
+
+    lines.push(format!("Locale       {}", config.locale.locales.join(", ")));
+    lines.push(format!("Keymap       {}", config.locale.keymap));
+    lines.push(format!("Timezone     {}", config.locale.timezone));
+    lines.push(format!(
+        "Network      {}, {}",
+        config.hostname,
+        config
+            .network
+            .stack
+            .map_or("no network stack", |stack| stack.name())
+    ));
+
+    for user in &config.users {
+        let mut line = format!("User         {}", user.name);
+        if !user.groups.is_empty() {
+            line.push_str(&format!(" in {}", user.groups.join(", ")));
+        }
+        if let Some(shell) = &user.shell {
+            line.push_str(&format!(", shell {}", shell));
+        }
+        lines.push(line);
+    }
+    if config.sudo_wheel {
+        lines.push("sudo         enabled for wheel".to_string());
+    }
+    lines.push(format!(
+        "Root         {}",
+        match config.root_password_hash {
+            Some(_) => "password set",
+            None => "locked",
+        }
+    ));
+    lines
+}
+
+// The whole disk is wiped and laid out from the partition plan
+fn automatic_layout(lines: &mut Vec<String>, disk: &BlockDevice, config: &InstallConfig) {
+    lines.push(format!(
+        "  Everything on {} ({}) will be erased",
+        disk.path,
+        format_size(disk.size)
+    ));
+    if !disk.partitions.is_empty() {
+        let existing: Vec<&str> = disk.partitions.iter().map(|p| p.name.as_str()).collect();
+        lines.push(format!("  Existing partitions: {}", existing.join(", ")));
+    }
+
+    lines.push(String::new());
+    lines.push("Partition table (GPT)".to_string());
+    let sizes = config.partitions.sizes_mib(disk.size);
+    for (i, (spec, size)) in config.partitions.partitions.iter().zip(sizes).enumerate() {
+        let mut line = format!(
+            "  {:<16}{:>10} MiB  {:<6}{:<8}",
+            partition_path(&disk.path, i as u32 + 1),
+            size,
+            spec.role.name(),
+            spec.filesystem.kind.name()
+        );
+        if spec.role == PartitionRole::Root && config.encryption.is_some() {
+            line.push_str("LUKS2  ");
+        }
+        line.push_str(spec.role.mountpoint().unwrap_or("swap"));
+        lines.push(line);
+    }
+    if config.partitions.bios_boot {
+        lines.push(format!(
+            "  {:<16}{:>10} MiB  BIOS boot partition for GRUB",
+            partition_path(&disk.path, BIOS_BOOT_NUMBER),
+            "<1"
+        ));
+    }
+}
+
+// Only what the editor changed happens, everything else stays as it is
+fn manual_table(
+    lines: &mut Vec<String>,
+    disk: &BlockDevice,
+    table: &GptTable,
+    config: &InstallConfig,
+) {
+    lines.push(format!(
+        "  The partition table of {} is rewritten, partitions not marked as removed or formatted keep their data",
+        disk.path
+    ));
+
+    lines.push(String::new());
+    lines.push("Partition table (GPT)".to_string());
+    for entry in &table.entries {
+        let mut line = format!(
+            "  {:<16}{:>10} MiB  {:<22}",
+            partition_path(&disk.path, entry.number),
+            table.mib(entry.extent.sectors()),
+            gpt::type_name(&entry.type_guid)
+        );
+        match (&entry.format, entry.fstype.as_deref()) {
+            (Some(spec), _) => line.push_str(&format!("format {:<8}", spec.kind.name())),
+            (None, Some(fstype)) => line.push_str(&format!("keep {:<10}", fstype)),
+            (None, None) => line.push_str(&format!("{:<15}", "")),
+        }
+        if entry.role == Some(PartitionRole::Root) && config.encryption.is_some() {
+            line.push_str("LUKS2  ");
+        }
+        match entry.role {
+            Some(role) => line.push_str(role.mountpoint().unwrap_or("swap")),
+            None => line.push_str("not used"),
+        }
+        if entry.existing.is_none() {
+            line.push_str(", new");
+        } else if entry.existing != Some(entry.extent) {
+            line.push_str(", resized");
+        }
+        lines.push(line);
+    }
+    if let Some(esp) = table.shared_esp() {
+        let size = table.mib(esp.extent.sectors());
+        lines.push(format!(
+            "  {} is the existing EFI system partition, shared with the other systems",
+            partition_path(&disk.path, esp.number)
+        ));
+        // Kernels and initramfs images live on the ESP
+        if size < SHARED_ESP_MIN_MIB {
+            lines.push(format!(
+                "  Warning: it has only {} MiB, make sure about 150 MiB are free for the kernel and initramfs images",
+                size
+            ));
+        }
+    }
+    for entry in &table.removed {
+        lines.push(format!(
+            "  {:<16}{:>10} MiB  removed, its data is lost",
+            partition_path(&disk.path, entry.number),
+            table.mib(entry.extent.sectors())
+        ));
+    }
+}
+
+/// Whether `typed` confirms erasing `disk`: exactly its device path, its
+/// kernel name or YES, so a stray character never counts as consent.
+pub fn confirms(typed: &str, disk: &BlockDevice) -> bool {
+    typed == "YES" || typed == disk.path || typed == disk.name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{disk::tests::disk, gpt};
+
+    #[test]
+    fn only_the_exact_disk_or_yes_confirms() {
+        let sda = disk("/dev/sda", 64 << 30);
+        for typed in ["YES", "/dev/sda", "sda"] {
+            assert!(confirms(typed, &sda), "{:?}", typed);
+        }
+        for typed in [
+            "yes",
+            "Yes",
+            "YES ",
+            " sda",
+            "/dev/sda ",
+            "sdb",
+            "/dev/sdb",
+            "",
+        ] {
+            assert!(!confirms(typed, &sda), "{:?}", typed);
+        }
+    }
+
+    #[test]
+    fn every_erased_disk_is_listed() {
+        let sda = disk("/dev/sda", 64 << 30);
+        let mut config = InstallConfig {
+            multi_disk: Some(MultiDisk::Mirror {
+                disks: vec!["/dev/sdb".to_string(), "/dev/sdc".to_string()],
+                raid: MirrorKind::Mdadm,
+            }),
+            ..InstallConfig::default()
+        };
+        let lines = summary(&sda, &config, BootMode::Uefi);
+        assert!(lines.contains(&"  Everything on /dev/sda (68.7 GB) will be erased".to_string()));
+        assert!(lines.contains(
+            &"  Everything on /dev/sdb, /dev/sdc will be erased and laid out like /dev/sda"
+                .to_string()
+        ));
+
+        config.multi_disk = Some(MultiDisk::SeparateHome {
+            disk: "/dev/sdb".to_string(),
+        });
+        let lines = summary(&sda, &config, BootMode::Uefi);
+        assert!(lines.contains(&"  Everything on /dev/sdb will be erased".to_string()));
+    }
+
+    #[test]
+    fn manual_tables_show_removed_and_resized_partitions() {
+        let (mut table, nvme) = gpt::tests::dual_boot();
+        table.delete(4).unwrap();
+        table.resize(3, 100 * 1024).unwrap();
+        let config = InstallConfig {
+            manual_partitions: Some(table),
+            ..InstallConfig::default()
+        };
+        let lines = summary(&nvme, &config, BootMode::Uefi);
+
+        let line = |prefix: &str| {
+            lines
+                .iter()
+                .find(|line| line.starts_with(prefix))
+                .unwrap_or_else(|| panic!("no line for {:?} in {:#?}", prefix, lines))
+                .clone()
+        };
+        assert!(line("  /dev/nvme0n1p3 ").ends_with(", resized"));
+        assert!(line("  /dev/nvme0n1p4 ").ends_with("removed, its data is lost"));
+        assert!(!line("  /dev/nvme0n1p1 ").contains("resized"));
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    disk::BlockDevice,
+    error::{InstallerError, Result},
+    fstab::unescape,
+};
+
+// Where archiso mounts the medium it booted from
+const ARCHISO_BOOTMNT: &str = "/run/archiso/bootmnt";
+
+/// Why installing onto a disk would break the running system or lose data in use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hazard {
+    // The live medium the installer itself runs from
+    BootMedium,
+    Mounted { device: String, mountpoint: String },
+    Swap(String),
+    LvmMember(String),
+    RaidMember(String),
+    ReadOnly,
+}
+
+impl Hazard {
+    /// Hazards an expert may knowingly override. A read-only disk can never be written.
+    pub fn overridable(&self) -> bool {
+        !matches!(self, Hazard::ReadOnly)
+    }
+}
+
+impl fmt::Display for Hazard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Hazard::BootMedium => write!(f, "live boot medium"),
+            Hazard::Mounted { device, mountpoint } => {
+                write!(f, "{} mounted on {}", device, mountpoint)
+            }
+            Hazard::Swap(device) => write!(f, "{} is active swap", device),
+            Hazard::LvmMember(device) => write!(f, "{} is an LVM physical volume", device),
+            Hazard::RaidMember(device) => write!(f, "{} is a RAID member", device),
+            Hazard::ReadOnly => write!(f, "read-only"),
+        }
+    }
+}
+
+/// What the running system is using, read from the mount table, the active
+/// swap list and archiso's boot mount below `root`.
+#[derive(Debug, Clone, Default)]
+pub struct Safety {
+    // Device the live medium is mounted from
+    boot_device: Option<String>,
+    // (device, mountpoint) pairs from the mount table
+    mounts: Vec<(String, String)>,
+    swaps: Vec<String>,
+}
+
+impl Safety {
+    /// Probe the system rooted at `root`, normally `/`. A fixture tree only
+    /// needs proc/self/mounts and optionally proc/swaps.
+    pub fn probe(root: &Path) -> Result<Safety> {
+        let mounts = parse_mounts(&fs::read_to_string(root.join("proc/self/mounts"))?);
+        // Without swap support there is no proc/swaps
+        let swaps = fs::read_to_string(root.join("proc/swaps"))
+            .map(|text| parse_swaps(&text))
+            .unwrap_or_default();
+
+        let boot_device = mounts
+            .iter()
+            .find(|(_, mountpoint)| mountpoint == ARCHISO_BOOTMNT)
+            .map(|(device, _)| resolve(root, device));
+
+        Ok(Safety {
+            boot_device,
+            mounts,
+            swaps,
+        })
+    }
+
+    pub fn boot_device(&self) -> Option<&str> {
+        self.boot_device.as_deref()
+    }
+
+    /// Everything in use on `disk` or anything stacked on it.
+    pub fn hazards(&self, disk: &BlockDevice) -> Vec<Hazard> {
+        let mut hazards = Vec::new();
+        if disk.read_only {
+            hazards.push(Hazard::ReadOnly);
+        }
+
+        for device in disk.flatten() {
+            if self.boot_device.as_deref() == Some(device.path.as_str()) {
+                hazards.push(Hazard::BootMedium);
+            }
+
+            let mountpoints: Vec<&str> = device
+                .mountpoints
+                .iter()
+                .map(String::as_str)
+                .chain(
+                    self.mounts
+                        .iter()
+                        .filter(|(source, _)| *source == device.path)
+                        .map(|(_, mountpoint)| mountpoint.as_str()),
+                )
+                .collect();
+            for mountpoint in mountpoints {
+                if mountpoint == "[SWAP]" {
+                    continue;
+                }
+                let hazard = Hazard::Mounted {
+                    device: device.path.clone(),
+                    mountpoint: mountpoint.to_string(),
+                };
+                if !hazards.contains(&hazard) {
+                    hazards.push(hazard);
+                }
+            }
+
+            if device.mountpoints.iter().any(|m| m == "[SWAP]") || self.swaps.contains(&device.path)
+            {
+                hazards.push(Hazard::Swap(device.path.clone()));
+            }
+
+            match device.fstype.as_deref() {
+                Some("LVM2_member") => hazards.push(Hazard::LvmMember(device.path.clone())),
+                Some("linux_raid_member") => hazards.push(Hazard::RaidMember(device.path.clone())),
+                _ => {}
+            }
+        }
+        hazards
+    }
+
+    /// The hazards that rule out `disk`, `allow_unsafe` drops the overridable ones.
+    pub fn blocking(&self, disk: &BlockDevice, allow_unsafe: bool) -> Vec<Hazard> {
+        self.hazards(disk)
+            .into_iter()
+            .filter(|h| !(allow_unsafe && h.overridable()))
+            .collect()
+    }
+
+    /// Refuse `disk` if anything on it is in use, unless `allow_unsafe` is set
+    /// and every hazard can be overridden.
+    pub fn check(&self, disk: &BlockDevice, allow_unsafe: bool) -> Result<()> {
+        let blocking = self.blocking(disk, allow_unsafe);
+        if blocking.is_empty() {
+            return Ok(());
+        }
+        Err(InstallerError::PreconditionFailed(format!(
+            "Refusing to install onto {}: {}",
+            disk.path,
+            describe(&blocking)
+        )))
+    }
+}
+
+/// Hazards as one comma separated line.
+pub fn describe(hazards: &[Hazard]) -> String {
+    hazards
+        .iter()
+        .map(Hazard::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// (device, mountpoint) pairs of a /proc/mounts style table
+fn parse_mounts(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?;
+            let mountpoint = fields.next()?;
+            Some((unescape(device), unescape(mountpoint)))
+        })
+        .collect()
+}
+
+// Device paths from /proc/swaps, skipping the header and swap files
+fn parse_swaps(text: &str) -> Vec<String> {
+    text.lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(unescape)
+        .filter(|device| device.starts_with("/dev/"))
+        .collect()
+}
+
+// Follow /dev/disk/by-* links so the device matches lsblk's PATH
+fn resolve(root: &Path, device: &str) -> String {
+    let path = root.join(device.trim_start_matches('/'));
+    match fs::canonicalize(&path) {
+        Ok(resolved) => {
+            let relative = resolved.strip_prefix(root).unwrap_or(&resolved);
+            PathBuf::from("/")
+                .join(relative)
+                .to_string_lossy()
+                .into_owned()
+        }
+        Err(_) => device.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{disk::tests::disk, testing::TempDir};
+
+    const MOUNTS: &str = include_str!("../tests/fixtures/archiso-mounts");
+    const SWAPS: &str = include_str!("../tests/fixtures/archiso-swaps");
+
+    // A live system booted from the USB stick sdd, with its by-label link
+    fn live_system() -> (TempDir, Safety) {
+        let root = TempDir::new();
+        root.write("proc/self/mounts", MOUNTS);
+        root.write("proc/swaps", SWAPS);
+        let sdd1 = root.write("dev/sdd1", "");
+        let link = root.mkdir("dev/disk/by-label").join("ARCH_202410");
+        std::os::unix::fs::symlink(sdd1, link).unwrap();
+
+        let safety = Safety::probe(&fs::canonicalize(root.path()).unwrap()).unwrap();
+        (root, safety)
+    }
+
+    // `path` with a single partition `part`
+    fn disk_with(path: &str, part: BlockDevice) -> BlockDevice {
+        let mut device = disk(path, 64 << 30);
+        device.partitions.push(part);
+        device
+    }
+
+    fn partition(path: &str, fstype: Option<&str>) -> BlockDevice {
+        let mut part = disk(path, 1 << 30);
+        part.device_type = "part".to_string();
+        part.fstype = fstype.map(str::to_string);
+        part
+    }
+
+    #[test]
+    fn mount_table_fields_are_unescaped() {
+        assert_eq!(unescape("/mnt/my\\040backup"), "/mnt/my backup");
+        assert_eq!(unescape("a\\011b\\012c"), "a\tb\nc");
+        assert_eq!(unescape("back\\134slash"), "back\\slash");
+        // Anything that is not a three digit octal escape stays as it is
+        assert_eq!(unescape("odd\\9xx\\"), "odd\\9xx\\");
+        assert_eq!(unescape("\\04"), "\\04");
+
+        let mounts = parse_mounts(MOUNTS);
+        assert_eq!(mounts.len(), 10);
+        assert!(mounts.contains(&("/dev/sdb1".to_string(), "/mnt/my backup".to_string())));
+        assert_eq!(parse_swaps(SWAPS), ["/dev/sda3"]);
+    }
+
+    #[test]
+    fn boot_medium_is_found_through_its_link() {
+        let (_root, safety) = live_system();
+        assert_eq!(safety.boot_device(), Some("/dev/sdd1"));
+
+        let stick = disk_with("/dev/sdd", partition("/dev/sdd1", Some("iso9660")));
+        // The mount table names it by label, the link is only followed to find the medium
+        assert_eq!(safety.hazards(&stick), [Hazard::BootMedium]);
+        // The medium stays mounted for the whole session, so only experts may erase it
+        assert!(safety.check(&stick, false).is_err());
+        assert!(safety.check(&stick, true).is_ok());
+    }
+
+    #[test]
+    fn hazards_cover_everything_in_use() {
+        let (_root, safety) = live_system();
+        let hazards = |disk: &BlockDevice| describe(&safety.hazards(disk));
+
+        let backup = disk_with("/dev/sdb", partition("/dev/sdb1", Some("ext4")));
+        assert_eq!(hazards(&backup), "/dev/sdb1 mounted on /mnt/my backup");
+        let swap = disk_with("/dev/sda", partition("/dev/sda3", Some("swap")));
+        assert_eq!(hazards(&swap), "/dev/sda3 is active swap");
+        let mut lsblk_swap = partition("/dev/sdc2", Some("swap"));
+        lsblk_swap.mountpoints = vec!["[SWAP]".to_string()];
+        assert_eq!(
+            hazards(&disk_with("/dev/sdc", lsblk_swap)),
+            "/dev/sdc2 is active swap"
+        );
+        let raid = disk_with(
+            "/dev/sde",
+            partition("/dev/sde1", Some("linux_raid_member")),
+        );
+        assert_eq!(hazards(&raid), "/dev/sde1 is a RAID member");
+        let lvm = disk_with("/dev/sdf", partition("/dev/sdf1", Some("LVM2_member")));
+        assert_eq!(hazards(&lvm), "/dev/sdf1 is an LVM physical volume");
+
+        let spare = disk_with("/dev/sdg", partition("/dev/sdg1", Some("ext4")));
+        assert!(safety.hazards(&spare).is_empty());
+        safety.check(&spare, false).unwrap();
+    }
+
+    #[test]
+    fn read_only_disks_are_refused_even_for_experts() {
+        let (_root, safety) = live_system();
+        let mut backup = disk_with("/dev/sdb", partition("/dev/sdb1", Some("ext4")));
+        backup.read_only = true;
+
+        assert_eq!(safety.blocking(&backup, true), [Hazard::ReadOnly]);
+        assert_eq!(
+            safety.check(&backup, false).unwrap_err().to_string(),
+            "Refusing to install onto /dev/sdb: read-only, /dev/sdb1 mounted on /mnt/my backup"
+        );
+    }
+
+    #[test]
+    fn probe_works_without_swap_support() {
+        let root = TempDir::new();
+        root.write("proc/self/mounts", "/dev/sda2 / ext4 rw 0 0\n");
+        let safety = Safety::probe(root.path()).unwrap();
+        assert_eq!(safety.boot_device(), None);
+        let sda = disk_with("/dev/sda", partition("/dev/sda2", Some("ext4")));
+        assert_eq!(safety.hazards(&sda).len(), 1);
+
+        assert!(Safety::probe(&root.path().join("missing")).is_err());
+    }
+}
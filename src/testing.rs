@@ -0,0 +1,102 @@
+use std::{
+    env,
+    fs::{self, File},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::command::{Cmd, CommandRunner, SystemRunner};
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Scratch directory for tests that need real files, removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("archinstaller-test-{}-{}", process::id(), n));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    // Create `relative` with `contents`, along with its parent directories
+    pub fn write(&self, relative: &str, contents: &str) -> PathBuf {
+        let path = self.0.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    pub fn mkdir(&self, relative: &str) -> PathBuf {
+        let path = self.0.join(relative);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Whether an ignored test that works on real devices can run here: as root
+/// and with every one of `programs` on the PATH. Says why when it cannot.
+pub fn privileged(programs: &[&str]) -> bool {
+    if fs::metadata("/proc/self").map_or(true, |proc| proc.uid() != 0) {
+        eprintln!("skipped, needs root");
+        return false;
+    }
+    let path = env::var_os("PATH").unwrap_or_default();
+    for program in programs {
+        if !env::split_paths(&path).any(|dir| dir.join(program).is_file()) {
+            eprintln!("skipped, {} is not installed", program);
+            return false;
+        }
+    }
+    true
+}
+
+/// Sparse image of `size` bytes attached as a loop device with partition
+/// scanning, detached again when dropped. Only for tests gated on `privileged`.
+pub struct LoopDevice {
+    path: String,
+    // Holds the image until the device is detached
+    _dir: TempDir,
+}
+
+impl LoopDevice {
+    pub fn new(size: u64) -> Self {
+        let dir = TempDir::new();
+        let image = dir.path().join("disk.img");
+        File::create(&image).unwrap().set_len(size).unwrap();
+        let output = SystemRunner
+            .run_checked(
+                &Cmd::new("losetup")
+                    .args(["-fP", "--show"])
+                    .arg(image.to_string_lossy()),
+            )
+            .unwrap();
+        LoopDevice {
+            path: output.stdout.trim().to_string(),
+            _dir: dir,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        let _ = SystemRunner.run(&Cmd::new("losetup").args(["-d", &self.path]));
+    }
+}
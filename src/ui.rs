@@ -0,0 +1,369 @@
+use std::{collections::VecDeque, io, time::Duration};
+
+use crossterm::event::{self, Event, KeyCode};
+use tui::{
+    backend::Backend,
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    style::{Color, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
+    Terminal,
+};
+
+use crate::{
+    error::{InstallerError, Result},
+    install::Progress,
+};
+
+pub mod partition_editor;
+pub mod select_list;
+
+pub use partition_editor::PartitionEditor;
+pub use select_list::SelectList;
+
+// Rectangle of the given percentage size centered inside `area`
+pub fn centered_rect(percent_x: u16, percent_y: u16, area: Rect) -> Rect {
+    let vertical = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Percentage((100 - percent_y) / 2),
+                Constraint::Percentage(percent_y),
+                Constraint::Percentage((100 - percent_y) / 2),
+            ]
+            .as_ref(),
+        )
+        .split(area);
+
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Percentage((100 - percent_x) / 2),
+                Constraint::Percentage(percent_x),
+                Constraint::Percentage((100 - percent_x) / 2),
+            ]
+            .as_ref(),
+        )
+        .split(vertical[1])[1]
+}
+
+/// Show `err` in a modal dialog on top of the current screen until a key is pressed.
+pub fn show_error<B: Backend>(terminal: &mut Terminal<B>, err: &InstallerError) -> io::Result<()> {
+    let message = format!("{}\n\nPress any key to continue", err);
+
+    terminal.draw(|f| {
+        let area = centered_rect(60, 30, f.size());
+
+        let dialog = Paragraph::new(message.as_str())
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(err.title())
+                    .border_style(Style::default().fg(Color::Red)),
+            )
+            .alignment(Alignment::Center)
+            .wrap(Wrap { trim: true });
+
+        // Clear whatever is underneath so the dialog is readable
+        f.render_widget(Clear, area);
+        f.render_widget(dialog, area);
+    })?;
+
+    // Wait for a key press to dismiss
+    loop {
+        if let Event::Key(_) = event::read()? {
+            return Ok(());
+        }
+    }
+}
+
+/// Ask a yes/no question, Esc cancels.
+pub fn confirm<B: Backend>(
+    terminal: &mut Terminal<B>,
+    title: &str,
+    question: &str,
+) -> Result<bool> {
+    let message = format!("{}\n\n[y] Yes    [n] No", question);
+
+    loop {
+        terminal.draw(|f| {
+            let area = centered_rect(60, 30, f.size());
+            let dialog = Paragraph::new(message.as_str())
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .alignment(Alignment::Center)
+                .wrap(Wrap { trim: true });
+
+            f.render_widget(Clear, area);
+            f.render_widget(dialog, area);
+        })?;
+
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Char('y') | KeyCode::Char('Y') => return Ok(true),
+                KeyCode::Char('n') | KeyCode::Char('N') => return Ok(false),
+                KeyCode::Esc => return Err(InstallerError::Cancelled),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Single line text entry. With `masked` every character is drawn as `*`.
+pub fn input<B: Backend>(
+    terminal: &mut Terminal<B>,
+    title: &str,
+    prompt: &str,
+    masked: bool,
+    error: Option<&str>,
+) -> Result<String> {
+    text_entry(terminal, title, prompt, String::new(), masked, error)
+}
+
+/// Single line text entry starting out with `initial`.
+pub fn edit<B: Backend>(
+    terminal: &mut Terminal<B>,
+    title: &str,
+    prompt: &str,
+    initial: &str,
+    error: Option<&str>,
+) -> Result<String> {
+    text_entry(terminal, title, prompt, initial.to_string(), false, error)
+}
+
+fn text_entry<B: Backend>(
+    terminal: &mut Terminal<B>,
+    title: &str,
+    prompt: &str,
+    mut value: String,
+    masked: bool,
+    error: Option<&str>,
+) -> Result<String> {
+    loop {
+        terminal.draw(|f| {
+            let area = centered_rect(60, 30, f.size());
+            let shown = if masked {
+                "*".repeat(value.chars().count())
+            } else {
+                value.clone()
+            };
+
+            let mut lines = vec![
+                Spans::from(prompt),
+                Spans::from(""),
+                Spans::from(format!("> {}_", shown)),
+            ];
+            if let Some(error) = error {
+                lines.push(Spans::from(""));
+                lines.push(Spans::from(Span::styled(
+                    error,
+                    Style::default().fg(Color::Red),
+                )));
+            }
+
+            let dialog = Paragraph::new(lines)
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .wrap(Wrap { trim: false });
+
+            f.render_widget(Clear, area);
+            f.render_widget(dialog, area);
+        })?;
+
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Char(c) => value.push(c),
+                KeyCode::Backspace => {
+                    value.pop();
+                }
+                KeyCode::Enter => return Ok(value),
+                KeyCode::Esc => return Err(InstallerError::Cancelled),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Ask for a new passphrase twice until both entries match and are not empty.
+pub fn passphrase<B: Backend>(terminal: &mut Terminal<B>, title: &str) -> Result<String> {
+    let mut error = None;
+
+    loop {
+        let first = input(terminal, title, "Enter passphrase", true, error)?;
+        if first.is_empty() {
+            error = Some("The passphrase cannot be empty");
+            continue;
+        }
+
+        let second = input(terminal, title, "Repeat passphrase", true, None)?;
+        if first == second {
+            return Ok(first);
+        }
+        error = Some("The passphrases did not match, try again");
+    }
+}
+
+/// Show `lines` full screen above a text entry and only return once the typed
+/// text satisfies `accept`. Up/Down and PageUp/PageDown scroll, Esc cancels.
+pub fn typed_confirmation<B: Backend>(
+    terminal: &mut Terminal<B>,
+    title: &str,
+    lines: &[String],
+    prompt: &str,
+    accept: impl Fn(&str) -> bool,
+) -> Result<()> {
+    let mut typed = String::new();
+    let mut scroll: u16 = 0;
+    let mut error = None;
+
+    loop {
+        let mut page = 0;
+        terminal.draw(|f| {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(3), Constraint::Length(4)].as_ref())
+                .split(f.size());
+            page = chunks[0].height.saturating_sub(2);
+
+            let text: Vec<Spans> = lines
+                .iter()
+                .map(|line| Spans::from(line.as_str()))
+                .collect();
+            let review = Paragraph::new(text)
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .wrap(Wrap { trim: false })
+                .scroll((scroll, 0));
+            f.render_widget(review, chunks[0]);
+
+            let mut entry = vec![Spans::from(format!("{} > {}_", prompt, typed))];
+            if let Some(error) = error {
+                entry.push(Spans::from(Span::styled(
+                    error,
+                    Style::default().fg(Color::Red),
+                )));
+            }
+            let entry = Paragraph::new(entry).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(Style::default().fg(Color::Red)),
+            );
+            f.render_widget(entry, chunks[1]);
+        })?;
+
+        let last = (lines.len() as u16).saturating_sub(page);
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Up => scroll = scroll.saturating_sub(1),
+                KeyCode::Down => scroll = (scroll + 1).min(last),
+                KeyCode::PageUp => scroll = scroll.saturating_sub(page),
+                KeyCode::PageDown => scroll = (scroll + page).min(last),
+                KeyCode::Char(c) => typed.push(c),
+                KeyCode::Backspace => {
+                    typed.pop();
+                }
+                KeyCode::Enter if accept(&typed) => return Ok(()),
+                KeyCode::Enter => {
+                    error = Some("That does not match, nothing has been changed");
+                    typed.clear();
+                }
+                KeyCode::Esc => return Err(InstallerError::Cancelled),
+                _ => {}
+            }
+        }
+    }
+}
+
+// Lines of command output kept for the log pane
+const LOG_LINES: usize = 500;
+
+/// Shows which install stage is running above a scrolling log of command
+/// output, and lets Esc cancel between steps.
+pub struct InstallScreen<'t, B: Backend> {
+    terminal: &'t mut Terminal<B>,
+    stages: Vec<String>,
+    log: VecDeque<String>,
+}
+
+impl<'t, B: Backend> InstallScreen<'t, B> {
+    pub fn new(terminal: &'t mut Terminal<B>) -> Self {
+        InstallScreen {
+            terminal,
+            stages: Vec::new(),
+            log: VecDeque::new(),
+        }
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        let stages = &self.stages;
+        let log = &self.log;
+        self.terminal.draw(|f| {
+            // Same split as the main menu, the log lives in the bottom chunk
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Percentage(80), Constraint::Percentage(20)].as_ref())
+                .split(f.size());
+
+            let last = stages.len().saturating_sub(1);
+            let lines: Vec<Spans> = stages
+                .iter()
+                .enumerate()
+                .map(|(i, stage)| {
+                    if i == last {
+                        Spans::from(Span::styled(
+                            format!(">> {}...", stage),
+                            Style::default().fg(Color::Yellow),
+                        ))
+                    } else {
+                        Spans::from(format!("   {} done", stage))
+                    }
+                })
+                .collect();
+
+            let block = Paragraph::new(lines).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Installing (Esc to cancel)"),
+            );
+            f.render_widget(block, chunks[0]);
+
+            // Only the newest lines that fit inside the borders
+            let visible = chunks[1].height.saturating_sub(2) as usize;
+            let tail: Vec<Spans> = log
+                .iter()
+                .skip(log.len().saturating_sub(visible))
+                .map(|line| Spans::from(line.as_str()))
+                .collect();
+            let pane =
+                Paragraph::new(tail).block(Block::default().borders(Borders::ALL).title("Log"));
+            f.render_widget(pane, chunks[1]);
+        })?;
+        Ok(())
+    }
+}
+
+impl<B: Backend> Progress for InstallScreen<'_, B> {
+    fn stage(&mut self, name: &str) {
+        self.stages.push(name.to_string());
+        // Progress display is best effort, the install carries on regardless
+        let _ = self.draw();
+    }
+
+    fn log(&mut self, line: &str) {
+        if self.log.len() == LOG_LINES {
+            self.log.pop_front();
+        }
+        self.log.push_back(line.to_string());
+        let _ = self.draw();
+    }
+
+    fn cancelled(&mut self) -> bool {
+        // Drain pending input without blocking
+        while let Ok(true) = event::poll(Duration::ZERO) {
+            if let Ok(Event::Key(key)) = event::read() {
+                if key.code == KeyCode::Esc {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,436 @@
+use crossterm::event::{self, Event, KeyCode};
+use tui::{
+    backend::Backend,
+    layout::{Constraint, Direction, Layout},
+    text::Spans,
+    widgets::{Block, Borders, Paragraph, Wrap},
+    Terminal,
+};
+
+use super::{select_list::Action, SelectList};
+use crate::{
+    disk::{format_size, BlockDevice},
+    error::{InstallerError, Result},
+    format::{FilesystemKind, FilesystemSpec},
+    gpt::{self, Extent, Flag, GptTable, PartitionType},
+    partition::{partition_path, PartitionRole},
+};
+
+// A line of the table view
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Row {
+    Partition(u32),
+    Free(Extent),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    New,
+    Delete,
+    Resize,
+    Type,
+    Name,
+    Flags,
+    Use,
+    Done,
+    Discard,
+}
+
+impl Command {
+    fn key(self) -> char {
+        match self {
+            Command::New => 'n',
+            Command::Delete => 'd',
+            Command::Resize => 'r',
+            Command::Type => 't',
+            Command::Name => 'l',
+            Command::Flags => 'f',
+            Command::Use => 'u',
+            Command::Done => 'w',
+            Command::Discard => 'q',
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Command::New => "New partition in this free space",
+            Command::Delete => "Delete",
+            Command::Resize => "Resize",
+            Command::Type => "Change the type",
+            Command::Name => "Change the name (label)",
+            Command::Flags => "Toggle flags",
+            Command::Use => "Use as mount point and filesystem",
+            Command::Done => "Done, use this table",
+            Command::Discard => "Discard all changes",
+        }
+    }
+
+    // What can be done on `row`
+    fn on(row: Option<Row>) -> Vec<Command> {
+        let mut commands = match row {
+            Some(Row::Free(_)) => vec![Command::New],
+            Some(Row::Partition(_)) => vec![
+                Command::Use,
+                Command::Resize,
+                Command::Type,
+                Command::Name,
+                Command::Flags,
+                Command::Delete,
+            ],
+            None => Vec::new(),
+        };
+        commands.extend([Command::Done, Command::Discard]);
+        commands
+    }
+}
+
+/// cfdisk style editor for the partition table of one disk, working on a
+/// `GptTable` in memory. The disk itself is only written by the install.
+///
+/// Enter opens the actions for the row under the cursor, each of them also
+/// has a single key shortcut. Esc discards the changes.
+pub struct PartitionEditor<'d> {
+    disk: &'d BlockDevice,
+    table: GptTable,
+    // Table the editor started with, to tell if anything changed
+    initial: GptTable,
+}
+
+impl<'d> PartitionEditor<'d> {
+    pub fn new(disk: &'d BlockDevice, table: GptTable) -> Self {
+        PartitionEditor {
+            disk,
+            initial: table.clone(),
+            table,
+        }
+    }
+
+    fn rows(&self) -> Vec<Row> {
+        let mut rows: Vec<(u64, Row)> = self
+            .table
+            .entries
+            .iter()
+            .map(|e| (e.extent.first_lba, Row::Partition(e.number)))
+            .chain(
+                self.table
+                    .free_regions()
+                    .into_iter()
+                    .map(|r| (r.first_lba, Row::Free(r))),
+            )
+            .collect();
+        rows.sort_by_key(|(lba, _)| *lba);
+        rows.into_iter().map(|(_, row)| row).collect()
+    }
+
+    fn label(&self, row: &Row) -> String {
+        let table = &self.table;
+        match row {
+            Row::Free(region) => format!(
+                "{:<16}{:>10}  Free space",
+                "",
+                format_size(region.sectors() * table.sector_size)
+            ),
+            Row::Partition(number) => {
+                let Ok(entry) = table.entry(*number) else {
+                    return String::new();
+                };
+                let mut label = format!(
+                    "{:<16}{:>10}  {:<22}{:<12}",
+                    partition_path(&self.disk.path, entry.number),
+                    format_size(entry.extent.sectors() * table.sector_size),
+                    gpt::type_name(&entry.type_guid),
+                    entry.name
+                );
+                let mountpoint = entry.role.map(|r| r.mountpoint().unwrap_or("swap"));
+                match (mountpoint, &entry.format, entry.fstype.as_deref()) {
+                    (Some(mountpoint), Some(spec), _) => {
+                        label.push_str(&format!("{} format {}", mountpoint, spec.kind.name()))
+                    }
+                    (Some(mountpoint), None, fstype) => {
+                        label.push_str(&format!("{} keep {}", mountpoint, fstype.unwrap_or("?")))
+                    }
+                    (None, _, Some(fstype)) => label.push_str(fstype),
+                    (None, _, None) => {}
+                }
+                let flags: Vec<&str> = Flag::ALL
+                    .iter()
+                    .filter(|&&f| entry.has_flag(f))
+                    .map(|f| f.name())
+                    .collect();
+                if !flags.is_empty() {
+                    label.push_str(&format!("  [{}]", flags.join(", ")));
+                }
+                label
+            }
+        }
+    }
+
+    fn help(&self) -> Vec<String> {
+        let mut lines = vec![self.disk.summary()];
+        let free: u64 = self.table.free_regions().iter().map(Extent::sectors).sum();
+        lines.push(format!(
+            "{} partitions, {} unallocated",
+            self.table.entries.len(),
+            format_size(free * self.table.sector_size)
+        ));
+        for entry in &self.table.removed {
+            lines.push(format!(
+                "{} is removed when the table is written",
+                partition_path(&self.disk.path, entry.number)
+            ));
+        }
+        lines.push(String::new());
+        let keys: Vec<String> = [
+            Command::New,
+            Command::Delete,
+            Command::Resize,
+            Command::Type,
+            Command::Name,
+            Command::Flags,
+            Command::Use,
+            Command::Done,
+        ]
+        .iter()
+        .map(|c| format!("{} {}", c.key(), c.label().to_lowercase()))
+        .collect();
+        lines.push(format!("Enter: actions   {}", keys.join("   ")));
+        lines.push("Nothing is written to the disk until the install starts".to_string());
+        lines
+    }
+
+    /// Edit until the user is done, returning the new table. Discarding the
+    /// changes fails with `InstallerError::Cancelled`.
+    pub fn run<B: Backend>(mut self, terminal: &mut Terminal<B>) -> Result<GptTable> {
+        let title = format!("Partition table of {}", self.disk.path);
+        let mut cursor = 0;
+        loop {
+            // Stay on the same line after the table changed
+            let rows = self.rows();
+            let current = rows.get(cursor.min(rows.len().saturating_sub(1))).copied();
+            let mut list = SelectList::new(title.as_str(), rows, |row| self.label(row))
+                .select_where(|row| Some(*row) == current);
+            let help = self.help();
+
+            let command = loop {
+                terminal.draw(|f| {
+                    let chunks = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints([Constraint::Min(5), Constraint::Length(9)].as_ref())
+                        .split(f.size());
+                    list.render(f, chunks[0]);
+                    let lines: Vec<Spans> = help.iter().cloned().map(Spans::from).collect();
+                    let pane = Paragraph::new(lines)
+                        .block(Block::default().borders(Borders::ALL))
+                        .wrap(Wrap { trim: false });
+                    f.render_widget(pane, chunks[1]);
+                })?;
+
+                let event = event::read()?;
+                let row = list.selected().copied();
+                let shortcut = match &event {
+                    Event::Key(key) if !list.searching() => Command::on(row)
+                        .into_iter()
+                        .find(|c| key.code == KeyCode::Char(c.key())),
+                    _ => None,
+                };
+                if shortcut.is_some() {
+                    break shortcut;
+                }
+                match list.handle_event(&event) {
+                    Some(Action::Confirm) => break None,
+                    Some(Action::Cancel) => break Some(Command::Discard),
+                    None => {}
+                }
+            };
+            cursor = list.selected_index().unwrap_or(0);
+            let row = list.selected().copied();
+
+            let command = match command {
+                Some(command) => command,
+                // Enter shows what can be done on the row
+                None => match SelectList::new("Action", Command::on(row), |c| {
+                    format!("{}  {}", c.key(), c.label())
+                })
+                .pick(terminal)
+                {
+                    Ok(command) => command,
+                    Err(InstallerError::Cancelled) => continue,
+                    Err(err) => return Err(err),
+                },
+            };
+
+            match self.apply(terminal, command, row) {
+                Ok(Some(table)) => return Ok(table),
+                Ok(None) | Err(InstallerError::Cancelled) => {}
+                Err(err) => super::show_error(terminal, &err)?,
+            }
+            if command == Command::Discard && self.table == self.initial {
+                return Err(InstallerError::Cancelled);
+            }
+        }
+    }
+
+    // Carry out `command` on `row`, the finished table once the user is done
+    fn apply<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        command: Command,
+        row: Option<Row>,
+    ) -> Result<Option<GptTable>> {
+        let title = format!("Partition table of {}", self.disk.path);
+        match (command, row) {
+            (Command::New, Some(Row::Free(region))) => {
+                let size = size_mib(
+                    terminal,
+                    &title,
+                    &format!(
+                        "Size in MiB, at most {}, empty fills the free space",
+                        self.table.mib(region.sectors())
+                    ),
+                    "",
+                )?;
+                let linux = PartitionType::by_code("8300").expect("Linux filesystem type");
+                self.table.create(region, size, linux)?;
+            }
+            (Command::Delete, Some(Row::Partition(number))) => {
+                let entry = self.table.entry(number)?;
+                if entry.existing.is_none()
+                    || super::confirm(
+                        terminal,
+                        &title,
+                        &format!(
+                            "Delete {}? Its data is lost once the table is written",
+                            partition_path(&self.disk.path, number)
+                        ),
+                    )?
+                {
+                    self.table.delete(number)?;
+                }
+            }
+            (Command::Resize, Some(Row::Partition(number))) => {
+                let current = self.table.mib(self.table.entry(number)?.extent.sectors());
+                if let Some(size) =
+                    size_mib(terminal, &title, "New size in MiB", &current.to_string())?
+                {
+                    self.table.resize(number, size)?;
+                }
+            }
+            (Command::Type, Some(Row::Partition(number))) => {
+                let current = self.table.entry(number)?.type_guid.clone();
+                let mut types: Vec<Option<&PartitionType>> = gpt::TYPES.iter().map(Some).collect();
+                types.push(None);
+                let guid = match SelectList::new("Partition type", types, |t| match t {
+                    Some(t) => format!("{}  {}", t.code, t.name),
+                    None => "Other, enter a type GUID".to_string(),
+                })
+                .select_where(|t| t.is_some_and(|t| t.guid == current))
+                .pick(terminal)?
+                {
+                    Some(kind) => kind.guid.to_string(),
+                    None => super::edit(terminal, &title, "Partition type GUID", &current, None)?,
+                };
+                self.table.set_type(number, &guid)?;
+            }
+            (Command::Name, Some(Row::Partition(number))) => {
+                let current = self.table.entry(number)?.name.clone();
+                let name = super::edit(terminal, &title, "Partition name", &current, None)?;
+                self.table.set_name(number, name.trim())?;
+            }
+            (Command::Flags, Some(Row::Partition(number))) => loop {
+                let entry = self.table.entry(number)?;
+                let flag = SelectList::new(
+                    "Flags, Enter toggles, Esc when done",
+                    Flag::ALL.to_vec(),
+                    |f| {
+                        let mark = if entry.has_flag(*f) { "x" } else { " " };
+                        format!("[{}] {}", mark, f.name())
+                    },
+                )
+                .pick(terminal)?;
+                self.table.toggle_flag(number, flag)?;
+            },
+            (Command::Use, Some(Row::Partition(number))) => self.assign(terminal, number)?,
+            (Command::Done, _) => {
+                self.table.validate()?;
+                return Ok(Some(self.table.clone()));
+            }
+            (Command::Discard, _)
+                if self.table != self.initial
+                    && super::confirm(terminal, &title, "Discard every change to the table?")? =>
+            {
+                self.table = self.initial.clone();
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    // Pick the role of a partition and whether it is formatted
+    fn assign<B: Backend>(&mut self, terminal: &mut Terminal<B>, number: u32) -> Result<()> {
+        let entry = self.table.entry(number)?;
+        let roles = vec![
+            Some(PartitionRole::Root),
+            Some(PartitionRole::Esp),
+            Some(PartitionRole::Home),
+            Some(PartitionRole::Swap),
+            None,
+        ];
+        let role = SelectList::new("Use as", roles, |role| match role {
+            Some(PartitionRole::Esp) => "/boot, EFI system partition".to_string(),
+            Some(role) => role.mountpoint().unwrap_or("swap").to_string(),
+            None => "Not used by the new system".to_string(),
+        })
+        .select_where(|role| *role == entry.role)
+        .pick(terminal)?;
+        let Some(role) = role else {
+            return self.table.assign(number, None, None);
+        };
+
+        // Keeping the filesystem is offered for existing partitions that have a usable one
+        let kept = entry
+            .fstype
+            .as_deref()
+            .and_then(FilesystemKind::from_name)
+            .filter(|&kind| entry.existing.is_some() && role.allows(kind));
+        let mut choices: Vec<Option<FilesystemKind>> = FilesystemKind::ALL
+            .into_iter()
+            .filter(|&kind| role.allows(kind))
+            .map(Some)
+            .collect();
+        if kept.is_some() && role != PartitionRole::Root {
+            choices.insert(0, None);
+        }
+        let current = entry.format.as_ref().map(|spec| spec.kind);
+        let kind = SelectList::new("Filesystem", choices, |kind| match (kind, kept) {
+            (Some(kind), _) => format!("Format as {}", kind.name()),
+            (None, Some(kept)) => format!("Keep the existing {} and its data", kept.name()),
+            (None, None) => String::new(),
+        })
+        .select_where(|kind| *kind == current || (current.is_none() && *kind == kept))
+        .pick(terminal)?;
+        self.table
+            .assign(number, Some(role), kind.map(FilesystemSpec::new))
+    }
+}
+
+// A size in MiB, `None` for an empty entry
+fn size_mib<B: Backend>(
+    terminal: &mut Terminal<B>,
+    title: &str,
+    prompt: &str,
+    initial: &str,
+) -> Result<Option<u64>> {
+    let mut text = initial.to_string();
+    let mut error = None;
+    loop {
+        text = super::edit(terminal, title, prompt, &text, error.as_deref())?;
+        let trimmed = text.trim();
+        if trimmed.is_empty() {
+            return Ok(None);
+        }
+        match trimmed.parse() {
+            Ok(size) => return Ok(Some(size)),
+            Err(_) => error = Some(format!("\"{}\" is not a whole number of MiB", trimmed)),
+        }
+    }
+}
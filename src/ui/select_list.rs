@@ -0,0 +1,653 @@
+use crossterm::event::{self, Event, KeyCode, KeyEvent, MouseButton, MouseEvent, MouseEventKind};
+use tui::{
+    backend::Backend,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::Spans,
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap},
+    Frame, Terminal,
+};
+
+use crate::error::{InstallerError, Result};
+
+/// What the user decided after an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Confirm,
+    Cancel,
+}
+
+/// A scrolling list to pick one or, in multi-select mode, several items from.
+///
+/// Up/Down and j/k move with wrap-around, PageUp/PageDown, Home/End and g/G
+/// jump, `/` starts a search that narrows the list to matching entries, Space
+/// marks entries in multi-select mode, Enter confirms and Esc or q backs out.
+/// With type-ahead, any other printable key starts the search itself instead.
+/// Clicking an entry selects it, clicking it again confirms. Disabled entries
+/// are greyed out along with the reason and cannot be picked.
+///
+/// Input handling is separate from drawing: `handle_event` only updates state,
+/// so screens with their own layout can embed the list through `render`.
+pub struct SelectList<T> {
+    title: String,
+    items: Vec<T>,
+    labels: Vec<String>,
+    // Indices into `items` that match the filter, in display order
+    visible: Vec<usize>,
+    // Position in `visible`
+    cursor: usize,
+    // First visible row that is drawn
+    offset: usize,
+    multi: bool,
+    marked: Vec<bool>,
+    // Reason an item cannot be picked, it is shown greyed out
+    disabled: Vec<Option<String>>,
+    filter: String,
+    searching: bool,
+    // Printable keys start searching, so none of them navigate or cancel
+    type_ahead: bool,
+    // Inside of the border at the last draw, for paging and mouse clicks
+    area: Rect,
+}
+
+impl<T> SelectList<T> {
+    pub fn new(title: impl Into<String>, items: Vec<T>, label: impl Fn(&T) -> String) -> Self {
+        let labels: Vec<String> = items.iter().map(label).collect();
+        SelectList {
+            title: title.into(),
+            visible: (0..items.len()).collect(),
+            marked: vec![false; items.len()],
+            disabled: vec![None; items.len()],
+            items,
+            labels,
+            cursor: 0,
+            offset: 0,
+            multi: false,
+            filter: String::new(),
+            searching: false,
+            type_ahead: false,
+            area: Rect::default(),
+        }
+    }
+
+    /// Let Space mark several entries.
+    pub fn multi(mut self) -> Self {
+        self.multi = true;
+        self
+    }
+
+    /// Search as soon as a printable key is typed, for long lists picked by name.
+    pub fn type_ahead(mut self) -> Self {
+        self.type_ahead = true;
+        self
+    }
+
+    /// Start out on the first item matching `pred`.
+    pub fn select_where(mut self, pred: impl Fn(&T) -> bool) -> Self {
+        if let Some(index) = self.items.iter().position(pred) {
+            self.cursor = index;
+        }
+        self
+    }
+
+    /// Start out with every item matching `pred` marked, disabled ones excepted.
+    pub fn mark_where(mut self, pred: impl Fn(&T) -> bool) -> Self {
+        for (i, item) in self.items.iter().enumerate() {
+            self.marked[i] = self.disabled[i].is_none() && pred(item);
+        }
+        self
+    }
+
+    /// Grey out every item `reason` returns a reason for, they cannot be picked
+    /// and lose any mark they had.
+    pub fn disable_where(mut self, reason: impl Fn(&T) -> Option<String>) -> Self {
+        self.disabled = self.items.iter().map(reason).collect();
+        for (marked, disabled) in self.marked.iter_mut().zip(&self.disabled) {
+            *marked &= disabled.is_none();
+        }
+        self
+    }
+
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Index into the items of the entry under the cursor.
+    pub fn selected_index(&self) -> Option<usize> {
+        self.visible.get(self.cursor).copied()
+    }
+
+    pub fn selected(&self) -> Option<&T> {
+        self.selected_index().map(|i| &self.items[i])
+    }
+
+    /// Marked items in multi-select mode, or the one under the cursor when
+    /// nothing is marked.
+    pub fn chosen(&self) -> Vec<&T> {
+        let marked: Vec<&T> = self
+            .items
+            .iter()
+            .zip(&self.marked)
+            .filter_map(|(item, marked)| marked.then_some(item))
+            .collect();
+        if marked.is_empty() {
+            self.selected()
+                .filter(|_| self.selectable())
+                .into_iter()
+                .collect()
+        } else {
+            marked
+        }
+    }
+
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    // Whether keys are going into the search
+    pub fn searching(&self) -> bool {
+        self.searching
+    }
+
+    fn page(&self) -> usize {
+        (self.area.height as usize).max(1)
+    }
+
+    fn refilter(&mut self) {
+        let current = self.selected_index();
+        let needle = self.filter.to_lowercase();
+        self.visible = (0..self.items.len())
+            .filter(|&i| self.labels[i].to_lowercase().contains(&needle))
+            .collect();
+        // Stay on the same item if it still matches
+        self.cursor = current
+            .and_then(|c| self.visible.iter().position(|&i| i == c))
+            .unwrap_or(0);
+    }
+
+    fn move_by(&mut self, delta: isize, wrap: bool) {
+        let len = self.visible.len() as isize;
+        if len == 0 {
+            return;
+        }
+        let next = self.cursor as isize + delta;
+        self.cursor = if wrap {
+            next.rem_euclid(len)
+        } else {
+            next.clamp(0, len - 1)
+        } as usize;
+    }
+
+    fn selectable(&self) -> bool {
+        self.selected_index()
+            .is_some_and(|i| self.disabled[i].is_none())
+    }
+
+    fn toggle(&mut self) {
+        if let Some(index) = self.selected_index().filter(|_| self.selectable()) {
+            self.marked[index] = !self.marked[index];
+        }
+    }
+
+    /// Apply one input event, returning what the user decided if anything.
+    pub fn handle_event(&mut self, event: &Event) -> Option<Action> {
+        match event {
+            Event::Key(key) => self.handle_key(key),
+            Event::Mouse(mouse) => self.handle_mouse(mouse),
+            _ => None,
+        }
+    }
+
+    fn handle_key(&mut self, key: &KeyEvent) -> Option<Action> {
+        let page = self.page() as isize;
+        match key.code {
+            KeyCode::Up => self.move_by(-1, true),
+            KeyCode::Down => self.move_by(1, true),
+            KeyCode::PageUp => self.move_by(-page, false),
+            KeyCode::PageDown => self.move_by(page, false),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.visible.len().saturating_sub(1),
+            KeyCode::Enter if self.searching => self.searching = false,
+            KeyCode::Enter if self.selectable() || self.marked.contains(&true) => {
+                return Some(Action::Confirm)
+            }
+            KeyCode::Esc if self.searching || !self.filter.is_empty() => {
+                self.searching = false;
+                self.filter.clear();
+                self.refilter();
+            }
+            KeyCode::Esc => return Some(Action::Cancel),
+            KeyCode::Backspace if self.searching => {
+                if self.filter.pop().is_none() {
+                    self.searching = false;
+                }
+                self.refilter();
+            }
+            KeyCode::Char(c) if self.searching => {
+                self.filter.push(c);
+                self.refilter();
+            }
+            KeyCode::Char('/') => self.searching = true,
+            KeyCode::Char(' ') if self.multi => self.toggle(),
+            KeyCode::Char(c) if self.type_ahead && !c.is_control() => {
+                self.searching = true;
+                self.filter.push(c);
+                self.refilter();
+            }
+            KeyCode::Char('k') => self.move_by(-1, true),
+            KeyCode::Char('j') => self.move_by(1, true),
+            KeyCode::Char('g') => self.cursor = 0,
+            KeyCode::Char('G') => self.cursor = self.visible.len().saturating_sub(1),
+            KeyCode::Char('q') => return Some(Action::Cancel),
+            _ => {}
+        }
+        None
+    }
+
+    fn handle_mouse(&mut self, mouse: &MouseEvent) -> Option<Action> {
+        match mouse.kind {
+            MouseEventKind::ScrollUp => self.move_by(-1, false),
+            MouseEventKind::ScrollDown => self.move_by(1, false),
+            MouseEventKind::Down(MouseButton::Left) => {
+                let area = self.area;
+                let inside = (area.x..area.x + area.width).contains(&mouse.column)
+                    && (area.y..area.y + area.height).contains(&mouse.row);
+                let row = self.offset + (mouse.row.saturating_sub(area.y)) as usize;
+                if !inside || row >= self.visible.len() {
+                    return None;
+                }
+                if self.multi {
+                    self.cursor = row;
+                    self.toggle();
+                } else if self.cursor == row && self.selectable() {
+                    return Some(Action::Confirm);
+                } else {
+                    self.cursor = row;
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Draw the list into `area`, scrolled so the cursor is visible.
+    pub fn render<B: Backend>(&mut self, f: &mut Frame<B>, area: Rect) {
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(self.block_title());
+        self.area = block.inner(area);
+
+        // Scroll just far enough to keep the cursor on screen
+        let page = self.page();
+        if self.cursor < self.offset {
+            self.offset = self.cursor;
+        } else if self.cursor >= self.offset + page {
+            self.offset = self.cursor + 1 - page;
+        }
+
+        let items: Vec<ListItem> = self
+            .visible
+            .iter()
+            .skip(self.offset)
+            .take(page)
+            .map(|&i| {
+                let label = &self.labels[i];
+                let mut text = match (self.multi, self.marked[i]) {
+                    (true, true) => format!("[x] {}", label),
+                    (true, false) => format!("[ ] {}", label),
+                    (false, _) => label.clone(),
+                };
+                match &self.disabled[i] {
+                    Some(reason) => {
+                        text.push_str(&format!("  ({})", reason));
+                        ListItem::new(text).style(Style::default().fg(Color::DarkGray))
+                    }
+                    None => ListItem::new(text),
+                }
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(block)
+            .highlight_style(
+                Style::default()
+                    .fg(Color::Yellow)
+                    .add_modifier(Modifier::BOLD),
+            )
+            .highlight_symbol(">> ");
+        let mut state = ListState::default();
+        state.select((!self.visible.is_empty()).then_some(self.cursor - self.offset));
+        f.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn block_title(&self) -> String {
+        if self.searching {
+            format!("{}  /{}_", self.title, self.filter)
+        } else if !self.filter.is_empty() {
+            format!(
+                "{}  /{} ({} of {})",
+                self.title,
+                self.filter,
+                self.visible.len(),
+                self.items.len()
+            )
+        } else {
+            self.title.clone()
+        }
+    }
+
+    /// Show the list full screen until the user confirms or cancels.
+    pub fn run<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<()> {
+        loop {
+            terminal.draw(|f| self.render(f, f.size()))?;
+            match self.handle_event(&event::read()?) {
+                Some(Action::Confirm) => return Ok(()),
+                Some(Action::Cancel) => return Err(InstallerError::Cancelled),
+                None => {}
+            }
+        }
+    }
+
+    /// Like `run`, with a pane below the list describing the item under the
+    /// cursor. `details` runs once per item, before the first draw that shows
+    /// it, and never from inside drawing, so it may be slow.
+    pub fn run_with_details<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        pane_title: &str,
+        mut details: impl FnMut(&T) -> Vec<String>,
+    ) -> Result<()> {
+        let mut cache: Vec<Option<Vec<String>>> = vec![None; self.items.len()];
+        loop {
+            let lines: Vec<Spans> = match self.selected_index() {
+                Some(i) => cache[i]
+                    .get_or_insert_with(|| details(&self.items[i]))
+                    .iter()
+                    .cloned()
+                    .map(Spans::from)
+                    .collect(),
+                None => Vec::new(),
+            };
+            terminal.draw(|f| {
+                let chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Percentage(40), Constraint::Percentage(60)].as_ref())
+                    .split(f.size());
+                self.render(f, chunks[0]);
+
+                let pane = Paragraph::new(lines)
+                    .block(Block::default().borders(Borders::ALL).title(pane_title))
+                    .wrap(Wrap { trim: false });
+                f.render_widget(pane, chunks[1]);
+            })?;
+            match self.handle_event(&event::read()?) {
+                Some(Action::Confirm) => return Ok(()),
+                Some(Action::Cancel) => return Err(InstallerError::Cancelled),
+                None => {}
+            }
+        }
+    }
+}
+
+impl<T: Clone> SelectList<T> {
+    /// Run the list and return the item picked.
+    pub fn pick<B: Backend>(mut self, terminal: &mut Terminal<B>) -> Result<T> {
+        self.run(terminal)?;
+        self.selected().cloned().ok_or(InstallerError::Cancelled)
+    }
+
+    /// Run the list in multi-select mode and return every item chosen.
+    pub fn pick_many<B: Backend>(mut self, terminal: &mut Terminal<B>) -> Result<Vec<T>> {
+        self.multi = true;
+        self.run(terminal)?;
+        Ok(self.chosen().into_iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyModifiers;
+    use tui::backend::TestBackend;
+
+    const GREEK: [&str; 8] = [
+        "alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta",
+    ];
+
+    fn list() -> SelectList<&'static str> {
+        SelectList::new("Letters", GREEK.to_vec(), |s| s.to_string())
+    }
+
+    fn key(code: KeyCode) -> Event {
+        Event::Key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    // Feed `keys`, returning the last decision
+    fn press<T>(list: &mut SelectList<T>, keys: &[KeyCode]) -> Option<Action> {
+        keys.iter()
+            .map(|&code| list.handle_event(&key(code)))
+            .last()
+            .flatten()
+    }
+
+    fn type_text<T>(list: &mut SelectList<T>, text: &str) {
+        for c in text.chars() {
+            list.handle_event(&key(KeyCode::Char(c)));
+        }
+    }
+
+    // Draw once so the list knows its area, 5 rows inside the border
+    fn draw<T>(list: &mut SelectList<T>) {
+        let mut terminal = Terminal::new(TestBackend::new(40, 7)).unwrap();
+        terminal.draw(|f| list.render(f, f.size())).unwrap();
+    }
+
+    fn click(column: u16, row: u16) -> Event {
+        Event::Mouse(MouseEvent {
+            kind: MouseEventKind::Down(MouseButton::Left),
+            column,
+            row,
+            modifiers: KeyModifiers::NONE,
+        })
+    }
+
+    #[test]
+    fn arrows_wrap_around() {
+        let mut list = list();
+        press(&mut list, &[KeyCode::Up]);
+        assert_eq!(list.selected(), Some(&"theta"));
+        press(&mut list, &[KeyCode::Down]);
+        assert_eq!(list.selected(), Some(&"alpha"));
+        press(&mut list, &[KeyCode::Char('j'), KeyCode::Char('j')]);
+        assert_eq!(list.selected(), Some(&"gamma"));
+        press(&mut list, &[KeyCode::Char('G'), KeyCode::Char('j')]);
+        assert_eq!(list.selected(), Some(&"alpha"));
+    }
+
+    #[test]
+    fn paging_stops_at_the_ends() {
+        let mut list = list();
+        draw(&mut list);
+        press(&mut list, &[KeyCode::PageDown]);
+        assert_eq!(list.selected(), Some(&"zeta"));
+        press(&mut list, &[KeyCode::PageDown]);
+        assert_eq!(list.selected(), Some(&"theta"));
+        press(&mut list, &[KeyCode::PageUp]);
+        assert_eq!(list.selected(), Some(&"gamma"));
+        press(&mut list, &[KeyCode::PageUp, KeyCode::PageUp]);
+        assert_eq!(list.selected(), Some(&"alpha"));
+        press(&mut list, &[KeyCode::End]);
+        assert_eq!(list.selected(), Some(&"theta"));
+        press(&mut list, &[KeyCode::Home]);
+        assert_eq!(list.selected(), Some(&"alpha"));
+    }
+
+    #[test]
+    fn slash_filters_the_list() {
+        let mut list = list();
+        press(&mut list, &[KeyCode::Char('/')]);
+        assert!(list.searching());
+        // Typed keys go to the search, not to navigation or cancelling
+        type_text(&mut list, "ETA");
+        assert_eq!(list.filter(), "ETA");
+        assert_eq!(list.selected(), Some(&"beta"));
+        press(&mut list, &[KeyCode::Down, KeyCode::Down]);
+        assert_eq!(list.selected(), Some(&"eta"));
+        press(&mut list, &[KeyCode::Down, KeyCode::Down]);
+        assert_eq!(list.selected(), Some(&"beta"));
+
+        // Enter ends the search and keeps the filter, Enter again confirms
+        assert_eq!(press(&mut list, &[KeyCode::Enter]), None);
+        assert!(!list.searching());
+        press(&mut list, &[KeyCode::Char('j')]);
+        assert_eq!(list.selected(), Some(&"zeta"));
+
+        // Esc drops the filter but stays on the same item
+        assert_eq!(press(&mut list, &[KeyCode::Esc]), None);
+        assert_eq!(list.filter(), "");
+        assert_eq!(list.selected(), Some(&"zeta"));
+        assert_eq!(press(&mut list, &[KeyCode::Enter]), Some(Action::Confirm));
+
+        press(&mut list, &[KeyCode::Char('/')]);
+        type_text(&mut list, "x");
+        assert_eq!(list.selected(), None);
+        assert_eq!(press(&mut list, &[KeyCode::Enter, KeyCode::Enter]), None);
+        press(
+            &mut list,
+            &[KeyCode::Char('/'), KeyCode::Backspace, KeyCode::Backspace],
+        );
+        assert!(!list.searching());
+        assert_eq!(list.selected(), Some(&"alpha"));
+    }
+
+    #[test]
+    fn type_ahead_searches_on_any_printable_key() {
+        let mut list = list().type_ahead();
+        // Neither q nor the navigation keys do anything but search
+        assert_eq!(press(&mut list, &[KeyCode::Char('q')]), None);
+        assert!(list.searching());
+        assert_eq!(list.selected(), None);
+        press(&mut list, &[KeyCode::Backspace]);
+        type_text(&mut list, "th");
+        assert_eq!(list.filter(), "th");
+        assert_eq!(list.selected(), Some(&"theta"));
+        assert_eq!(press(&mut list, &[KeyCode::Enter]), None);
+        assert_eq!(press(&mut list, &[KeyCode::Enter]), Some(Action::Confirm));
+
+        // Cleared again, the search can start over with another key
+        press(&mut list, &[KeyCode::Esc]);
+        type_text(&mut list, "gk");
+        assert_eq!(list.filter(), "gk");
+        press(
+            &mut list,
+            &[KeyCode::Backspace, KeyCode::Backspace, KeyCode::Backspace],
+        );
+        assert!(!list.searching());
+        assert_eq!(press(&mut list, &[KeyCode::Esc]), Some(Action::Cancel));
+    }
+
+    #[test]
+    fn type_ahead_leaves_space_for_marking() {
+        let mut list = list().multi().type_ahead();
+        press(&mut list, &[KeyCode::Char(' ')]);
+        type_text(&mut list, "z");
+        assert_eq!(list.selected(), Some(&"zeta"));
+        press(&mut list, &[KeyCode::Enter, KeyCode::Char(' ')]);
+        assert_eq!(list.chosen(), [&"alpha", &"zeta"]);
+    }
+
+    #[test]
+    fn space_marks_several_in_multi_select() {
+        let mut list = list();
+        press(&mut list, &[KeyCode::Char(' ')]);
+        assert_eq!(list.chosen(), [&"alpha"]);
+
+        let mut list = list.multi();
+        press(
+            &mut list,
+            &[
+                KeyCode::Char(' '),
+                KeyCode::Down,
+                KeyCode::Down,
+                KeyCode::Char(' '),
+            ],
+        );
+        assert_eq!(list.chosen(), [&"alpha", &"gamma"]);
+        press(&mut list, &[KeyCode::Char(' ')]);
+        assert_eq!(list.chosen(), [&"alpha"]);
+        // Marked items stay chosen wherever the cursor is
+        assert_eq!(
+            press(&mut list, &[KeyCode::Down, KeyCode::Enter]),
+            Some(Action::Confirm)
+        );
+        assert_eq!(list.chosen(), [&"alpha"]);
+    }
+
+    #[test]
+    fn disabled_items_cannot_be_picked() {
+        let mut list = list()
+            .multi()
+            .disable_where(|s| s.starts_with('e').then(|| "in use".to_string()));
+        press(
+            &mut list,
+            &[KeyCode::Char('G'), KeyCode::Up, KeyCode::Up, KeyCode::Up],
+        );
+        assert_eq!(list.selected(), Some(&"epsilon"));
+        assert_eq!(press(&mut list, &[KeyCode::Char(' ')]), None);
+        assert!(list.chosen().is_empty());
+        assert_eq!(press(&mut list, &[KeyCode::Enter]), None);
+
+        press(&mut list, &[KeyCode::Down]);
+        assert_eq!(list.chosen(), [&"zeta"]);
+        assert_eq!(press(&mut list, &[KeyCode::Enter]), Some(Action::Confirm));
+        assert_eq!(
+            press(&mut list, &[KeyCode::Char('q')]),
+            Some(Action::Cancel)
+        );
+    }
+
+    #[test]
+    fn disabled_items_are_never_marked() {
+        let in_use = |s: &&str| (*s == "beta").then(|| "in use".to_string());
+        let pair = |s: &&str| *s == "alpha" || *s == "beta";
+
+        // The disk step marks the previous choice after greying out what became unsafe
+        let list = list().multi().disable_where(in_use).mark_where(pair);
+        assert_eq!(list.chosen(), [&"alpha"]);
+        let list = self::list().multi().mark_where(pair).disable_where(in_use);
+        assert_eq!(list.chosen(), [&"alpha"]);
+
+        let list = self::list()
+            .multi()
+            .disable_where(in_use)
+            .mark_where(|s| *s == "beta")
+            .select_where(|s| *s == "beta");
+        assert!(list.chosen().is_empty());
+    }
+
+    #[test]
+    fn clicks_hit_the_row_under_the_pointer() {
+        let mut list = list();
+        draw(&mut list);
+        // The border takes the first row and column
+        assert_eq!(list.handle_event(&click(5, 3)), None);
+        assert_eq!(list.selected(), Some(&"gamma"));
+        assert_eq!(list.handle_event(&click(5, 3)), Some(Action::Confirm));
+        // Outside the list or on the border nothing happens
+        assert_eq!(list.handle_event(&click(5, 0)), None);
+        assert_eq!(list.handle_event(&click(45, 2)), None);
+        assert_eq!(list.selected(), Some(&"gamma"));
+
+        // Scrolled down, rows are counted from the first item shown
+        press(&mut list, &[KeyCode::End]);
+        draw(&mut list);
+        list.handle_event(&click(5, 1));
+        assert_eq!(list.selected(), Some(&"delta"));
+
+        let mut list = self::list().multi();
+        draw(&mut list);
+        list.handle_event(&click(5, 1));
+        list.handle_event(&click(5, 2));
+        assert_eq!(list.chosen(), [&"alpha", &"beta"]);
+    }
+}
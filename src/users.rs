@@ -0,0 +1,353 @@
+use std::{fs, io::Read, path::Path};
+
+use serde::{Deserialize, Serialize};
+use sha_crypt::{CustomizedPasswordHasher, Params, ShaCrypt};
+
+use crate::{
+    command::{target_path, Cmd, CommandRunner},
+    config::InstallConfig,
+    error::{InstallerError, Result},
+};
+
+/// Login shells on offer and the package providing each.
+pub const SHELLS: &[(&str, &str)] = &[
+    ("/bin/bash", "bash"),
+    ("/usr/bin/zsh", "zsh"),
+    ("/usr/bin/fish", "fish"),
+];
+
+// Accounts a fresh install already has, from the filesystem package and
+// systemd's sysusers.d. systemd-* accounts are reserved as a whole
+const RESERVED: &[&str] = &[
+    "root", "bin", "daemon", "mail", "ftp", "http", "nobody", "dbus", "uuidd",
+];
+
+// Drop-in letting wheel use sudo, sudo reads /etc/sudoers.d by default
+const SUDOERS_WHEEL: &str = "/etc/sudoers.d/10-wheel";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    // Login shell, useradd's default when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    // crypt(3) hash handed to `chpasswd -e`, the account is locked when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+}
+
+impl User {
+    pub fn validate(&self) -> Result<()> {
+        validate_name(&self.name, "user")?;
+        if RESERVED.contains(&self.name.as_str()) || self.name.starts_with("systemd-") {
+            return Err(InstallerError::PreconditionFailed(format!(
+                "{} already exists, pick another user name",
+                self.name
+            )));
+        }
+        for group in &self.groups {
+            validate_name(group, "group")?;
+        }
+        if let Some(shell) = &self.shell {
+            if !shell.starts_with('/') {
+                return Err(InstallerError::PreconditionFailed(format!(
+                    "The login shell \"{}\" is not an absolute path",
+                    shell
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups.iter().any(|g| g == group)
+    }
+}
+
+/// Check a user or group name against the rules useradd and groupadd apply:
+/// at most 32 characters of lower case letters, digits, `_` and `-`, not
+/// starting with a digit or `-`, optionally ending in `$`.
+pub fn validate_name(name: &str, what: &str) -> Result<()> {
+    let body = name.strip_suffix('$').unwrap_or(name);
+    let valid_start = body
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c == '_');
+    let valid_rest = body
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+
+    if !valid_start || !valid_rest || name.len() > 32 {
+        return Err(InstallerError::PreconditionFailed(format!(
+            "Invalid {} name \"{}\": use up to 32 lower case letters, digits, _ or -, starting with a letter or _",
+            what, name
+        )));
+    }
+    Ok(())
+}
+
+/// SHA-512 crypt hash of `password` with a random salt, as understood by `chpasswd -e`.
+pub fn hash_password(password: &str) -> Result<String> {
+    // 12 random bytes make the 16 character salt glibc uses at most
+    let mut salt = [0u8; 12];
+    fs::File::open("/dev/urandom")?.read_exact(&mut salt)?;
+    let hash = ShaCrypt::SHA512
+        .hash_password_customized(password.as_bytes(), &salt, None, None, Params::RECOMMENDED)
+        .map_err(|err| InstallerError::parse("password hash", err))?;
+    Ok(hash.to_string())
+}
+
+/// Extra packages the configured users need: sudo and any non-default login shells.
+pub fn packages(config: &InstallConfig) -> Vec<&'static str> {
+    let mut packages = Vec::new();
+    if config.sudo_wheel {
+        packages.push("sudo");
+    }
+    for user in &config.users {
+        if let Some((_, package)) = SHELLS
+            .iter()
+            .find(|(path, _)| user.shell.as_deref() == Some(path))
+        {
+            packages.push(*package);
+        }
+    }
+    packages
+}
+
+// Set a password from its hash inside the target
+fn set_password_hash(
+    runner: &dyn CommandRunner,
+    target: &Path,
+    name: &str,
+    hash: &str,
+) -> Result<()> {
+    runner.run_checked(
+        &Cmd::chroot(target, "chpasswd")
+            .arg("-e")
+            .secret_stdin(format!("{}:{}\n", name, hash)),
+    )?;
+    Ok(())
+}
+
+/// Create every user in the target, set or lock the root password and set up sudo.
+pub fn configure(runner: &dyn CommandRunner, target: &Path, config: &InstallConfig) -> Result<()> {
+    for user in &config.users {
+        user.validate()?;
+    }
+
+    match &config.root_password_hash {
+        Some(hash) => set_password_hash(runner, target, "root", hash)?,
+        // The stock shadow file has an empty root password, lock it instead
+        None => {
+            runner.run_checked(&Cmd::chroot(target, "passwd").args(["-l", "root"]))?;
+        }
+    }
+
+    for user in &config.users {
+        let mut cmd = Cmd::chroot(target, "useradd").arg("-m");
+        if !user.groups.is_empty() {
+            cmd = cmd.args(["-G".to_string(), user.groups.join(",")]);
+        }
+        if let Some(shell) = &user.shell {
+            cmd = cmd.args(["-s", shell]);
+        }
+        runner.run_checked(&cmd.arg(user.name.as_str()))?;
+
+        if let Some(hash) = &user.password_hash {
+            set_password_hash(runner, target, &user.name, hash)?;
+        }
+    }
+
+    if config.sudo_wheel {
+        let sudoers = target_path(target, SUDOERS_WHEEL);
+        runner.write_file(&sudoers, "%wheel ALL=(ALL:ALL) ALL\n")?;
+        // sudo ignores drop-ins that are writable by anyone but root
+        runner.run_checked(&Cmd::chroot(target, "chmod").args(["0440", SUDOERS_WHEEL]))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{CommandOutput, MockRunner, REDACTED};
+    use sha_crypt::PasswordVerifier;
+
+    fn user(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            groups: Vec::new(),
+            shell: None,
+            password_hash: None,
+        }
+    }
+
+    #[test]
+    fn configure_creates_users_and_sets_passwords() {
+        let runner = MockRunner::new();
+        let alice = User {
+            groups: vec!["wheel".to_string(), "video".to_string()],
+            shell: Some("/usr/bin/zsh".to_string()),
+            password_hash: Some("$6$salt$hash".to_string()),
+            ..user("alice")
+        };
+        let config = InstallConfig {
+            root_password_hash: Some("$6$root$hash".to_string()),
+            users: vec![alice, user("bob")],
+            sudo_wheel: true,
+            ..InstallConfig::default()
+        };
+
+        configure(&runner, Path::new("/mnt"), &config).unwrap();
+        assert_eq!(
+            runner.command_lines(),
+            [
+                "arch-chroot /mnt chpasswd -e",
+                "arch-chroot /mnt useradd -m -G wheel,video -s /usr/bin/zsh alice",
+                "arch-chroot /mnt chpasswd -e",
+                "arch-chroot /mnt useradd -m bob",
+                "arch-chroot /mnt chmod 0440 /etc/sudoers.d/10-wheel",
+            ]
+        );
+        // Hashes go through stdin only, and are not even kept by the mock
+        let chpasswd = &runner.invocations()[0];
+        assert!(chpasswd.secret_stdin);
+        assert_eq!(chpasswd.stdin.as_deref(), Some(REDACTED));
+
+        assert_eq!(
+            runner.written_files(),
+            [(
+                Path::new("/mnt/etc/sudoers.d/10-wheel").to_path_buf(),
+                "%wheel ALL=(ALL:ALL) ALL\n".to_string()
+            )]
+        );
+        assert_eq!(packages(&config), ["sudo", "zsh"]);
+    }
+
+    #[test]
+    fn configure_locks_root_without_a_password() {
+        let runner = MockRunner::new();
+        configure(&runner, Path::new("/mnt"), &InstallConfig::default()).unwrap();
+        assert_eq!(runner.command_lines(), ["arch-chroot /mnt passwd -l root"]);
+        assert!(runner.written_files().is_empty());
+    }
+
+    #[test]
+    fn configure_rejects_invalid_users_first() {
+        let runner = MockRunner::new();
+        let config = InstallConfig {
+            users: vec![user("alice"), user("Bad Name")],
+            ..InstallConfig::default()
+        };
+        assert!(configure(&runner, Path::new("/mnt"), &config).is_err());
+        assert!(runner.invocations().is_empty());
+    }
+
+    #[test]
+    fn configure_stops_when_useradd_fails() {
+        let runner = MockRunner::new();
+        runner.on_args(
+            "arch-chroot",
+            &["/mnt", "useradd", "-m", "alice"],
+            CommandOutput::failure(9, "useradd: user 'alice' already exists"),
+        );
+        let config = InstallConfig {
+            root_password_hash: None,
+            users: vec![User {
+                password_hash: Some("$6$salt$hash".to_string()),
+                ..user("alice")
+            }],
+            sudo_wheel: true,
+            ..InstallConfig::default()
+        };
+        assert!(configure(&runner, Path::new("/mnt"), &config).is_err());
+        // Neither the password nor sudo is set up for a user that was not created
+        assert_eq!(
+            runner.command_lines(),
+            [
+                "arch-chroot /mnt passwd -l root",
+                "arch-chroot /mnt useradd -m alice",
+            ]
+        );
+        assert!(runner.written_files().is_empty());
+    }
+
+    #[test]
+    fn validate_name_follows_useradd() {
+        let longest = format!("a{}", "b".repeat(31));
+        for name in [
+            "alice",
+            "a",
+            "_svc",
+            "web_01",
+            "user-1",
+            "smb$",
+            longest.as_str(),
+        ] {
+            assert!(validate_name(name, "user").is_ok(), "{}", name);
+        }
+
+        let too_long = format!("{}c", longest);
+        let machine_too_long = format!("{}$", longest);
+        for name in [
+            "",
+            "1alice",
+            "-alice",
+            "Alice",
+            "al ice",
+            "al.ice",
+            "jürgen",
+            "$",
+            "a$b",
+            too_long.as_str(),
+            machine_too_long.as_str(),
+        ] {
+            assert!(validate_name(name, "user").is_err(), "{:?}", name);
+        }
+        assert_eq!(
+            validate_name("9wheel", "group").unwrap_err().to_string(),
+            "Invalid group name \"9wheel\": use up to 32 lower case letters, digits, _ or -, starting with a letter or _"
+        );
+    }
+
+    #[test]
+    fn reserved_names_are_refused() {
+        for name in ["root", "bin", "nobody", "dbus", "systemd-network"] {
+            assert_eq!(
+                user(name).validate().unwrap_err().to_string(),
+                format!("{} already exists, pick another user name", name)
+            );
+        }
+        for name in ["rooty", "systemd", "nobody2"] {
+            assert!(user(name).validate().is_ok(), "{}", name);
+        }
+
+        let mut bad_group = user("alice");
+        bad_group.groups = vec!["Wheel".to_string()];
+        assert!(bad_group.validate().is_err());
+        let mut relative_shell = user("alice");
+        relative_shell.shell = Some("zsh".to_string());
+        assert!(relative_shell.validate().is_err());
+    }
+
+    #[test]
+    fn hash_password_makes_a_salted_sha512_crypt_hash() {
+        let hash = hash_password("correct horse").unwrap();
+        let fields: Vec<&str> = hash.split('$').collect();
+        assert_eq!(fields[..2], ["", "6"]);
+        assert_eq!(fields.len(), 5, "{}", hash);
+        assert!(fields[2].starts_with("rounds="));
+        assert_eq!(fields[3].len(), 16);
+
+        ShaCrypt::default()
+            .verify_password(b"correct horse", hash.as_str())
+            .unwrap();
+        assert!(ShaCrypt::default()
+            .verify_password(b"battery staple", hash.as_str())
+            .is_err());
+        // A fresh salt every time
+        assert_ne!(hash_password("correct horse").unwrap(), hash);
+    }
+}
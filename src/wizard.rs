@@ -0,0 +1,1046 @@
+use std::{
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+};
+
+use tui::{backend::Backend, Terminal};
+
+use crate::{
+    bootloader::{self, BootMode},
+    command::CommandRunner,
+    config::InstallConfig,
+    crypt::{Encryption, LuksKey},
+    disk::{self, BlockDevice},
+    error::{InstallerError, Result},
+    format::{FilesystemKind, FilesystemSpec},
+    gpt::GptTable,
+    install,
+    locale::LocaleSources,
+    network::{self, NetworkStack},
+    partition::PartitionRole,
+    probe,
+    raid::{MirrorKind, MultiDisk},
+    review,
+    safety::{self, Safety},
+    ui::{self, PartitionEditor, SelectList},
+    users::{self, User},
+};
+
+// Suggested location for an exported install profile
+const DEFAULT_PROFILE: &str = "/root/archinstall.toml";
+
+/// The steps of the interactive install, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Screen {
+    Disk,
+    Partitioning,
+    Encryption,
+    Locale,
+    Keymap,
+    Timezone,
+    User,
+    Root,
+    Network,
+    Summary,
+}
+
+impl Screen {
+    pub const ALL: [Screen; 10] = [
+        Screen::Disk,
+        Screen::Partitioning,
+        Screen::Encryption,
+        Screen::Locale,
+        Screen::Keymap,
+        Screen::Timezone,
+        Screen::User,
+        Screen::Root,
+        Screen::Network,
+        Screen::Summary,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Screen::Disk => "Disk",
+            Screen::Partitioning => "Partitioning",
+            Screen::Encryption => "Encryption",
+            Screen::Locale => "Locale",
+            Screen::Keymap => "Keymap",
+            Screen::Timezone => "Timezone",
+            Screen::User => "User account",
+            Screen::Root => "Root account",
+            Screen::Network => "Network",
+            Screen::Summary => "Summary",
+        }
+    }
+
+    // 1 based position for the step indicator
+    pub fn step(self) -> usize {
+        Screen::ALL.iter().position(|&s| s == self).unwrap_or(0) + 1
+    }
+
+    pub fn next(self) -> Option<Screen> {
+        Screen::ALL.get(self.step()).copied()
+    }
+
+    pub fn prev(self) -> Option<Screen> {
+        Screen::ALL.get(self.step().checked_sub(2)?).copied()
+    }
+}
+
+/// How the target disk is partitioned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Partitioning {
+    // Wipe the disk and use the automatic layout
+    #[default]
+    Erase,
+    // Keep every partition and use unallocated space
+    Alongside,
+    Manual,
+}
+
+/// Everything answered so far, kept while moving between screens.
+#[derive(Debug, Clone, Default)]
+pub struct InstallState {
+    pub disk: Option<BlockDevice>,
+    pub partitioning: Partitioning,
+    pub config: InstallConfig,
+}
+
+impl InstallState {
+    /// The answers as an install config naming the chosen disk, as used for
+    /// the install itself and for an exported profile.
+    pub fn install_config(&self) -> InstallConfig {
+        let mut config = self.config.clone();
+        config.disk = self.disk.as_ref().map(|d| d.path.clone());
+        config
+    }
+
+    /// One line describing the answer given on `screen`.
+    pub fn answer(&self, screen: Screen) -> String {
+        let config = &self.config;
+        match screen {
+            Screen::Disk => {
+                let mut answer = self
+                    .disk
+                    .as_ref()
+                    .map_or("not chosen".to_string(), BlockDevice::summary);
+                match &config.multi_disk {
+                    Some(MultiDisk::SeparateHome { disk }) => {
+                        answer.push_str(&format!(", /home on {}", disk));
+                    }
+                    Some(MultiDisk::Mirror { disks, raid }) => {
+                        answer.push_str(&format!(
+                            ", mirrored onto {} with {}",
+                            disks.join(", "),
+                            raid.name()
+                        ));
+                    }
+                    None => {}
+                }
+                answer
+            }
+            Screen::Partitioning => match (self.partitioning, &config.manual_partitions) {
+                (Partitioning::Alongside, Some(table)) if table.shared_esp().is_some() => {
+                    "alongside the existing partitions, sharing their ESP".to_string()
+                }
+                (Partitioning::Alongside, Some(_)) => {
+                    "alongside the existing partitions".to_string()
+                }
+                (_, Some(table)) => format!("manual, {} partitions", table.entries.len()),
+                (_, None) => "erase the disk, automatic layout".to_string(),
+            },
+            Screen::Encryption => match &config.encryption {
+                Some(_) => "LUKS2 on the root partition".to_string(),
+                None => "none".to_string(),
+            },
+            Screen::Locale => config.locale.locales.join(", "),
+            Screen::Keymap => config.locale.keymap.clone(),
+            Screen::Timezone => config.locale.timezone.clone(),
+            Screen::User => match config.users.first() {
+                Some(user) => {
+                    let mut answer = user.name.clone();
+                    if !user.groups.is_empty() {
+                        answer.push_str(&format!(" ({})", user.groups.join(", ")));
+                    }
+                    if let Some(shell) = &user.shell {
+                        answer.push_str(&format!(", {}", shell));
+                    }
+                    if config.sudo_wheel {
+                        answer.push_str(", sudo for wheel");
+                    }
+                    answer
+                }
+                None => "none".to_string(),
+            },
+            Screen::Root => match config.root_password_hash {
+                Some(_) => "password set".to_string(),
+                None => "locked".to_string(),
+            },
+            Screen::Network => format!(
+                "{}, {}",
+                config.hostname,
+                config
+                    .network
+                    .stack
+                    .map_or("no network stack", NetworkStack::name)
+            ),
+            Screen::Summary => String::new(),
+        }
+    }
+}
+
+// Where to go after a screen
+enum Transition {
+    Next,
+    Back,
+    Goto(Screen),
+    Install,
+}
+
+#[derive(Clone, Copy)]
+enum SummaryItem {
+    Edit(Screen),
+    SaveProfile,
+    Install,
+}
+
+/// The interactive install as a state machine over `Screen`s. Esc goes back
+/// a step and every screen starts out on the answer given before.
+pub struct Wizard<'r> {
+    runner: &'r dyn CommandRunner,
+    locale_sources: LocaleSources,
+    // Root of the live system, for copying its network profiles
+    live_root: PathBuf,
+    screen: Screen,
+    // Set while changing one answer from the summary
+    from_summary: bool,
+    // Let experts pick disks that are in use
+    allow_unsafe: bool,
+    pub state: InstallState,
+}
+
+impl<'r> Wizard<'r> {
+    pub fn new(runner: &'r dyn CommandRunner) -> Self {
+        Wizard {
+            runner,
+            locale_sources: LocaleSources::new("/"),
+            live_root: PathBuf::from("/"),
+            screen: Screen::Disk,
+            from_summary: false,
+            allow_unsafe: false,
+            state: InstallState::default(),
+        }
+    }
+
+    /// Read locales, keymaps, timezones and network profiles below `root`
+    /// instead of the live system's `/`.
+    pub fn with_root(mut self, root: &Path) -> Self {
+        self.locale_sources = LocaleSources::new(root);
+        self.live_root = root.to_path_buf();
+        self
+    }
+
+    /// Offer disks that are in use instead of greying them out.
+    pub fn allow_unsafe(mut self, allow: bool) -> Self {
+        self.allow_unsafe = allow;
+        self
+    }
+
+    pub fn screen(&self) -> Screen {
+        self.screen
+    }
+
+    // Dialog title carrying the step indicator
+    fn title(&self, text: &str) -> String {
+        format!(
+            "Step {} of {}: {}",
+            self.screen.step(),
+            Screen::ALL.len(),
+            text
+        )
+    }
+
+    /// Walk through the screens until the user asks to install, returning
+    /// the chosen disk and configuration, or `None` when they back out of
+    /// the first screen. Answers are kept, so running again resumes.
+    pub fn run<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+    ) -> Result<Option<(BlockDevice, InstallConfig)>> {
+        loop {
+            let transition = match self.show(terminal) {
+                Ok(transition) => transition,
+                Err(InstallerError::Cancelled) => Transition::Back,
+                Err(err) => {
+                    ui::show_error(terminal, &err)?;
+                    Transition::Back
+                }
+            };
+            if let ControlFlow::Break(done) = self.follow(transition) {
+                return Ok(done);
+            }
+        }
+    }
+
+    // Move to the screen `transition` leads to, or stop with what `run` returns
+    fn follow(
+        &mut self,
+        transition: Transition,
+    ) -> ControlFlow<Option<(BlockDevice, InstallConfig)>> {
+        let next = match transition {
+            // Changing one answer leads straight back to the summary
+            Transition::Next | Transition::Back if self.from_summary => Some(Screen::Summary),
+            Transition::Next => self.screen.next(),
+            Transition::Back => self.screen.prev(),
+            Transition::Goto(screen) => Some(screen),
+            Transition::Install => {
+                let Some(disk) = self.state.disk.clone() else {
+                    self.screen = Screen::Disk;
+                    return ControlFlow::Continue(());
+                };
+                // Still on the summary, so a failed install resumes there
+                return ControlFlow::Break(Some((disk, self.state.install_config())));
+            }
+        };
+
+        self.from_summary =
+            matches!(transition, Transition::Goto(screen) if screen != Screen::Summary);
+        match next {
+            Some(screen) => {
+                self.screen = screen;
+                ControlFlow::Continue(())
+            }
+            None => {
+                self.screen = Screen::Disk;
+                ControlFlow::Break(None)
+            }
+        }
+    }
+
+    fn show<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Transition> {
+        match self.screen {
+            Screen::Disk => self.disk(terminal),
+            Screen::Partitioning => self.partitioning(terminal),
+            Screen::Encryption => self.encryption(terminal),
+            Screen::Locale => self.locale(terminal),
+            Screen::Keymap => self.keymap(terminal),
+            Screen::Timezone => self.timezone(terminal),
+            Screen::User => self.user(terminal),
+            Screen::Root => self.root(terminal),
+            Screen::Network => self.network(terminal),
+            Screen::Summary => self.summary(terminal),
+        }
+    }
+
+    fn disk<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Transition> {
+        let disks = disk::get_available_disks(self.runner, self.allow_unsafe)?;
+        if disks.is_empty() {
+            return Err(InstallerError::PreconditionFailed(
+                "No disks found".to_string(),
+            ));
+        }
+
+        let safety = Safety::probe(&self.live_root)?;
+        let allow_unsafe = self.allow_unsafe;
+        let current = self.state.disk.as_ref().map(|d| d.path.clone());
+        let others: Vec<String> = match &self.state.config.multi_disk {
+            Some(multi) => multi.disks().into_iter().map(String::from).collect(),
+            None => Vec::new(),
+        };
+        let mut list = SelectList::new(
+            self.title("Select a disk, Space marks several for /home or a mirror"),
+            disks,
+            BlockDevice::summary,
+        )
+        .multi()
+        .disable_where(|d| {
+            let blocking = safety.blocking(d, allow_unsafe);
+            (!blocking.is_empty()).then(|| safety::describe(&blocking))
+        })
+        .select_where(|d| Some(&d.path) == current.as_ref())
+        .mark_where(|d| {
+            !others.is_empty() && (Some(&d.path) == current.as_ref() || others.contains(&d.path))
+        });
+
+        // Probed once per disk, when it is first under the cursor
+        let scratch = self.live_root.join(probe::SCRATCH.trim_start_matches('/'));
+        let runner = self.runner;
+        list.run_with_details(terminal, "Disk contents", |disk| {
+            disk_details(disk, |part| {
+                match probe::detect_os(runner, part, &scratch) {
+                    Ok(found) => found.unwrap_or_default(),
+                    Err(_) => "?".to_string(),
+                }
+            })
+        })?;
+        let chosen: Vec<BlockDevice> = list.chosen().into_iter().cloned().collect();
+        let (disk, multi_disk) = match chosen.len() {
+            0 => return Err(InstallerError::Cancelled),
+            1 => (chosen[0].clone(), None),
+            _ => {
+                let (disk, multi) = self.disk_roles(terminal, chosen)?;
+                (disk, Some(multi))
+            }
+        };
+        // A table edited for another disk does not apply, and several disks are always erased
+        if current.as_ref() != Some(&disk.path) || multi_disk.is_some() {
+            self.state.partitioning = Partitioning::Erase;
+            self.state.config.manual_partitions = None;
+        }
+        self.state.disk = Some(disk);
+        self.state.config.multi_disk = multi_disk;
+        Ok(Transition::Next)
+    }
+
+    // What several marked disks are for: /home on the second of two, or a mirror across all
+    fn disk_roles<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        mut disks: Vec<BlockDevice>,
+    ) -> Result<(BlockDevice, MultiDisk)> {
+        // None is the separate /home disk
+        let mut layouts = vec![Some(MirrorKind::Mdadm), Some(MirrorKind::Btrfs)];
+        if disks.len() == 2 {
+            layouts.insert(0, None);
+        }
+        let current = match &self.state.config.multi_disk {
+            Some(MultiDisk::Mirror { raid, .. }) => Some(*raid),
+            _ => None,
+        };
+        let names: Vec<&str> = disks.iter().map(|d| d.path.as_str()).collect();
+        let names = names.join(", ");
+        let layout =
+            SelectList::new(
+                self.title("Use of several disks"),
+                layouts,
+                |layout| match layout {
+                    None => "Root on one disk, /home on the other".to_string(),
+                    Some(raid) => {
+                        format!("Mirror everything across {} with {}", names, raid.name())
+                    }
+                },
+            )
+            .select_where(|&layout| layout == current)
+            .pick(terminal)?;
+
+        let Some(raid) = layout else {
+            let primary = self.state.disk.as_ref().map(|d| d.path.clone());
+            let root = SelectList::new(
+                self.title("Disk for the root filesystem"),
+                disks.clone(),
+                BlockDevice::summary,
+            )
+            .select_where(|d| Some(&d.path) == primary.as_ref())
+            .pick(terminal)?;
+            let home = disks
+                .into_iter()
+                .find(|d| d.path != root.path)
+                .ok_or(InstallerError::Cancelled)?;
+            return Ok((root, MultiDisk::SeparateHome { disk: home.path }));
+        };
+
+        if raid == MirrorKind::Btrfs {
+            // btrfs raid1 needs btrfs to mirror
+            if let Some(root) = self.state.config.partitions.find_mut(PartitionRole::Root) {
+                if root.filesystem.kind != FilesystemKind::Btrfs {
+                    root.filesystem = FilesystemSpec::new(FilesystemKind::Btrfs);
+                }
+            }
+        }
+        // The first disk carries the ESP and the bootloader
+        let primary = disks.remove(0);
+        let disks = disks.into_iter().map(|d| d.path).collect();
+        Ok((primary, MultiDisk::Mirror { disks, raid }))
+    }
+
+    // Erase the disk, use its free space next to the existing partitions, or the manual editor
+    fn partitioning<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Transition> {
+        let Some(disk) = self.state.disk.clone() else {
+            return Ok(Transition::Back);
+        };
+
+        loop {
+            // Keeping partitions only works on a single disk
+            let choices = match self.state.config.multi_disk {
+                Some(_) => vec![Partitioning::Erase],
+                None => vec![
+                    Partitioning::Erase,
+                    Partitioning::Alongside,
+                    Partitioning::Manual,
+                ],
+            };
+            let choice =
+                SelectList::new(self.title("Partitioning"), choices, |choice| match choice {
+                    Partitioning::Erase => {
+                        format!("Erase {} and use the automatic layout", disk.path)
+                    }
+                    Partitioning::Alongside => {
+                        "Install alongside the existing partitions, into unallocated space"
+                            .to_string()
+                    }
+                    Partitioning::Manual => "Edit the partition table manually".to_string(),
+                })
+                .select_where(|&choice| choice == self.state.partitioning)
+                .pick(terminal)?;
+
+            let table = match choice {
+                Partitioning::Erase => {
+                    self.state.partitioning = choice;
+                    self.state.config.manual_partitions = None;
+                    return Ok(Transition::Next);
+                }
+                Partitioning::Alongside => self.alongside(terminal, &disk),
+                // Starts out on the table set up before, so an alongside layout can be adjusted
+                Partitioning::Manual => match self.state.config.manual_partitions.clone() {
+                    Some(table) => Ok(table),
+                    None => GptTable::read(self.runner, &disk),
+                }
+                .and_then(|table| PartitionEditor::new(&disk, table).run(terminal)),
+            };
+            match table {
+                Ok(table) => {
+                    self.state.partitioning = choice;
+                    self.state.config.manual_partitions = Some(table);
+                    return Ok(Transition::Next);
+                }
+                // Backing out leads back to the choice
+                Err(InstallerError::Cancelled) => {}
+                Err(err) => ui::show_error(terminal, &err)?,
+            }
+        }
+    }
+
+    // The automatic layout in unallocated space, the largest region unless the user picks another
+    fn alongside<B: Backend>(
+        &self,
+        terminal: &mut Terminal<B>,
+        disk: &BlockDevice,
+    ) -> Result<GptTable> {
+        let mut table = GptTable::read(self.runner, disk)?;
+        let Some(largest) = table.largest_free() else {
+            return Err(InstallerError::PreconditionFailed(format!(
+                "{} has no unallocated space, shrink one of its partitions first",
+                disk.path
+            )));
+        };
+        let regions = table.free_regions();
+        let region = if regions.len() == 1 {
+            largest
+        } else {
+            let sector_size = table.sector_size;
+            SelectList::new(
+                self.title("Unallocated space to install into"),
+                regions,
+                |region| {
+                    format!(
+                        "{:>10} free, starting at {}",
+                        disk::format_size(region.sectors() * sector_size),
+                        disk::format_size(region.first_lba * sector_size)
+                    )
+                },
+            )
+            .select_where(|&region| region == largest)
+            .pick(terminal)?
+        };
+
+        let mut plan = self.state.config.partitions.clone();
+        plan.bios_boot = bootloader::detect_boot_mode(&self.live_root) == BootMode::Bios;
+        table.install_alongside(region, &plan)?;
+        Ok(table)
+    }
+
+    fn encryption<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Transition> {
+        let title = self.title("Encryption");
+        if !ui::confirm(terminal, &title, "Encrypt the root partition with LUKS2?")? {
+            self.state.config.encryption = None;
+            return Ok(Transition::Next);
+        }
+
+        let entered = matches!(
+            self.state.config.encryption.as_ref().map(|e| &e.key),
+            Some(LuksKey::Passphrase(_))
+        );
+        if !(entered && ui::confirm(terminal, &title, "Keep the passphrase entered before?")?) {
+            let passphrase = ui::passphrase(terminal, &self.title("Encryption passphrase"))?;
+            self.state.config.encryption = Some(Encryption::new(LuksKey::Passphrase(passphrase)));
+        }
+        Ok(Transition::Next)
+    }
+
+    // A list the live system does not provide keeps its default
+    fn locale<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Transition> {
+        let locales = self.locale_sources.locales().unwrap_or_default();
+        if locales.is_empty() {
+            return Ok(Transition::Next);
+        }
+
+        let settings = &self.state.config.locale;
+        let current = settings.locales.first().cloned().unwrap_or_default();
+        let lang = SelectList::new(
+            self.title("System locale (LANG), type to search"),
+            locales.clone(),
+            String::clone,
+        )
+        .type_ahead()
+        .select_where(|l| *l == current)
+        .pick(terminal)?;
+        let extra = SelectList::new(
+            self.title(
+                "Additional locales to generate, type to search, Space marks, Enter to continue",
+            ),
+            locales,
+            String::clone,
+        )
+        .type_ahead()
+        .mark_where(|l| *l != lang && settings.locales.contains(l))
+        .select_where(|l| *l == lang)
+        .pick_many(terminal)?;
+
+        let settings = &mut self.state.config.locale;
+        settings.locales = vec![lang.clone()];
+        settings
+            .locales
+            .extend(extra.into_iter().filter(|l| *l != lang));
+        Ok(Transition::Next)
+    }
+
+    fn keymap<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Transition> {
+        let keymaps = self.locale_sources.keymaps().unwrap_or_default();
+        if keymaps.is_empty() {
+            return Ok(Transition::Next);
+        }
+
+        let title = self.title("Console keymap, type to search");
+        let settings = &mut self.state.config.locale;
+        settings.keymap = SelectList::new(title, keymaps, String::clone)
+            .type_ahead()
+            .select_where(|k| *k == settings.keymap)
+            .pick(terminal)?;
+        Ok(Transition::Next)
+    }
+
+    fn timezone<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Transition> {
+        let timezones = self.locale_sources.timezones().unwrap_or_default();
+        if timezones.is_empty() {
+            return Ok(Transition::Next);
+        }
+
+        let title = self.title("Timezone, type to search");
+        let settings = &mut self.state.config.locale;
+        settings.timezone = SelectList::new(title, timezones, String::clone)
+            .type_ahead()
+            .select_where(|t| *t == settings.timezone)
+            .pick(terminal)?;
+        Ok(Transition::Next)
+    }
+
+    // The primary user and sudo for wheel
+    fn user<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Transition> {
+        let title = self.title("User account");
+        let previous = self.state.config.users.first().cloned();
+
+        let mut name = previous
+            .as_ref()
+            .map(|u| u.name.clone())
+            .unwrap_or_default();
+        let mut error = None;
+        loop {
+            name = ui::edit(terminal, &title, "User name", &name, error.as_deref())?;
+            let user = User {
+                name: name.clone(),
+                groups: Vec::new(),
+                shell: None,
+                password_hash: None,
+            };
+            match user.validate() {
+                Ok(()) => break,
+                Err(err) => error = Some(err.to_string()),
+            }
+        }
+
+        let mut groups = previous
+            .as_ref()
+            .map_or("wheel".to_string(), |u| u.groups.join(","));
+        let mut error = None;
+        let groups = loop {
+            groups = ui::edit(
+                terminal,
+                &title,
+                &format!("Groups for {} (comma separated)", name),
+                &groups,
+                error.as_deref(),
+            )?;
+            let parsed: Vec<String> = groups
+                .split(',')
+                .map(str::trim)
+                .filter(|g| !g.is_empty())
+                .map(str::to_string)
+                .collect();
+            match parsed
+                .iter()
+                .try_for_each(|g| users::validate_name(g, "group"))
+            {
+                Ok(()) => break parsed,
+                Err(err) => error = Some(err.to_string()),
+            }
+        };
+
+        let current_shell = previous.as_ref().and_then(|u| u.shell.clone());
+        let shell = SelectList::new(
+            self.title("Login shell"),
+            users::SHELLS.to_vec(),
+            |(path, _)| path.to_string(),
+        )
+        .select_where(|(path, _)| Some(*path) == current_shell.as_deref())
+        .pick(terminal)?
+        .0;
+
+        // A password only carries over for the same user
+        let kept = previous
+            .filter(|u| u.name == name)
+            .and_then(|u| u.password_hash);
+        let password_hash = match kept {
+            Some(hash) if ui::confirm(terminal, &title, "Keep the password entered before?")? => {
+                hash
+            }
+            _ => {
+                let password =
+                    ui::passphrase(terminal, &self.title(&format!("Password for {}", name)))?;
+                users::hash_password(&password)?
+            }
+        };
+
+        let user = User {
+            name,
+            groups,
+            // Leave useradd's default alone
+            shell: (shell != users::SHELLS[0].0).then(|| shell.to_string()),
+            password_hash: Some(password_hash),
+        };
+        self.state.config.sudo_wheel = user.in_group("wheel")
+            && ui::confirm(
+                terminal,
+                &title,
+                "Allow members of wheel to run commands as root with sudo?",
+            )?;
+        self.state.config.users = vec![user];
+        Ok(Transition::Next)
+    }
+
+    // Set the root password or lock root
+    fn root<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Transition> {
+        let title = self.title("Root account");
+        let config = &mut self.state.config;
+
+        loop {
+            if ui::confirm(
+                terminal,
+                &title,
+                "Set a root password? Answering no locks the root account",
+            )? {
+                let keep = config.root_password_hash.is_some()
+                    && ui::confirm(terminal, &title, "Keep the root password entered before?")?;
+                if !keep {
+                    let password = ui::passphrase(terminal, &title)?;
+                    config.root_password_hash = Some(users::hash_password(&password)?);
+                }
+                return Ok(Transition::Next);
+            }
+            if config.sudo_wheel
+                || ui::confirm(
+                    terminal,
+                    &title,
+                    "Root will be locked and nobody can use sudo, so the system cannot be administered. Lock root anyway?",
+                )?
+            {
+                config.root_password_hash = None;
+                return Ok(Transition::Next);
+            }
+        }
+    }
+
+    // Hostname, network stack and whether to bring the live system's connections along
+    fn network<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Transition> {
+        let title = self.title("Network");
+        let config = &mut self.state.config;
+
+        let mut hostname = config.hostname.clone();
+        let mut error = None;
+        loop {
+            hostname = ui::edit(terminal, &title, "Hostname", &hostname, error.as_deref())?;
+            match network::validate_hostname(&hostname) {
+                Ok(()) => break,
+                Err(err) => error = Some(err.to_string()),
+            }
+        }
+
+        let mut choices: Vec<Option<NetworkStack>> =
+            NetworkStack::ALL.into_iter().map(Some).collect();
+        choices.push(None);
+        let current = config.network.stack;
+        let stack = SelectList::new(self.title("Network stack"), choices, |stack| match stack {
+            Some(stack) => stack.name().to_string(),
+            None => "None, configure the network later".to_string(),
+        })
+        .select_where(|stack| *stack == current)
+        .pick(terminal)?;
+
+        let copy_live_profiles = match stack {
+            Some(stack) if !network::live_profiles(&self.live_root, stack).is_empty() => {
+                ui::confirm(
+                    terminal,
+                    &title,
+                    "Copy the connection profiles of this live system into the new install?",
+                )?
+            }
+            _ => false,
+        };
+
+        let config = &mut self.state.config;
+        config.hostname = hostname;
+        config.network.stack = stack;
+        config.network.copy_live_profiles = copy_live_profiles;
+        Ok(Transition::Next)
+    }
+
+    // Every answer at a glance, Enter on one goes back to change it
+    fn summary<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<Transition> {
+        let mut items: Vec<SummaryItem> = Screen::ALL
+            .iter()
+            .filter(|&&s| s != Screen::Summary)
+            .map(|&s| SummaryItem::Edit(s))
+            .collect();
+        items.extend([SummaryItem::SaveProfile, SummaryItem::Install]);
+
+        let state = &self.state;
+        let picked =
+            SelectList::new(
+                self.title("Enter on a step changes it"),
+                items,
+                |item| match item {
+                    SummaryItem::Edit(screen) => {
+                        format!("{:<14}{}", screen.name(), state.answer(*screen))
+                    }
+                    SummaryItem::SaveProfile => {
+                        "Save these choices as an install profile".to_string()
+                    }
+                    SummaryItem::Install => "Install".to_string(),
+                },
+            )
+            .select_where(|item| matches!(item, SummaryItem::Install))
+            .pick(terminal)?;
+
+        match picked {
+            SummaryItem::Edit(screen) => Ok(Transition::Goto(screen)),
+            SummaryItem::SaveProfile => {
+                self.save_profile(terminal)?;
+                Ok(Transition::Goto(Screen::Summary))
+            }
+            SummaryItem::Install => match self.review(terminal) {
+                Ok(()) => Ok(Transition::Install),
+                Err(InstallerError::Cancelled) => Ok(Transition::Goto(Screen::Summary)),
+                Err(err) => {
+                    ui::show_error(terminal, &err)?;
+                    Ok(Transition::Goto(Screen::Summary))
+                }
+            },
+        }
+    }
+
+    // Last stop before anything is written, the device name has to be typed out
+    fn review<B: Backend>(&self, terminal: &mut Terminal<B>) -> Result<()> {
+        let Some(disk) = &self.state.disk else {
+            return Err(InstallerError::PreconditionFailed(
+                "No disk has been chosen".to_string(),
+            ));
+        };
+        // With the chosen disk, so a mirror or home disk naming it again is caught here
+        self.state.install_config().validate()?;
+        let mode = bootloader::detect_boot_mode(&self.live_root);
+        let config = install::prepare(&self.state.config, mode)?;
+        let lines = review::summary(disk, &config, mode);
+        let prompt = match (&config.manual_partitions, &config.multi_disk) {
+            (Some(_), _) => format!(
+                "Type {} or YES to write its partition table and install",
+                disk.path
+            ),
+            (None, Some(multi)) => format!(
+                "Type {} or YES to erase it and {} and install",
+                disk.path,
+                multi.disks().join(", ")
+            ),
+            (None, None) => format!("Type {} or YES to erase it and install", disk.path),
+        };
+
+        ui::typed_confirmation(
+            terminal,
+            &self.title("Review, Esc goes back"),
+            &lines,
+            &prompt,
+            |typed| review::confirms(typed, disk),
+        )
+    }
+
+    // Ask where to write the profile until it is saved or the user gives up
+    fn save_profile<B: Backend>(&self, terminal: &mut Terminal<B>) -> Result<()> {
+        let title = self.title("Install profile");
+        // Without the disk the profile could not be replayed with --config
+        let profile = self.state.install_config().export_profile()?;
+        let mut path = DEFAULT_PROFILE.to_string();
+        let mut error = None;
+
+        loop {
+            path = match ui::edit(terminal, &title, "Save profile to", &path, error.as_deref()) {
+                Ok(path) => path,
+                // Skipping the export is fine, the install itself goes on
+                Err(InstallerError::Cancelled) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            match InstallConfig::save_profile(Path::new(&path), &profile) {
+                Ok(()) => return Ok(()),
+                Err(err) => error = Some(format!("Could not write {}: {}", path, err)),
+            }
+        }
+    }
+}
+
+// Detail pane of the disk step, one row per partition with what `contents` finds on it
+fn disk_details(
+    disk: &BlockDevice,
+    mut contents: impl FnMut(&BlockDevice) -> String,
+) -> Vec<String> {
+    let mut lines = vec![disk.summary()];
+    if let Some(serial) = &disk.serial {
+        lines.push(format!("Serial {}", serial));
+    }
+    lines.push(String::new());
+    if disk.partitions.is_empty() {
+        lines.push("No partitions".to_string());
+        return lines;
+    }
+    lines.push(format!(
+        "{:<16}{:>10}  {:<12}{:<16}{}",
+        "Partition", "Size", "Filesystem", "Label", "Contents"
+    ));
+    for part in &disk.partitions {
+        lines.push(format!(
+            "{:<16}{:>10}  {:<12}{:<16}{}",
+            part.path,
+            disk::format_size(part.size),
+            part.fstype.as_deref().unwrap_or("-"),
+            part.label.as_deref().unwrap_or("-"),
+            contents(part)
+        ));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{command::MockRunner, disk::tests::disk};
+
+    #[test]
+    fn screens_go_forward_and_back_in_order() {
+        for (i, screen) in Screen::ALL.into_iter().enumerate() {
+            assert_eq!(screen.step(), i + 1);
+            assert_eq!(screen.next(), Screen::ALL.get(i + 1).copied());
+            assert_eq!(
+                screen.prev(),
+                i.checked_sub(1).map(|prev| Screen::ALL[prev])
+            );
+        }
+        assert_eq!(Screen::Disk.next(), Some(Screen::Partitioning));
+        assert_eq!(Screen::Network.next(), Some(Screen::Summary));
+        assert_eq!(Screen::Summary.next(), None);
+        assert_eq!(Screen::Disk.prev(), None);
+    }
+
+    #[test]
+    fn back_from_the_first_screen_leaves_the_wizard() {
+        let runner = MockRunner::new();
+        let mut wizard = Wizard::new(&runner);
+        assert_eq!(wizard.follow(Transition::Next), ControlFlow::Continue(()));
+        assert_eq!(wizard.screen(), Screen::Partitioning);
+        assert_eq!(wizard.follow(Transition::Back), ControlFlow::Continue(()));
+        assert_eq!(wizard.follow(Transition::Back), ControlFlow::Break(None));
+        assert_eq!(wizard.screen(), Screen::Disk);
+    }
+
+    #[test]
+    fn answers_survive_going_back_and_forth() {
+        let runner = MockRunner::new();
+        let mut wizard = Wizard::new(&runner);
+        wizard.state.disk = Some(disk("/dev/sda", 64 << 30));
+        wizard.state.partitioning = Partitioning::Manual;
+        wizard.state.config.hostname = "workstation".to_string();
+        wizard.state.config.locale.keymap = "de-latin1".to_string();
+        let answers = wizard.state.install_config();
+
+        for _ in 1..Screen::ALL.len() {
+            let _ = wizard.follow(Transition::Next);
+        }
+        assert_eq!(wizard.screen(), Screen::Summary);
+        while wizard.follow(Transition::Back) == ControlFlow::Continue(()) {}
+        for _ in 0..3 {
+            let _ = wizard.follow(Transition::Next);
+        }
+        assert_eq!(wizard.screen(), Screen::Locale);
+        assert_eq!(wizard.state.partitioning, Partitioning::Manual);
+        assert_eq!(wizard.state.install_config(), answers);
+    }
+
+    #[test]
+    fn editing_from_the_summary_returns_to_it() {
+        let runner = MockRunner::new();
+        let mut wizard = Wizard::new(&runner);
+        wizard.screen = Screen::Summary;
+        let _ = wizard.follow(Transition::Goto(Screen::Keymap));
+        assert_eq!(wizard.screen(), Screen::Keymap);
+        // Esc as well as confirming goes back to the summary
+        let _ = wizard.follow(Transition::Back);
+        assert_eq!(wizard.screen(), Screen::Summary);
+        let _ = wizard.follow(Transition::Goto(Screen::Timezone));
+        let _ = wizard.follow(Transition::Next);
+        assert_eq!(wizard.screen(), Screen::Summary);
+        let _ = wizard.follow(Transition::Back);
+        assert_eq!(wizard.screen(), Screen::Network);
+    }
+
+    #[test]
+    fn a_failed_install_resumes_on_the_summary() {
+        let runner = MockRunner::new();
+        let mut wizard = Wizard::new(&runner);
+        wizard.screen = Screen::Summary;
+        // Without a disk there is nothing to install onto yet
+        assert_eq!(
+            wizard.follow(Transition::Install),
+            ControlFlow::Continue(())
+        );
+        assert_eq!(wizard.screen(), Screen::Disk);
+
+        wizard.state.disk = Some(disk("/dev/sda", 64 << 30));
+        wizard.state.config.hostname = "workstation".to_string();
+        wizard.screen = Screen::Summary;
+        let ControlFlow::Break(Some((disk, config))) = wizard.follow(Transition::Install) else {
+            panic!("the wizard did not hand over the install");
+        };
+        assert_eq!(disk.path, "/dev/sda");
+        assert_eq!(config.hostname, "workstation");
+        // `run` starts again from here once the error is shown
+        assert_eq!(wizard.screen(), Screen::Summary);
+        assert_eq!(wizard.state.config.hostname, "workstation");
+    }
+
+    #[test]
+    fn exported_profile_names_the_chosen_disk() {
+        let mut state = InstallState::default();
+        assert_eq!(state.install_config().disk, None);
+
+        state.disk = Some(disk("/dev/sda", 64 << 30));
+        let profile = state.install_config().export_profile().unwrap();
+        let replayed = InstallConfig::from_toml(&profile).unwrap();
+        assert_eq!(replayed.disk.as_deref(), Some("/dev/sda"));
+        assert_eq!(state.config.disk, None);
+    }
+}